@@ -0,0 +1,218 @@
+use std::{collections::HashMap, io::ErrorKind};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+
+/// Optional settings which don't fit in an environment variable, read from a JSON file.
+///
+/// ```json
+/// {
+///   "channels": { "C0123456": { "code_interpreter": true, "transcribe_audio": true, "remote_mcp_servers": ["deepwiki"] } },
+///   "remote_mcp_servers": [{ "label": "deepwiki", "url": "https://mcp.deepwiki.com/mcp" }],
+///   "schedules": [{ "cron": "0 9 * * *", "job": { "type": "digest", "channel": "C0123456", "post_to": "C0654321" } }],
+///   "mcp_server": { "channels": ["C0123456"], "tools": ["read_thread", "search_messages"] },
+///   "resource_servers": ["docs"]
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelConfig>,
+    /// MCP servers which OpenAI calls by itself through the Responses API.
+    #[serde(default)]
+    pub remote_mcp_servers: Vec<RemoteMcpServer>,
+    /// Jobs run by the bot itself, in the timezone of `TZ`.
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    /// What other agents may do through the bot as an MCP server. Not served if missing.
+    #[serde(default)]
+    pub mcp_server: Option<McpServerAccess>,
+    /// MCP servers whose resources anyone may attach. Only admins may attach those of others.
+    #[serde(default)]
+    pub resource_servers: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ChannelConfig {
+    #[serde(default)]
+    pub code_interpreter: bool,
+    /// Whether audio files are transcribed, also when `TRANSCRIBE_AUDIO` is off.
+    #[serde(default)]
+    pub transcribe_audio: bool,
+    /// Labels of the remote MCP servers usable in the channel. All of them if missing.
+    #[serde(default)]
+    pub remote_mcp_servers: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoteMcpServer {
+    pub label: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub require_approval: RemoteMcpApproval,
+    /// Every tool of the server is allowed if missing.
+    #[serde(default)]
+    pub allowed_tools: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteMcpApproval {
+    #[default]
+    Always,
+    Never,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct McpServerAccess {
+    /// The only channels the tools may read or write.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Every tool is allowed if missing.
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+}
+
+impl McpServerAccess {
+    pub fn allows_channel(&self, channel: &str) -> bool {
+        self.channels.iter().any(|allowed| allowed == channel)
+    }
+
+    pub fn allows_tool(&self, tool: &str) -> bool {
+        self.tools
+            .as_ref()
+            .is_none_or(|tools| tools.iter().any(|allowed| allowed == tool))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleConfig {
+    /// Cron expression, with or without the seconds field. Without it, as in crontab.
+    pub cron: String,
+    pub job: Job,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Job {
+    /// Summarizes the recent messages of `channel` into `post_to`, or the same channel if missing.
+    Digest {
+        channel: String,
+        #[serde(default)]
+        post_to: Option<String>,
+        /// Like `24h` or `7d`. A day if missing.
+        #[serde(default)]
+        period: Option<String>,
+        #[serde(default)]
+        model: Option<String>,
+    },
+}
+
+impl Config {
+    /// A missing file is the same as an empty one.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).context("Failed to read config file"),
+        };
+
+        serde_json::from_str(&content).context("Failed to parse config file")
+    }
+
+    pub fn channel(&self, channel: &str) -> Option<&ChannelConfig> {
+        self.channels.get(channel)
+    }
+
+    pub fn remote_mcp_servers<'a>(
+        &'a self,
+        channel: &str,
+    ) -> impl Iterator<Item = &'a RemoteMcpServer> + 'a {
+        let allowed = self
+            .channel(channel)
+            .and_then(|channel| channel.remote_mcp_servers.as_ref());
+
+        self.remote_mcp_servers
+            .iter()
+            .filter(move |server| allowed.is_none_or(|allowed| allowed.contains(&server.label)))
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let config = serde_json::from_str::<Config>(
+        r#"{
+            "channels": {
+                "C123": { "code_interpreter": true, "transcribe_audio": true, "remote_mcp_servers": ["wiki"] },
+                "C456": {}
+            },
+            "remote_mcp_servers": [
+                { "label": "wiki", "url": "https://wiki.example.com/mcp" },
+                {
+                    "label": "issues",
+                    "url": "https://issues.example.com/mcp",
+                    "headers": { "Authorization": "Bearer token" },
+                    "require_approval": "never",
+                    "allowed_tools": ["search"]
+                }
+            ],
+            "schedules": [
+                {
+                    "cron": "0 9 * * *",
+                    "job": { "type": "digest", "channel": "C123", "post_to": "C456", "period": "7d" }
+                }
+            ],
+            "mcp_server": { "channels": ["C123"], "tools": ["read_thread"] },
+            "resource_servers": ["docs"]
+        }"#,
+    )
+    .unwrap();
+
+    assert!(config.channel("C123").unwrap().code_interpreter);
+    assert!(!config.channel("C456").unwrap().code_interpreter);
+    assert!(config.channel("C123").unwrap().transcribe_audio);
+    assert!(!config.channel("C456").unwrap().transcribe_audio);
+    assert!(config.channel("C789").is_none());
+
+    let labels = |channel| {
+        config
+            .remote_mcp_servers(channel)
+            .map(|server| server.label.as_str())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(labels("C123"), vec!["wiki"]);
+    assert_eq!(labels("C456"), vec!["wiki", "issues"]);
+
+    let issues = &config.remote_mcp_servers[1];
+
+    assert_eq!(issues.require_approval, RemoteMcpApproval::Never);
+    assert_eq!(
+        config.remote_mcp_servers[0].require_approval,
+        RemoteMcpApproval::Always
+    );
+    assert_eq!(
+        issues.allowed_tools.as_deref(),
+        Some(&["search".to_string()][..])
+    );
+
+    let schedule = &config.schedules[0];
+
+    assert_eq!(schedule.cron, "0 9 * * *");
+    assert!(matches!(
+        &schedule.job,
+        Job::Digest { channel, post_to: Some(post_to), period: Some(period), model: None }
+            if channel == "C123" && post_to == "C456" && period == "7d"
+    ));
+
+    let access = config.mcp_server.unwrap();
+
+    assert!(access.allows_channel("C123"));
+    assert!(!access.allows_channel("C456"));
+    assert!(access.allows_tool("read_thread"));
+    assert!(!access.allows_tool("post_message"));
+    assert!(McpServerAccess::default().allows_tool("post_message"));
+    assert_eq!(config.resource_servers, vec!["docs"]);
+}
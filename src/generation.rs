@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::slack::{ActionBlock, BlockElement, ButtonBlock, ButtonStyle};
+
+pub const STOP_ACTION_ID: &str = "stop_generation";
+pub const STOP_REACTIONS: &[&str] = &["stop", "octagonal_sign"];
+
+struct Generation {
+    id: u64,
+    requester: String,
+    cancel_token: CancellationToken,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopResult {
+    Stopped,
+    NotAllowed,
+    NotFound,
+}
+
+/// Keeps track of the answers which are still streaming, keyed by (channel, message ts).
+#[derive(Default)]
+pub struct GenerationRegistry {
+    generations: Mutex<HashMap<(String, String), Generation>>,
+    next_id: AtomicU64,
+}
+
+/// A message held for a generation which hasn't started streaming yet.
+/// Released when dropped, unless a generation registered since took the message over.
+pub struct GenerationClaim<'a> {
+    registry: &'a GenerationRegistry,
+    key: (String, String),
+    id: u64,
+}
+
+impl Drop for GenerationClaim<'_> {
+    fn drop(&mut self) {
+        let mut generations = self.registry.generations.lock().unwrap();
+
+        if generations.get(&self.key).map(|generation| generation.id) == Some(self.id) {
+            generations.remove(&self.key);
+        }
+    }
+}
+
+impl GenerationRegistry {
+    fn generation(&self, requester: &str, token: CancellationToken) -> Generation {
+        Generation {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            requester: requester.to_string(),
+            cancel_token: token,
+        }
+    }
+
+    pub fn register(&self, channel: &str, ts: &str, requester: &str, token: CancellationToken) {
+        let generation = self.generation(requester, token);
+        let mut generations = self.generations.lock().unwrap();
+
+        generations.insert((channel.to_string(), ts.to_string()), generation);
+    }
+
+    /// Claims the message unless a generation is already running on it.
+    pub fn try_register(
+        &self,
+        channel: &str,
+        ts: &str,
+        requester: &str,
+    ) -> Option<GenerationClaim<'_>> {
+        let generation = self.generation(requester, CancellationToken::new());
+        let id = generation.id;
+        let key = (channel.to_string(), ts.to_string());
+
+        let mut generations = self.generations.lock().unwrap();
+
+        if generations.contains_key(&key) {
+            return None;
+        }
+
+        generations.insert(key.clone(), generation);
+
+        Some(GenerationClaim {
+            registry: self,
+            key,
+            id,
+        })
+    }
+
+    pub fn unregister(&self, channel: &str, ts: &str) {
+        let mut generations = self.generations.lock().unwrap();
+
+        generations.remove(&(channel.to_string(), ts.to_string()));
+    }
+
+    pub fn is_running(&self, channel: &str, ts: &str) -> bool {
+        let generations = self.generations.lock().unwrap();
+
+        generations.contains_key(&(channel.to_string(), ts.to_string()))
+    }
+
+    pub fn stop(&self, channel: &str, ts: &str, user: &str, is_admin: bool) -> StopResult {
+        let generations = self.generations.lock().unwrap();
+
+        let generation = match generations.get(&(channel.to_string(), ts.to_string())) {
+            Some(generation) => generation,
+            None => return StopResult::NotFound,
+        };
+
+        if generation.requester != user && !is_admin {
+            return StopResult::NotAllowed;
+        }
+
+        generation.cancel_token.cancel();
+
+        StopResult::Stopped
+    }
+}
+
+pub fn stop_button_block() -> BlockElement {
+    BlockElement::Actions(ActionBlock {
+        block_id: None,
+        elements: Some(vec![BlockElement::Button(ButtonBlock::new(
+            "Stop",
+            STOP_ACTION_ID,
+            STOP_ACTION_ID,
+            Some(ButtonStyle::Danger),
+        ))]),
+    })
+}
+
+#[test]
+fn test_stop_generation() {
+    let registry = GenerationRegistry::default();
+    let token = CancellationToken::new();
+
+    registry.register("C123", "1.0", "U1", token.clone());
+
+    assert_eq!(
+        registry.stop("C123", "2.0", "U1", false),
+        StopResult::NotFound
+    );
+    assert_eq!(
+        registry.stop("C123", "1.0", "U2", false),
+        StopResult::NotAllowed
+    );
+    assert!(!token.is_cancelled());
+
+    assert_eq!(
+        registry.stop("C123", "1.0", "U2", true),
+        StopResult::Stopped
+    );
+    assert!(token.is_cancelled());
+
+    registry.unregister("C123", "1.0");
+
+    assert_eq!(
+        registry.stop("C123", "1.0", "U1", false),
+        StopResult::NotFound
+    );
+}
+
+#[test]
+fn test_claim_generation() {
+    let registry = GenerationRegistry::default();
+
+    let claim = registry.try_register("C123", "1.0", "U1").unwrap();
+    assert!(registry.try_register("C123", "1.0", "U2").is_none());
+    assert!(registry.is_running("C123", "1.0"));
+
+    drop(claim);
+    assert!(!registry.is_running("C123", "1.0"));
+
+    // A generation which took the message over outlives the claim
+    let claim = registry.try_register("C123", "1.0", "U1").unwrap();
+    registry.register("C123", "1.0", "U1", CancellationToken::new());
+
+    drop(claim);
+    assert!(registry.is_running("C123", "1.0"));
+}
@@ -6,6 +6,7 @@ use axum::extract::Extension;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::routing::MethodFilter;
+use axum::Form;
 use axum::Json;
 use bytes::Bytes;
 use futures::SinkExt;
@@ -13,18 +14,13 @@ use futures::StreamExt;
 use log::{debug, error, info, warn};
 use reqwest::StatusCode;
 use rmcp::model::CallToolRequestParam;
-use rmcp::service::RunningService;
-use rmcp::transport::TokioChildProcess;
-use rmcp::Peer;
-use rmcp::RoleClient;
-use rmcp::ServiceExt;
+use slack::ConversationHistoryResponse;
 use slack::ConversationReplyResponse;
 use slack::EditMessage;
 use slack::EditMessageResponse;
 use slack::PostMessage;
 use slack::PostMessageResponse;
 use slack::SlackSocketOutput;
-use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
@@ -33,19 +29,20 @@ use std::{
     env,
 };
 use tokio::net::TcpStream;
-use tokio::process::Command;
 use tokio_tungstenite::tungstenite::Utf8Bytes;
 use tokio_tungstenite::MaybeTlsStream;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as TungsteniteMessage};
 
+mod config;
+mod generation;
+mod mcp;
 mod modules;
+mod scheduler;
 mod slack;
 #[cfg(test)]
 pub mod test;
 
-type McpClient = RunningService<RoleClient, ()>;
-
 pub struct MessageEvent {
     is_bot: bool,
     user: String,
@@ -54,6 +51,33 @@ pub struct MessageEvent {
     ts: String,
     thread_ts: Option<String>,
     link: Option<String>,
+    files: Vec<slack::SlackFile>,
+}
+
+pub struct ReactionEvent {
+    user: String,
+    reaction: String,
+    channel: String,
+    item_ts: String,
+}
+
+pub enum EditEvent {
+    Changed {
+        channel: String,
+        message: slack::HistoryMessage,
+    },
+    Deleted {
+        channel: String,
+        ts: String,
+    },
+}
+
+pub struct ActionEvent {
+    user: String,
+    channel: String,
+    message_ts: String,
+    thread_ts: Option<String>,
+    action_id: String,
 }
 
 #[derive(Clone)]
@@ -76,6 +100,17 @@ impl TryFrom<&slack::InternalEvent> for MessageEvent {
     fn try_from(val: &slack::InternalEvent) -> std::result::Result<Self, Self::Error> {
         match val {
             slack::InternalEvent::Message(slack::Message::BasicMessage(msg)) => {
+                // Joins, topic changes and the like look the same as messages otherwise
+                if !matches!(
+                    msg.subtype.as_deref(),
+                    None | Some("file_share" | "thread_broadcast" | "bot_message" | "me_message")
+                ) {
+                    return Err(ConvertMessageEventError::Unsupported(format!(
+                        "Message subtype {:?} not supported",
+                        msg.subtype
+                    )));
+                }
+
                 let mut link_url: Option<&String> = None;
 
                 msg.blocks.iter().any(|block| {
@@ -113,12 +148,9 @@ impl TryFrom<&slack::InternalEvent> for MessageEvent {
                     channel: msg.channel.to_string(),
                     text: msg.common.text.to_string(),
                     ts,
-                    thread_ts: if let Some(thread_ts) = msg.common.thread_ts.clone() {
-                        Some(String::from(&thread_ts))
-                    } else {
-                        None
-                    },
+                    thread_ts: msg.common.thread_ts.as_ref().map(String::from),
                     link,
+                    files: msg.files.clone(),
                 })
             }
             slack::InternalEvent::Message(slack::Message::TaggedMessage(_)) => {
@@ -140,6 +172,70 @@ impl TryFrom<&slack::InternalEvent> for MessageEvent {
     }
 }
 
+impl TryFrom<&slack::ReactionAddedEvent> for ReactionEvent {
+    type Error = ConvertMessageEventError;
+
+    fn try_from(val: &slack::ReactionAddedEvent) -> std::result::Result<Self, Self::Error> {
+        match &val.item {
+            slack::ReactionItem::Message { channel, ts } => Ok(Self {
+                user: val.user.clone(),
+                reaction: val.reaction.clone(),
+                channel: channel.clone(),
+                item_ts: ts.clone(),
+            }),
+            slack::ReactionItem::Unknown => Err(ConvertMessageEventError::Unsupported(
+                "Reaction on non-message item not supported".to_string(),
+            )),
+        }
+    }
+}
+
+impl TryFrom<&slack::InternalEvent> for EditEvent {
+    type Error = ConvertMessageEventError;
+
+    fn try_from(val: &slack::InternalEvent) -> std::result::Result<Self, Self::Error> {
+        match val {
+            slack::InternalEvent::Message(slack::Message::TaggedMessage(
+                slack::TaggedMessage::MessageChanged(changed),
+            )) => Ok(Self::Changed {
+                channel: changed.channel.clone(),
+                message: changed.message.clone(),
+            }),
+            slack::InternalEvent::Message(slack::Message::TaggedMessage(
+                slack::TaggedMessage::MessageDeleted(deleted),
+            )) => Ok(Self::Deleted {
+                channel: deleted.channel.clone(),
+                ts: deleted.deleted_ts.clone(),
+            }),
+            _ => Err(ConvertMessageEventError::InvalidMessageType(format!(
+                "{:?}",
+                val
+            ))),
+        }
+    }
+}
+
+impl ActionEvent {
+    fn from_block_actions(payload: &slack::BlockActionsPayload) -> Vec<Self> {
+        let (channel, message) = match (&payload.channel, &payload.message) {
+            (Some(channel), Some(message)) => (channel, message),
+            _ => return vec![],
+        };
+
+        payload
+            .actions
+            .iter()
+            .map(|action| Self {
+                user: payload.user.id.clone(),
+                channel: channel.id.clone(),
+                message_ts: message.ts.clone(),
+                thread_ts: message.thread_ts.clone(),
+                action_id: action.action_id.clone(),
+            })
+            .collect()
+    }
+}
+
 pub enum Message<'a> {
     Blocks(&'a [slack::BlockElement]),
     Text(&'a str),
@@ -201,6 +297,17 @@ pub trait Bot {
     fn bot_token(&self) -> &'_ str;
     fn openai_key(&self) -> &'_ str;
     fn gemini_key(&self) -> &'_ str;
+    fn generations(&self) -> &'_ generation::GenerationRegistry;
+    fn answers(&self) -> &'_ modules::llm::AnswerStore;
+    fn responses(&self) -> &'_ modules::chatgpt::ResponseStore;
+    fn approvals(&self) -> &'_ modules::llm::approval::ApprovalRegistry;
+    fn search_index(&self) -> &'_ modules::search::SearchIndex;
+    fn documents(&self) -> &'_ modules::llm::documents::DocumentCache;
+    fn translations(&self) -> &'_ modules::translate::TranslationLog;
+    fn mcp(&self) -> &'_ mcp::supervisor::McpRegistry;
+    fn tool_policies(&self) -> &'_ modules::llm::approval::ToolPolicies;
+    fn is_admin(&self, user: &str) -> bool;
+    fn config(&self) -> &'_ config::Config;
 
     async fn send_message(
         &self,
@@ -223,9 +330,51 @@ pub trait Bot {
         ts: &str,
     ) -> anyhow::Result<ConversationReplyResponse>;
 
+    /// A page of a thread if `thread_ts` is given, of the channel otherwise.
+    async fn get_messages(
+        &self,
+        channel: &str,
+        thread_ts: Option<&str>,
+        oldest: Option<&str>,
+        cursor: Option<&str>,
+    ) -> anyhow::Result<ConversationHistoryResponse>;
+
+    async fn get_user_name(&self, user: &str) -> anyhow::Result<String>;
+
+    async fn get_permalink(&self, channel: &str, ts: &str) -> anyhow::Result<String>;
+
+    /// Every channel the bot is a member of.
+    async fn get_channels(&self) -> anyhow::Result<Vec<slack::ChannelInfo>>;
+
+    /// The ids of the users in a channel.
+    async fn get_channel_members(&self, channel: &str) -> anyhow::Result<Vec<String>>;
+
+    /// Downloads a file shared in Slack, from its private URL.
+    async fn download_file(&self, url: &str) -> anyhow::Result<Vec<u8>>;
+
+    async fn upload_file(
+        &self,
+        channel: &str,
+        thread_ts: Option<&str>,
+        filename: &str,
+        content: Vec<u8>,
+    ) -> anyhow::Result<()>;
+
+    /// Reacts to a message with an emoji, named without colons.
+    async fn add_reaction(&self, channel: &str, ts: &str, name: &str) -> anyhow::Result<()>;
+
+    /// Posts a message only the user can see.
+    async fn send_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        thread_ts: Option<&str>,
+    ) -> anyhow::Result<()>;
+
     async fn get_all_tools_metadata(
         &self,
-    ) -> anyhow::Result<Vec<(String, HashMap<String, (String, String)>, HashSet<String>)>>;
+    ) -> anyhow::Result<Vec<modules::llm::tools::ToolMetadata>>;
 
     async fn call_mcp_tool(
         &self,
@@ -239,76 +388,60 @@ struct DittoBot {
     bot_token: String,
     openai_key: String,
     gemini_key: String,
+    admin_users: HashSet<String>,
+    tool_policies: modules::llm::approval::ToolPolicies,
+    config: config::Config,
     http_client: reqwest::Client,
-    mcp_clients: HashMap<String, McpClient>,
-    mcp_tools: HashMap<String, (Cow<'static, str>, Peer<RoleClient>)>,
+    generations: generation::GenerationRegistry,
+    answers: modules::llm::AnswerStore,
+    responses: modules::chatgpt::ResponseStore,
+    approvals: modules::llm::approval::ApprovalRegistry,
+    search_index: modules::search::SearchIndex,
+    documents: modules::llm::documents::DocumentCache,
+    translations: modules::translate::TranslationLog,
+    user_names: std::sync::Mutex<HashMap<String, String>>,
+    mcp: Arc<mcp::supervisor::McpRegistry>,
 }
 
 impl DittoBot {
-    pub async fn new(
+    pub fn new(
         bot_id: String,
         bot_token: String,
         openai_key: String,
         gemini_key: String,
-        mcp_clients: HashMap<String, McpClient>,
+        admin_users: HashSet<String>,
+        tool_policies: modules::llm::approval::ToolPolicies,
+        mcp: Arc<mcp::supervisor::McpRegistry>,
     ) -> Self {
-        let mut mcp_tools = HashMap::new();
-
-        for (name, client) in mcp_clients.iter() {
-            let tools = client.list_all_tools().await.unwrap_or_default();
-
-            for tool in tools {
-                let unified_name = format!("{}_{}", name, tool.name);
-                mcp_tools.insert(unified_name, (tool.name, client.peer().clone()));
-            }
-        }
-
         Self {
             bot_id,
             bot_token,
             openai_key,
             gemini_key,
+            admin_users,
+            tool_policies,
+            config: Default::default(),
             http_client: reqwest::Client::new(),
-            mcp_clients,
-            mcp_tools,
+            generations: Default::default(),
+            answers: Default::default(),
+            responses: Default::default(),
+            approvals: Default::default(),
+            search_index: Default::default(),
+            documents: Default::default(),
+            translations: Default::default(),
+            user_names: Default::default(),
+            mcp,
         }
     }
 
-    async fn create_mcp_clients(tz: String) -> HashMap<String, McpClient> {
-        let mut results = HashMap::new();
-
-        let client1 = async move {
-            ().serve(TokioChildProcess::new(
-                Command::new("uvx")
-                    .arg("mcp-server-time")
-                    .arg("--local-timezone")
-                    .arg(tz),
-            )?)
-            .await
-        }
-        .await;
-
-        let client2 = async move {
-            ().serve(TokioChildProcess::new(
-                Command::new("uvx").arg("mcp-server-fetch"),
-            )?)
-            .await
-        }
-        .await;
-
-        results.insert("mcp-server-time", client1);
-        results.insert("mcp-server-fetch", client2);
+    pub fn with_config(mut self, config: config::Config) -> Self {
+        self.config = config;
+        self
+    }
 
-        results
-            .into_iter()
-            .filter_map(|(name, client)| match client {
-                Ok(client) => Some((name.to_string(), client)),
-                Err(e) => {
-                    error!("Failed to create mcp client - {:?}", e);
-                    None
-                }
-            })
-            .collect()
+    pub fn with_search_index(mut self, search_index: modules::search::SearchIndex) -> Self {
+        self.search_index = search_index;
+        self
     }
 }
 
@@ -330,6 +463,50 @@ impl Bot for DittoBot {
         &self.gemini_key
     }
 
+    fn generations(&self) -> &'_ generation::GenerationRegistry {
+        &self.generations
+    }
+
+    fn answers(&self) -> &'_ modules::llm::AnswerStore {
+        &self.answers
+    }
+
+    fn responses(&self) -> &'_ modules::chatgpt::ResponseStore {
+        &self.responses
+    }
+
+    fn approvals(&self) -> &'_ modules::llm::approval::ApprovalRegistry {
+        &self.approvals
+    }
+
+    fn tool_policies(&self) -> &'_ modules::llm::approval::ToolPolicies {
+        &self.tool_policies
+    }
+
+    fn search_index(&self) -> &'_ modules::search::SearchIndex {
+        &self.search_index
+    }
+
+    fn documents(&self) -> &'_ modules::llm::documents::DocumentCache {
+        &self.documents
+    }
+
+    fn translations(&self) -> &'_ modules::translate::TranslationLog {
+        &self.translations
+    }
+
+    fn mcp(&self) -> &'_ mcp::supervisor::McpRegistry {
+        &self.mcp
+    }
+
+    fn is_admin(&self, user: &str) -> bool {
+        self.admin_users.contains(user)
+    }
+
+    fn config(&self) -> &'_ config::Config {
+        &self.config
+    }
+
     async fn send_message(
         &self,
         channel: &str,
@@ -405,59 +582,324 @@ impl Bot for DittoBot {
 
         let json_result = serde_json::from_str::<ConversationReplyResponse>(&body);
 
-        if json_result.is_ok() {
-            Ok(json_result.unwrap())
-        } else {
-            Err(anyhow!(
+        match json_result {
+            Ok(json) => Ok(json),
+            Err(e) => Err(anyhow!(
                 "Json parsing failed for conversations.replies: {:?} {}",
-                json_result.err(),
+                e,
                 body
-            ))
+            )),
         }
     }
 
-    async fn get_all_tools_metadata(
+    async fn get_messages(
         &self,
-    ) -> anyhow::Result<Vec<(String, HashMap<String, (String, String)>, HashSet<String>)>> {
-        let mut datas = vec![];
+        channel: &str,
+        thread_ts: Option<&str>,
+        oldest: Option<&str>,
+        cursor: Option<&str>,
+    ) -> anyhow::Result<ConversationHistoryResponse> {
+        let url = match thread_ts {
+            Some(_) => "https://slack.com/api/conversations.replies",
+            None => "https://slack.com/api/conversations.history",
+        };
+
+        let mut query = vec![("channel", channel), ("limit", "200")];
+
+        query.extend(thread_ts.map(|ts| ("ts", ts)));
+        query.extend(oldest.map(|oldest| ("oldest", oldest)));
+        query.extend(cursor.map(|cursor| ("cursor", cursor)));
+
+        let res = self
+            .http_client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .query(&query)
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<ConversationHistoryResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        if !res.ok {
+            return Err(anyhow!("{} failed: {:?}", url, res.error));
+        }
+
+        Ok(res)
+    }
+
+    async fn get_user_name(&self, user: &str) -> anyhow::Result<String> {
+        if let Some(name) = self.user_names.lock().unwrap().get(user) {
+            return Ok(name.clone());
+        }
+
+        let res = self
+            .http_client
+            .get("https://slack.com/api/users.info")
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .query(&[("user", user)])
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<slack::UserInfoResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        let name = match res.user {
+            Some(info) => info.display_name().to_string(),
+            None => return Err(anyhow!("users.info failed: {:?}", res.error)),
+        };
+
+        self.user_names
+            .lock()
+            .unwrap()
+            .insert(user.to_string(), name.clone());
+
+        Ok(name)
+    }
+
+    async fn get_permalink(&self, channel: &str, ts: &str) -> anyhow::Result<String> {
+        let res = self
+            .http_client
+            .get("https://slack.com/api/chat.getPermalink")
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .query(&[("channel", channel), ("message_ts", ts)])
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<slack::PermalinkResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        match res.permalink {
+            Some(permalink) => Ok(permalink),
+            None => Err(anyhow!("chat.getPermalink failed: {:?}", res.error)),
+        }
+    }
 
-        for (name, client) in self.mcp_clients.iter() {
-            let tools = client
-                .list_all_tools()
+    async fn get_channels(&self) -> anyhow::Result<Vec<slack::ChannelInfo>> {
+        let mut channels = vec![];
+        let mut cursor = None;
+
+        loop {
+            let mut query = vec![
+                ("types", "public_channel,private_channel"),
+                ("exclude_archived", "true"),
+                ("limit", "200"),
+            ];
+
+            query.extend(cursor.as_deref().map(|cursor| ("cursor", cursor)));
+
+            let res = self
+                .http_client
+                .get("https://slack.com/api/users.conversations")
+                .header("Authorization", format!("Bearer {}", &self.bot_token))
+                .query(&query)
+                .send()
                 .await
-                .context("Failed to list all tools")?;
-
-            for tool in tools {
-                let unified_name = format!("{}_{}", name, tool.name);
-
-                let properties: &serde_json::Map<String, serde_json::Value> =
-                    tool.input_schema["properties"].as_object().unwrap();
-
-                let required = tool.input_schema["required"].as_array().unwrap();
-                let required = required
-                    .iter()
-                    .map(|v| v.as_str().unwrap().to_string())
-                    .collect::<HashSet<_>>();
-
-                let arguments = properties
-                    .keys()
-                    .map(|arg_name| {
-                        let value = properties.get(arg_name).unwrap();
-                        let arg_type = value["type"].as_str().unwrap_or("string");
-                        let description = value["description"].as_str().unwrap_or("");
-
-                        (
-                            arg_name.to_string(),
-                            (arg_type.to_string(), description.to_string()),
-                        )
-                    })
-                    .collect::<HashMap<String, (String, String)>>();
+                .context("Failed to send request")?
+                .json::<slack::ConversationsListResponse>()
+                .await
+                .context("Failed to parse response")?;
+
+            if !res.ok {
+                return Err(anyhow!("users.conversations failed: {:?}", res.error));
+            }
 
-                datas.push((unified_name, arguments, required));
+            channels.extend(res.channels.iter().cloned());
+
+            cursor = match res.next_cursor() {
+                Some(cursor) => Some(cursor.to_string()),
+                None => break,
+            };
+        }
+
+        Ok(channels)
+    }
+
+    async fn get_channel_members(&self, channel: &str) -> anyhow::Result<Vec<String>> {
+        let mut members = vec![];
+        let mut cursor = None;
+
+        loop {
+            let mut query = vec![("channel", channel), ("limit", "200")];
+
+            query.extend(cursor.as_deref().map(|cursor| ("cursor", cursor)));
+
+            let res = self
+                .http_client
+                .get("https://slack.com/api/conversations.members")
+                .header("Authorization", format!("Bearer {}", &self.bot_token))
+                .query(&query)
+                .send()
+                .await
+                .context("Failed to send request")?
+                .json::<slack::ConversationMembersResponse>()
+                .await
+                .context("Failed to parse response")?;
+
+            if !res.ok {
+                return Err(anyhow!("conversations.members failed: {:?}", res.error));
             }
+
+            members.extend(res.members.iter().cloned());
+
+            cursor = match res.next_cursor() {
+                Some(cursor) => Some(cursor.to_string()),
+                None => break,
+            };
         }
 
-        Ok(datas)
+        Ok(members)
+    }
+
+    async fn download_file(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let res = self
+            .http_client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .send()
+            .await
+            .context("Failed to send request")?
+            .error_for_status()
+            .context("Failed to download file")?;
+
+        Ok(res.bytes().await?.to_vec())
+    }
+
+    async fn upload_file(
+        &self,
+        channel: &str,
+        thread_ts: Option<&str>,
+        filename: &str,
+        content: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let upload_url = self
+            .http_client
+            .get("https://slack.com/api/files.getUploadURLExternal")
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .query(&[
+                ("filename", filename.to_string()),
+                ("length", content.len().to_string()),
+            ])
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<slack::GetUploadUrlResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        let (upload_url, file_id) = match (upload_url.upload_url, upload_url.file_id) {
+            (Some(upload_url), Some(file_id)) => (upload_url, file_id),
+            _ => {
+                return Err(anyhow!(
+                    "files.getUploadURLExternal failed: {:?}",
+                    upload_url.error
+                ))
+            }
+        };
+
+        self.http_client
+            .post(upload_url)
+            .body(content)
+            .send()
+            .await
+            .context("Failed to upload file")?
+            .error_for_status()
+            .context("Failed to upload file")?;
+
+        let completed = self
+            .http_client
+            .post("https://slack.com/api/files.completeUploadExternal")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .json(&slack::CompleteUploadExternal {
+                files: vec![slack::UploadedFile {
+                    id: &file_id,
+                    title: filename,
+                }],
+                channel_id: channel,
+                thread_ts,
+            })
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<slack::CompleteUploadResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        if !completed.ok {
+            return Err(anyhow!(
+                "files.completeUploadExternal failed: {:?}",
+                completed.error
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn add_reaction(&self, channel: &str, ts: &str, name: &str) -> anyhow::Result<()> {
+        let res = self
+            .http_client
+            .post("https://slack.com/api/reactions.add")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .json(&slack::AddReaction {
+                channel,
+                timestamp: ts,
+                name,
+            })
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<slack::AddReactionResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        if !res.ok {
+            return Err(anyhow!("reactions.add failed: {:?}", res.error));
+        }
+
+        Ok(())
+    }
+
+    async fn send_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        thread_ts: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let res = self
+            .http_client
+            .post("https://slack.com/api/chat.postEphemeral")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", format!("Bearer {}", &self.bot_token))
+            .json(&slack::PostEphemeral {
+                channel,
+                user,
+                text,
+                thread_ts,
+            })
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json::<slack::PostEphemeralResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        if !res.ok {
+            return Err(anyhow!("chat.postEphemeral failed: {:?}", res.error));
+        }
+
+        Ok(())
+    }
+
+    async fn get_all_tools_metadata(
+        &self,
+    ) -> anyhow::Result<Vec<modules::llm::tools::ToolMetadata>> {
+        Ok(self.mcp.tools_metadata())
     }
 
     async fn call_mcp_tool(
@@ -465,19 +907,21 @@ impl Bot for DittoBot {
         unified_name: &str,
         arguments: HashMap<String, serde_json::Value>,
     ) -> anyhow::Result<String> {
-        let (tool_name, client) = self
-            .mcp_tools
-            .get(unified_name)
-            .ok_or_else(|| anyhow!("MCP tool not found"))?;
+        let (tool_name, client) = self.mcp.tool(unified_name)?;
 
         let mut tool_arguments = serde_json::Map::new();
 
         for (key, value) in arguments.iter() {
+            // Strict mode makes the model fill optional arguments with null, meaning left out
+            if value.is_null() {
+                continue;
+            }
+
             tool_arguments.insert(key.clone(), value.clone());
         }
 
         let params = CallToolRequestParam {
-            name: tool_name.clone(),
+            name: tool_name,
             arguments: Some(tool_arguments),
         };
 
@@ -511,6 +955,115 @@ impl DittoBot {
 
         Ok(())
     }
+
+    async fn slack_reaction_handler(&self, reaction: ReactionEvent) -> anyhow::Result<()> {
+        if generation::STOP_REACTIONS.contains(&reaction.reaction.as_str()) {
+            self.stop_generation(&reaction.channel, &reaction.item_ts, &reaction.user);
+        } else {
+            modules::invoke_all_reaction_handlers(self, reaction).await;
+        }
+
+        Ok(())
+    }
+
+    async fn slack_edit_handler(&self, edit: EditEvent) -> anyhow::Result<()> {
+        modules::invoke_all_edit_handlers(self, edit).await;
+
+        Ok(())
+    }
+
+    async fn slack_action_handler(&self, action: ActionEvent) -> anyhow::Result<()> {
+        if action.action_id == generation::STOP_ACTION_ID {
+            self.stop_generation(&action.channel, &action.message_ts, &action.user);
+        } else {
+            modules::invoke_all_action_handlers(self, action).await;
+        }
+
+        Ok(())
+    }
+
+    fn stop_generation(&self, channel: &str, ts: &str, user: &str) {
+        match self
+            .generations
+            .stop(channel, ts, user, self.is_admin(user))
+        {
+            generation::StopResult::Stopped => {
+                info!("Generation {} in {} stopped by {}", ts, channel, user);
+            }
+            generation::StopResult::NotAllowed => {
+                warn!(
+                    "User {} is not allowed to stop generation {} in {}",
+                    user, ts, channel
+                );
+            }
+            generation::StopResult::NotFound => {
+                debug!("No generation in progress for {} in {}", ts, channel);
+            }
+        }
+    }
+
+    fn dispatch_event(
+        self: &Arc<Self>,
+        event: &slack::InternalEvent,
+    ) -> Result<(), ConvertMessageEventError> {
+        let bot = self.clone();
+
+        match event {
+            slack::InternalEvent::ReactionAdded(reaction) => {
+                let reaction = reaction.try_into()?;
+
+                tokio::task::spawn(async move {
+                    if let Err(e) = bot.slack_reaction_handler(reaction).await {
+                        error!("Error occured while handling slack reaction - {:?}", e);
+                    }
+                });
+            }
+            slack::InternalEvent::Message(slack::Message::TaggedMessage(
+                slack::TaggedMessage::MessageChanged(_) | slack::TaggedMessage::MessageDeleted(_),
+            )) => {
+                let edit = event.try_into()?;
+
+                tokio::task::spawn(async move {
+                    if let Err(e) = bot.slack_edit_handler(edit).await {
+                        error!("Error occured while handling slack edit - {:?}", e);
+                    }
+                });
+            }
+            _ => {
+                let msg = event.try_into()?;
+
+                tokio::task::spawn(async move {
+                    if let Err(e) = bot.slack_event_handler(msg).await {
+                        error!("Error occured while handling slack event - {:?}", e);
+                    }
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dispatch_interactive(self: &Arc<Self>, payload: &slack::InteractivePayload) {
+        let actions = match payload {
+            slack::InteractivePayload::BlockActions(block_actions) => {
+                ActionEvent::from_block_actions(block_actions)
+            }
+            slack::InteractivePayload::Unknown => {
+                debug!("Unsupported interactive payload - {:?}", payload);
+                return;
+            }
+        };
+
+        for action in actions {
+            let bot = self.clone();
+
+            tokio::task::spawn(async move {
+                if let Err(e) = bot.slack_action_handler(action).await {
+                    error!("Error occured while handling slack action - {:?}", e);
+                }
+            });
+        }
+    }
 }
 
 #[cfg(feature = "check-req")]
@@ -539,7 +1092,7 @@ impl IntoResponse for HttpResponse {
     }
 }
 
-async fn http_handler<'a>(
+async fn http_handler(
     Extension(bot): Extension<Arc<DittoBot>>,
     Json(event): Json<slack::SlackEvent>,
 ) -> HttpResponse {
@@ -548,15 +1101,8 @@ async fn http_handler<'a>(
     match event {
         slack::SlackEvent::UrlVerification { challenge, .. } => HttpResponse::Challenge(challenge),
         slack::SlackEvent::EventCallback(event_callback) => {
-            match (&event_callback.event).try_into() {
-                Ok(msg) => {
-                    tokio::task::spawn(async move {
-                        if let Err(e) = bot.slack_event_handler(msg).await {
-                            error!("Error occured while handling slack event - {:?}", e);
-                        }
-                    });
-                    HttpResponse::Ok
-                }
+            match bot.dispatch_event(&event_callback.event) {
+                Ok(_) => HttpResponse::Ok,
                 Err(e) => {
                     match e {
                         ConvertMessageEventError::Unsupported(_) => {
@@ -578,6 +1124,31 @@ async fn http_handler<'a>(
     }
 }
 
+#[derive(serde::Deserialize)]
+struct InteractiveForm {
+    payload: String,
+}
+
+async fn interactive_handler(
+    Extension(bot): Extension<Arc<DittoBot>>,
+    Form(form): Form<InteractiveForm>,
+) -> HttpResponse {
+    match serde_json::from_str::<slack::InteractivePayload>(&form.payload) {
+        Ok(payload) => {
+            debug!("Parsed interactive payload: {:?}", payload);
+
+            bot.dispatch_interactive(&payload);
+
+            HttpResponse::Ok
+        }
+        Err(e) => {
+            error!("Failed to parse interactive payload - {:?}", e);
+
+            HttpResponse::Error(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
 async fn socket_handler(mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>, bot: Arc<DittoBot>) {
     while let Some(data) = ws.next().await {
         let data = match data {
@@ -615,19 +1186,8 @@ async fn socket_handler(mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>, bot:
 
                         let payload = payload.as_ref().unwrap();
 
-                        match (&payload.event).try_into() {
-                            Ok(msg) => {
-                                let bot = bot.clone();
-
-                                tokio::task::spawn(async move {
-                                    if let Err(e) = bot.slack_event_handler(msg).await {
-                                        error!(
-                                            "Error occured while handling slack event - {:?}",
-                                            e
-                                        );
-                                    }
-                                });
-                            }
+                        match bot.dispatch_event(&payload.event) {
+                            Ok(_) => {}
                             Err(e) => match e {
                                 ConvertMessageEventError::Unsupported(_) => {
                                     debug!("Unsupported message type - {:?}", e);
@@ -638,6 +1198,11 @@ async fn socket_handler(mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>, bot:
                             },
                         }
                     }
+                    slack::SlackEvent::Interactive(interactive) => {
+                        envelope_id = interactive.envelope_id.clone();
+
+                        bot.dispatch_interactive(&interactive.payload);
+                    }
                     slack::SlackEvent::Hello(hello) => {
                         debug!("Hello! Number of connections: {}", hello.num_connections);
                     }
@@ -752,15 +1317,55 @@ async fn main() -> anyhow::Result<()> {
 
     let tz = env::var("TZ").unwrap_or("Asia/Seoul".to_string());
 
+    // Run as an MCP server for a local client instead of as a Slack bot
+    let is_mcp_stdio = env::args().any(|arg| arg == "--mcp-stdio");
+
     let is_socket_mode = socket_mode == "1" || socket_mode.to_lowercase() == "true";
     info!("Is socket mode: {:?}", is_socket_mode);
 
-    let app = axum::Router::new().route(
-        "/",
-        axum::routing::on(MethodFilter::POST | MethodFilter::GET, http_handler),
+    let admin_users = env::var("ADMIN_USERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+        .collect::<HashSet<_>>();
+    info!("Admin users: {:?}", admin_users);
+
+    let tool_policies = modules::llm::approval::ToolPolicies::parse(
+        &env::var("MCP_TOOL_APPROVAL").unwrap_or_default(),
     );
+    info!("MCP tool approval policies: {:?}", tool_policies);
+
+    let config_path = env::var("CONFIG_PATH").unwrap_or("config.json".to_string());
+    let config = config::Config::load(&config_path)?;
+    info!("Config from {}: {:?}", config_path, config);
+
+    // Search is not worth keeping the bot down for
+    let search_index = modules::search::SearchIndex::from_env(&openai_key).unwrap_or_else(|e| {
+        error!(
+            "Failed to set up the search index, so nothing is indexed - {:?}",
+            e
+        );
+        Default::default()
+    });
+
+    let app = axum::Router::new()
+        .route(
+            "/",
+            axum::routing::on(MethodFilter::POST | MethodFilter::GET, http_handler),
+        )
+        .route("/interactive", axum::routing::post(interactive_handler));
 
-    let mcp_clients = DittoBot::create_mcp_clients(tz).await;
+    let mcp_servers_path = env::var("MCP_SERVERS_PATH").unwrap_or("mcp_servers.json".to_string());
+    let mcp_servers = if is_mcp_stdio {
+        Default::default()
+    } else {
+        mcp::McpServersConfig::load(&mcp_servers_path, &tz)?
+    };
+    info!("MCP servers from {}: {:?}", mcp_servers_path, mcp_servers);
+
+    let mcp = Arc::new(mcp::supervisor::McpRegistry::start(mcp_servers).await);
+    tokio::spawn(mcp.clone().supervise());
 
     let bot = Arc::new(
         DittoBot::new(
@@ -768,14 +1373,75 @@ async fn main() -> anyhow::Result<()> {
             bot_token.clone(),
             openai_key.clone(),
             gemini_key.clone(),
-            mcp_clients,
+            admin_users,
+            tool_policies,
+            mcp,
         )
-        .await,
+        .with_config(config)
+        .with_search_index(search_index),
     );
 
+    if is_mcp_stdio {
+        info!("Start serving MCP over stdio.");
+        return mcp::server::serve_stdio(bot).await;
+    }
+
+    let mcp_router = match (&bot.config.mcp_server, env::var("MCP_SERVER_TOKEN")) {
+        (Some(access), Ok(token)) if !token.is_empty() => {
+            info!("Serving MCP over SSE for channels {:?}", access.channels);
+            Some(Arc::new(mcp::server::SseServer::new(bot.clone(), token)).router())
+        }
+        (Some(_), _) => {
+            warn!("MCP_SERVER_TOKEN is not given, so MCP is not served over SSE");
+            None
+        }
+        (None, _) => None,
+    };
+
+    scheduler::start(bot.clone(), &bot.config.schedules, &tz);
+
+    tokio::spawn({
+        let bot = bot.clone();
+
+        async move {
+            if let Err(e) = modules::search::backfill(bot.as_ref()).await {
+                error!("Failed to backfill the search index - {:?}", e);
+            }
+        }
+    });
+
     if is_socket_mode {
         info!("Start using slack socket mode.");
 
+        // Slack doesn't need the HTTP server in socket mode, but MCP clients do
+        if let Some(mcp_router) = mcp_router {
+            // Empty, as docker compose passes it when unset, is the same as missing
+            let addr = env::var("MCP_SERVER_ADDR")
+                .ok()
+                .filter(|addr| !addr.is_empty())
+                .unwrap_or("0.0.0.0:8082".to_string());
+            // A bad address only costs MCP, not the Slack bot
+            let server = addr
+                .parse::<std::net::SocketAddr>()
+                .context("Invalid MCP_SERVER_ADDR")
+                .and_then(|addr| axum::Server::try_bind(&addr).context("Failed to bind"));
+
+            match server {
+                Ok(server) => {
+                    info!("Serving MCP over SSE at {}", addr);
+
+                    tokio::spawn(async move {
+                        let served = server.serve(mcp_router.into_make_service()).await;
+
+                        if let Err(e) = served {
+                            error!("Failed to serve MCP over SSE - {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("MCP is not served over SSE at {} - {:?}", addr, e),
+            }
+        }
+
         let ws = connect_slack_socket(&app_token)
             .await
             .context("Failed to connect to slack socket")?;
@@ -791,6 +1457,12 @@ async fn main() -> anyhow::Result<()> {
             auth::SlackAuthorization::new(signing_secret)
         }));
 
+        // After the Slack signature check, which MCP clients can't pass
+        let app = match mcp_router {
+            Some(mcp_router) => app.merge(mcp_router),
+            None => app,
+        };
+
         let use_ssl = env::var("USE_SSL")
             .ok()
             .and_then(|v| {
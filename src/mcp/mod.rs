@@ -0,0 +1,254 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context as _};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use rmcp::{
+    service::RunningService,
+    transport::{SseTransport, TokioChildProcess},
+    RoleClient, ServiceExt,
+};
+use serde::Deserialize;
+use tokio::process::Command;
+
+pub mod resources;
+pub mod server;
+pub mod streamable_http;
+pub mod supervisor;
+
+pub type McpClient = RunningService<RoleClient, resources::NotificationHandler>;
+
+static ENV_REGEX: OnceCell<Regex> = OnceCell::new();
+
+/// MCP servers the bot calls as tools, in the format most MCP clients use.
+///
+/// `${NAME}` in arguments, environment values, URLs and headers is replaced with the environment variable.
+///
+/// ```json
+/// {
+///   "mcpServers": {
+///     "time": { "command": "uvx", "args": ["mcp-server-time", "--local-timezone", "${TZ}"] },
+///     "docs": { "type": "sse", "url": "http://localhost:8000/sse" },
+///     "issues": {
+///       "type": "http",
+///       "url": "https://issues.example.com/mcp",
+///       "headers": { "Authorization": "Bearer ${ISSUES_TOKEN}" },
+///       "disabled": true
+///     }
+///   }
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct McpServersConfig {
+    #[serde(rename = "mcpServers", alias = "mcp_servers", default)]
+    pub servers: BTreeMap<String, McpServerConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum McpTransport {
+    /// A child process talking over its stdin and stdout.
+    Stdio,
+    Sse,
+    #[serde(alias = "streamable_http", alias = "streamable-http")]
+    Http,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct McpServerConfig {
+    /// Stdio if there is a command, else HTTP, if missing.
+    #[serde(rename = "type", default)]
+    pub transport: Option<McpTransport>,
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Only sent by the HTTP transport.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl McpServerConfig {
+    pub fn transport(&self) -> McpTransport {
+        self.transport.unwrap_or(if self.command.is_some() {
+            McpTransport::Stdio
+        } else {
+            McpTransport::Http
+        })
+    }
+
+    fn url(&self) -> anyhow::Result<String> {
+        let url = self
+            .url
+            .as_deref()
+            .ok_or_else(|| anyhow!("`url` is missing"))?;
+
+        expand_env(url)
+    }
+}
+
+/// Replaces `${NAME}` with the environment variable. Unset variables are an error.
+fn expand_env(text: &str) -> anyhow::Result<String> {
+    let regex = ENV_REGEX.get_or_try_init(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}"))?;
+
+    let mut missing = None;
+    let expanded = regex.replace_all(text, |captures: &regex::Captures| {
+        env::var(&captures[1]).unwrap_or_else(|_| {
+            missing = Some(captures[1].to_string());
+            String::new()
+        })
+    });
+
+    match missing {
+        Some(name) => Err(anyhow!("Environment variable {} is not set", name)),
+        None => Ok(expanded.into_owned()),
+    }
+}
+
+impl McpServersConfig {
+    /// The servers used before there was a config file.
+    fn builtin(tz: &str) -> Self {
+        let server = |args: &[&str]| McpServerConfig {
+            command: Some("uvx".to_string()),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            ..Default::default()
+        };
+
+        Self {
+            servers: BTreeMap::from([
+                (
+                    "mcp-server-time".to_string(),
+                    server(&["mcp-server-time", "--local-timezone", tz]),
+                ),
+                (
+                    "mcp-server-fetch".to_string(),
+                    server(&["mcp-server-fetch"]),
+                ),
+            ]),
+        }
+    }
+
+    /// Reads the servers from a JSON file, or uses the built-in ones if it doesn't exist.
+    pub fn load(path: &str, tz: &str) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                serde_json::from_str(&content).context("Failed to parse MCP servers file")
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::builtin(tz)),
+            Err(e) => Err(e).context("Failed to read MCP servers file"),
+        }
+    }
+}
+
+pub async fn connect(
+    server: &McpServerConfig,
+    handler: resources::NotificationHandler,
+) -> anyhow::Result<McpClient> {
+    match server.transport() {
+        McpTransport::Stdio => {
+            let command = server
+                .command
+                .as_deref()
+                .ok_or_else(|| anyhow!("`command` is missing"))?;
+
+            let mut command = Command::new(command);
+
+            for arg in &server.args {
+                command.arg(expand_env(arg)?);
+            }
+
+            for (name, value) in &server.env {
+                command.env(name, expand_env(value)?);
+            }
+
+            if let Some(cwd) = &server.cwd {
+                command.current_dir(cwd);
+            }
+
+            Ok(handler.serve(TokioChildProcess::new(&mut command)?).await?)
+        }
+        McpTransport::Sse => {
+            let transport = SseTransport::start(server.url()?).await?;
+
+            Ok(handler.serve(transport).await?)
+        }
+        McpTransport::Http => {
+            let headers = server
+                .headers
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), expand_env(value)?)))
+                .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+            let transport = streamable_http::transport(&server.url()?, &headers)?;
+
+            Ok(handler.serve(transport).await?)
+        }
+    }
+}
+
+#[test]
+fn test_parse_mcp_servers() {
+    let config = serde_json::from_str::<McpServersConfig>(
+        r#"{
+            "mcpServers": {
+                "time": { "command": "uvx", "args": ["mcp-server-time"], "cwd": "/tmp" },
+                "docs": { "type": "sse", "url": "http://localhost:8000/sse" },
+                "issues": {
+                    "url": "https://issues.example.com/mcp",
+                    "headers": { "Authorization": "Bearer token" },
+                    "disabled": true
+                },
+                "wiki": { "type": "streamable_http", "url": "https://wiki.example.com/mcp" }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let transports = config
+        .servers
+        .iter()
+        .map(|(name, server)| (name.as_str(), server.transport(), server.disabled))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        transports,
+        vec![
+            ("docs", McpTransport::Sse, false),
+            ("issues", McpTransport::Http, true),
+            ("time", McpTransport::Stdio, false),
+            ("wiki", McpTransport::Http, false),
+        ]
+    );
+
+    let builtin = McpServersConfig::builtin("Asia/Seoul");
+
+    assert_eq!(
+        builtin.servers["mcp-server-time"].args,
+        vec!["mcp-server-time", "--local-timezone", "Asia/Seoul"]
+    );
+}
+
+#[test]
+fn test_expand_env() {
+    env::set_var("DITTO_TEST_TOKEN", "secret");
+
+    assert_eq!(
+        expand_env("Bearer ${DITTO_TEST_TOKEN}").unwrap(),
+        "Bearer secret"
+    );
+    assert_eq!(expand_env("$HOME ${").unwrap(), "$HOME ${");
+    assert!(expand_env("${DITTO_TEST_UNSET_VARIABLE}").is_err());
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::info;
+use rmcp::{
+    model::{ResourceContents, ResourceUpdatedNotificationParam},
+    ClientHandler, Peer, RoleClient,
+};
+
+/// Longer resources are cut, as they go into every prompt they're attached to.
+const MAX_RESOURCE_CHARS: usize = 30_000;
+// Listings are read again after this, for servers which don't say when they change
+const LISTING_TTL: Duration = Duration::from_secs(5 * 60);
+
+struct Subscription {
+    server: String,
+    /// None until read, and again once the server says it changed.
+    text: Option<String>,
+}
+
+/// The resources and resource templates a server lists.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Listing {
+    pub uris: Vec<String>,
+    pub templates: Vec<String>,
+}
+
+/// Contents of the resources the bot is subscribed to, kept until their server says they changed,
+/// and what each server lists, so finding the server of a resource doesn't ask all of them.
+#[derive(Default)]
+pub struct ResourceCache {
+    subscriptions: Mutex<HashMap<String, Subscription>>,
+    listings: Mutex<HashMap<String, (Instant, Listing)>>,
+}
+
+impl ResourceCache {
+    /// The text of the resource with the name of its server.
+    pub fn get(&self, uri: &str) -> Option<(String, String)> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .get(uri)
+            .and_then(|subscription| {
+                Some((subscription.server.clone(), subscription.text.clone()?))
+            })
+    }
+
+    pub fn is_subscribed(&self, uri: &str) -> bool {
+        self.subscriptions.lock().unwrap().contains_key(uri)
+    }
+
+    pub fn insert(&self, uri: &str, server: &str, text: String) {
+        self.subscriptions.lock().unwrap().insert(
+            uri.to_string(),
+            Subscription {
+                server: server.to_string(),
+                text: Some(text),
+            },
+        );
+    }
+
+    fn invalidate(&self, uri: &str) {
+        if let Some(subscription) = self.subscriptions.lock().unwrap().get_mut(uri) {
+            subscription.text = None;
+        }
+    }
+
+    /// The listing of the server, unless it is older than `LISTING_TTL`.
+    pub fn listing(&self, server: &str) -> Option<Listing> {
+        self.listings
+            .lock()
+            .unwrap()
+            .get(server)
+            .filter(|(listed_at, _)| listed_at.elapsed() < LISTING_TTL)
+            .map(|(_, listing)| listing.clone())
+    }
+
+    pub fn insert_listing(&self, server: &str, listing: Listing) {
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(server.to_string(), (Instant::now(), listing));
+    }
+
+    fn forget_listing(&self, server: &str) {
+        self.listings.lock().unwrap().remove(server);
+    }
+
+    /// Subscriptions end with the connection to their server.
+    pub fn forget_server(&self, server: &str) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|_, subscription| subscription.server != server);
+        self.forget_listing(server);
+    }
+}
+
+/// Handles what a server sends without being asked.
+#[derive(Clone)]
+pub struct NotificationHandler {
+    server: String,
+    resources: Arc<ResourceCache>,
+    peer: Option<Peer<RoleClient>>,
+}
+
+impl NotificationHandler {
+    pub fn new(server: &str, resources: Arc<ResourceCache>) -> Self {
+        Self {
+            server: server.to_string(),
+            resources,
+            peer: None,
+        }
+    }
+}
+
+impl ClientHandler for NotificationHandler {
+    async fn on_resource_updated(&self, params: ResourceUpdatedNotificationParam) {
+        info!("MCP server {} updated {}", self.server, params.uri);
+        self.resources.invalidate(&params.uri);
+    }
+
+    async fn on_resource_list_changed(&self) {
+        info!("MCP server {} changed its resources", self.server);
+        self.resources.forget_listing(&self.server);
+    }
+
+    fn get_peer(&self) -> Option<Peer<RoleClient>> {
+        self.peer.clone()
+    }
+
+    fn set_peer(&mut self, peer: Peer<RoleClient>) {
+        self.peer = Some(peer);
+    }
+}
+
+/// Whether the URI is one of the template's, as in RFC 6570.
+/// `{name}` matches within a path segment, and `{+name}`, `{#name}` and `{/name}` across them.
+pub fn template_matches(template: &str, uri: &str) -> bool {
+    let mut pattern = String::from("^");
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        pattern += &regex::escape(&rest[..start]);
+
+        let expression = &rest[start + 1..end];
+        pattern += match expression.chars().next() {
+            Some('+') | Some('#') | Some('/') => ".*",
+            Some('?') | Some('&') => r"(\?.*)?",
+            _ => "[^/?#]*",
+        };
+
+        rest = &rest[end + 1..];
+    }
+
+    pattern += &regex::escape(rest);
+    pattern += "$";
+
+    regex::Regex::new(&pattern).is_ok_and(|regex| regex.is_match(uri))
+}
+
+/// The text of a resource, with binary parts only described.
+pub fn contents_text(contents: &[ResourceContents]) -> String {
+    let text = contents
+        .iter()
+        .map(|content| match content {
+            ResourceContents::TextResourceContents { text, .. } => text.clone(),
+            ResourceContents::BlobResourceContents { uri, mime_type, .. } => format!(
+                "({} is binary, of type {})",
+                uri,
+                mime_type.as_deref().unwrap_or("unknown")
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    match text.char_indices().nth(MAX_RESOURCE_CHARS) {
+        Some((end, _)) => format!(
+            "{}\n(cut at {} characters)",
+            &text[..end],
+            MAX_RESOURCE_CHARS
+        ),
+        None => text,
+    }
+}
+
+#[test]
+fn test_template_matches() {
+    assert!(template_matches(
+        "test://static/resource/{id}",
+        "test://static/resource/12"
+    ));
+    assert!(!template_matches(
+        "test://static/resource/{id}",
+        "test://static/resource/12/more"
+    ));
+    assert!(template_matches("file://{+path}", "file:///etc/hosts"));
+    assert!(template_matches(
+        "repo://{owner}/{repo}/issues{?state}",
+        "repo://ditto/bot/issues?state=open"
+    ));
+    assert!(template_matches("a.b://{x}", "a.b://y"));
+    assert!(!template_matches("a.b://{x}", "aXb://y"));
+}
+
+#[test]
+fn test_resource_cache() {
+    let cache = ResourceCache::default();
+
+    cache.insert("test://a", "everything", "A".to_string());
+    assert_eq!(
+        cache.get("test://a"),
+        Some(("everything".to_string(), "A".to_string()))
+    );
+
+    cache.invalidate("test://a");
+    assert_eq!(cache.get("test://a"), None);
+    assert!(cache.is_subscribed("test://a"));
+
+    let listing = Listing {
+        uris: vec!["test://a".to_string()],
+        templates: vec!["test://{id}".to_string()],
+    };
+
+    cache.insert_listing("everything", listing.clone());
+    assert_eq!(cache.listing("everything"), Some(listing));
+    assert_eq!(cache.listing("other"), None);
+
+    cache.forget_server("everything");
+    assert!(!cache.is_subscribed("test://a"));
+    assert_eq!(cache.listing("everything"), None);
+}
@@ -0,0 +1,568 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json,
+};
+use futures::{channel::mpsc, stream, SinkExt, StreamExt};
+use log::{error, info, warn};
+use rand::Rng;
+use rmcp::{
+    model::{
+        CallToolRequestParam, CallToolResult, ClientJsonRpcMessage, Content, Implementation,
+        JsonObject, ListToolsResult, PaginatedRequestParam, ServerCapabilities, ServerInfo, Tool,
+    },
+    service::RequestContext,
+    Error as McpError, RoleServer, ServerHandler, ServiceExt,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::McpServerAccess,
+    modules::{
+        llm::summarize::{fetch_messages, replace_mentions, user_name},
+        search,
+    },
+    Bot, Message, ReplyMessageEvent,
+};
+
+const SEARCH_RESULT_COUNT: usize = 10;
+
+/// The bot's own tools for other agents, acting with its Slack credentials
+/// in the channels and with the tools allowed by `mcp_server` in the config.
+pub struct DittoMcpServer<B> {
+    bot: Arc<B>,
+}
+
+impl<B> Clone for DittoMcpServer<B> {
+    fn clone(&self) -> Self {
+        Self {
+            bot: self.bot.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PostMessageArguments {
+    channel: String,
+    text: String,
+    #[serde(default)]
+    thread_ts: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReadThreadArguments {
+    channel: String,
+    thread_ts: String,
+}
+
+#[derive(Deserialize)]
+struct SearchMessagesArguments {
+    channel: String,
+    query: String,
+}
+
+#[derive(Deserialize)]
+struct AddReactionArguments {
+    channel: String,
+    ts: String,
+    name: String,
+}
+
+fn tool(name: &'static str, description: &'static str, schema: serde_json::Value) -> Tool {
+    let schema = match schema {
+        serde_json::Value::Object(schema) => schema,
+        _ => JsonObject::new(),
+    };
+
+    Tool::new(name, description, schema)
+}
+
+fn all_tools() -> Vec<Tool> {
+    let channel = json!({ "type": "string", "description": "Slack channel id, like C0123456" });
+
+    vec![
+        tool(
+            "post_message",
+            "Posts a message to a Slack channel, or as a reply in a thread if `thread_ts` is given.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "channel": channel,
+                    "text": { "type": "string", "description": "Slack mrkdwn text" },
+                    "thread_ts": { "type": "string", "description": "Timestamp of the thread's first message" },
+                },
+                "required": ["channel", "text"],
+            }),
+        ),
+        tool(
+            "read_thread",
+            "Reads every message of a Slack thread, oldest first.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "channel": channel,
+                    "thread_ts": { "type": "string", "description": "Timestamp of the thread's first message" },
+                },
+                "required": ["channel", "thread_ts"],
+            }),
+        ),
+        tool(
+            "list_channels",
+            "Lists the Slack channels this server may read and write.",
+            json!({ "type": "object", "properties": {} }),
+        ),
+        tool(
+            "search_messages",
+            "Searches past Slack messages by meaning, returning the closest ones with links.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "channel": channel,
+                    "query": { "type": "string", "description": "What to look for" },
+                },
+                "required": ["channel", "query"],
+            }),
+        ),
+        tool(
+            "add_reaction",
+            "Reacts to a Slack message with an emoji.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "channel": channel,
+                    "ts": { "type": "string", "description": "Timestamp of the message" },
+                    "name": { "type": "string", "description": "Emoji name without colons, like thumbsup" },
+                },
+                "required": ["channel", "ts", "name"],
+            }),
+        ),
+    ]
+}
+
+/// The tools `access` allows.
+fn tools(access: &McpServerAccess) -> Vec<Tool> {
+    all_tools()
+        .into_iter()
+        .filter(|tool| access.allows_tool(&tool.name))
+        .collect()
+}
+
+fn arguments<T: DeserializeOwned>(arguments: Option<JsonObject>) -> Result<T, McpError> {
+    serde_json::from_value(serde_json::Value::Object(arguments.unwrap_or_default()))
+        .map_err(|e| McpError::invalid_params(e.to_string(), None))
+}
+
+fn check_channel(access: &McpServerAccess, channel: &str) -> Result<(), McpError> {
+    if access.allows_channel(channel) {
+        Ok(())
+    } else {
+        Err(McpError::invalid_params(
+            format!("Channel {} is not allowed", channel),
+            None,
+        ))
+    }
+}
+
+impl<B: Bot + Send + Sync + 'static> DittoMcpServer<B> {
+    pub fn new(bot: Arc<B>) -> Self {
+        Self { bot }
+    }
+
+    fn access(&self) -> Result<&McpServerAccess, McpError> {
+        self.bot
+            .config()
+            .mcp_server
+            .as_ref()
+            .ok_or_else(|| McpError::internal_error("The MCP server is disabled", None))
+    }
+
+    async fn post_message(&self, arguments: PostMessageArguments) -> anyhow::Result<String> {
+        let reply = arguments.thread_ts.map(|thread_ts| ReplyMessageEvent {
+            msg: thread_ts,
+            broadcast: false,
+        });
+
+        let res = self
+            .bot
+            .send_message(
+                &arguments.channel,
+                Message::Text(&arguments.text),
+                reply,
+                None,
+            )
+            .await?;
+
+        match res.ts {
+            Some(ts) => Ok(format!("Posted the message, with ts {}", String::from(&ts))),
+            None => Err(anyhow!("chat.postMessage failed: {:?}", res.error)),
+        }
+    }
+
+    async fn read_thread(&self, arguments: ReadThreadArguments) -> anyhow::Result<String> {
+        let messages = fetch_messages(
+            self.bot.as_ref(),
+            &arguments.channel,
+            Some(&arguments.thread_ts),
+            None,
+        )
+        .await?;
+
+        let mut names = HashMap::new();
+        let mut lines = vec![];
+
+        for message in messages {
+            let author = match (&message.user, &message.username) {
+                (Some(user), _) => user_name(self.bot.as_ref(), &mut names, user).await,
+                (None, Some(username)) => username.clone(),
+                (None, None) => "bot".to_string(),
+            };
+            let text = replace_mentions(self.bot.as_ref(), &mut names, &message.text).await?;
+
+            lines.push(format!("[{}] {}: {}", message.ts, author, text));
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    async fn list_channels(&self, access: &McpServerAccess) -> anyhow::Result<String> {
+        let lines = self
+            .bot
+            .get_channels()
+            .await?
+            .into_iter()
+            .filter(|channel| access.allows_channel(&channel.id))
+            .map(|channel| {
+                let visibility = if channel.is_private {
+                    "private"
+                } else {
+                    "public"
+                };
+
+                format!("{} #{} ({})", channel.id, channel.name, visibility)
+            })
+            .collect::<Vec<_>>();
+
+        if lines.is_empty() {
+            return Ok("No channels.".to_string());
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    async fn search_messages(
+        &self,
+        access: &McpServerAccess,
+        arguments: SearchMessagesArguments,
+    ) -> anyhow::Result<String> {
+        // The index also searches public channels, which may not be allowed
+        let hits = self
+            .bot
+            .search_index()
+            .search(&arguments.query, &arguments.channel, SEARCH_RESULT_COUNT)
+            .await?
+            .into_iter()
+            .filter(|hit| access.allows_channel(&hit.channel))
+            .collect::<Vec<_>>();
+
+        if hits.is_empty() {
+            return Ok("No messages found.".to_string());
+        }
+
+        Ok(search::hit_lines(self.bot.as_ref(), &hits)
+            .await?
+            .join("\n"))
+    }
+
+    async fn add_reaction(&self, arguments: AddReactionArguments) -> anyhow::Result<String> {
+        let name = arguments.name.trim_matches(':');
+
+        self.bot
+            .add_reaction(&arguments.channel, &arguments.ts, name)
+            .await?;
+
+        Ok(format!("Reacted with :{}:", name))
+    }
+}
+
+impl<B: Bot + Send + Sync + 'static> ServerHandler for DittoMcpServer<B> {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            server_info: Implementation {
+                name: "ditto".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            instructions: Some("Reads and writes messages in Slack as the ditto bot.".to_string()),
+            ..Default::default()
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        Ok(ListToolsResult {
+            tools: tools(self.access()?),
+            next_cursor: None,
+        })
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let access = self.access()?;
+
+        if !access.allows_tool(&request.name) {
+            return Err(McpError::invalid_params(
+                format!("Tool {} is not allowed", request.name),
+                None,
+            ));
+        }
+
+        info!("MCP server tool call: {}", request.name);
+
+        let result = match request.name.as_ref() {
+            "post_message" => {
+                let arguments = arguments::<PostMessageArguments>(request.arguments)?;
+                check_channel(access, &arguments.channel)?;
+                self.post_message(arguments).await
+            }
+            "read_thread" => {
+                let arguments = arguments::<ReadThreadArguments>(request.arguments)?;
+                check_channel(access, &arguments.channel)?;
+                self.read_thread(arguments).await
+            }
+            "list_channels" => self.list_channels(access).await,
+            "search_messages" => {
+                let arguments = arguments::<SearchMessagesArguments>(request.arguments)?;
+                check_channel(access, &arguments.channel)?;
+                self.search_messages(access, arguments).await
+            }
+            "add_reaction" => {
+                let arguments = arguments::<AddReactionArguments>(request.arguments)?;
+                check_channel(access, &arguments.channel)?;
+                self.add_reaction(arguments).await
+            }
+            name => {
+                return Err(McpError::invalid_params(
+                    format!("Unknown tool {}", name),
+                    None,
+                ))
+            }
+        };
+
+        Ok(match result {
+            Ok(text) => CallToolResult::success(vec![Content::text(text)]),
+            Err(e) => CallToolResult::error(vec![Content::text(format!("Error: {:#}", e))]),
+        })
+    }
+}
+
+/// Serves the tools over stdin and stdout until the client leaves.
+pub async fn serve_stdio<B: Bot + Send + Sync + 'static>(bot: Arc<B>) -> anyhow::Result<()> {
+    let service = DittoMcpServer::new(bot)
+        .serve(rmcp::transport::stdio())
+        .await?;
+
+    service.waiting().await?;
+
+    Ok(())
+}
+
+/// Clients connected over SSE, each with its own session.
+pub struct SseServer<B> {
+    handler: DittoMcpServer<B>,
+    /// Clients must send it as a bearer token.
+    token: String,
+    sessions: Arc<Mutex<HashMap<String, mpsc::Sender<ClientJsonRpcMessage>>>>,
+}
+
+/// Forgets the session when its event stream is dropped, which ends the service.
+struct SessionGuard {
+    sessions: Arc<Mutex<HashMap<String, mpsc::Sender<ClientJsonRpcMessage>>>>,
+    session_id: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.session_id);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageQuery {
+    session_id: String,
+}
+
+impl<B: Bot + Send + Sync + 'static> SseServer<B> {
+    pub fn new(bot: Arc<B>, token: String) -> Self {
+        Self {
+            handler: DittoMcpServer::new(bot),
+            token,
+            sessions: Default::default(),
+        }
+    }
+
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            // In constant time, so the token can't be guessed from how fast it is refused
+            .is_some_and(|token| token.as_bytes().ct_eq(self.token.as_bytes()).into())
+    }
+
+    /// `GET /mcp/sse` opens a session and `POST /mcp/message` sends to it.
+    pub fn router<S>(self: Arc<Self>) -> axum::Router<S> {
+        axum::Router::new()
+            .route("/mcp/sse", get(sse_handler::<B>))
+            .route("/mcp/message", post(message_handler::<B>))
+            .with_state(self)
+    }
+}
+
+async fn sse_handler<B: Bot + Send + Sync + 'static>(
+    State(server): State<Arc<SseServer<B>>>,
+    headers: HeaderMap,
+) -> Response {
+    if !server.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let session_id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+
+    let (incoming_tx, incoming_rx) = mpsc::channel::<ClientJsonRpcMessage>(16);
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded();
+
+    server
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(session_id.clone(), incoming_tx);
+
+    let outgoing_tx = outgoing_tx.sink_map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e));
+
+    tokio::spawn({
+        let handler = server.handler.clone();
+        let session_id = session_id.clone();
+
+        async move {
+            info!("MCP server session {} started", session_id);
+
+            match handler.serve((outgoing_tx, incoming_rx)).await {
+                Ok(service) => {
+                    let _ = service.waiting().await;
+                }
+                Err(e) => warn!("MCP server session {} failed - {:?}", session_id, e),
+            }
+
+            info!("MCP server session {} ended", session_id);
+        }
+    });
+
+    let guard = SessionGuard {
+        sessions: server.sessions.clone(),
+        session_id: session_id.clone(),
+    };
+
+    let endpoint = Event::default()
+        .event("endpoint")
+        .data(format!("/mcp/message?sessionId={}", session_id));
+
+    let events = stream::once(async move { Ok(endpoint) }).chain(outgoing_rx.map(move |message| {
+        let _ = &guard;
+
+        Event::default().event("message").json_data(message)
+    }));
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn message_handler<B: Bot + Send + Sync + 'static>(
+    State(server): State<Arc<SseServer<B>>>,
+    Query(query): Query<MessageQuery>,
+    headers: HeaderMap,
+    Json(message): Json<ClientJsonRpcMessage>,
+) -> StatusCode {
+    if !server.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let sender = server
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&query.session_id)
+        .cloned();
+
+    match sender {
+        Some(mut sender) => match sender.send(message).await {
+            Ok(()) => StatusCode::ACCEPTED,
+            Err(e) => {
+                error!("Failed to pass a message to MCP server session - {}", e);
+                StatusCode::GONE
+            }
+        },
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[test]
+fn test_allowed_tools() {
+    let access = McpServerAccess {
+        channels: vec!["C123".to_string()],
+        tools: Some(vec!["read_thread".to_string(), "list_channels".to_string()]),
+    };
+
+    let names = |access: &McpServerAccess| {
+        tools(access)
+            .into_iter()
+            .map(|tool| tool.name.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(names(&access), vec!["read_thread", "list_channels"]);
+    assert_eq!(names(&McpServerAccess::default()).len(), 5);
+    assert!(check_channel(&access, "C123").is_ok());
+    assert!(check_channel(&access, "C456").is_err());
+    assert!(arguments::<ReadThreadArguments>(Some(JsonObject::new())).is_err());
+}
+
+#[test]
+fn test_is_authorized() {
+    let server = SseServer::new(
+        Arc::new(crate::test::MockBot::default()),
+        "secret".to_string(),
+    );
+    let headers = |authorization: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", authorization.parse().unwrap());
+        headers
+    };
+
+    assert!(server.is_authorized(&headers("Bearer secret")));
+    assert!(!server.is_authorized(&headers("Bearer secret2")));
+    assert!(!server.is_authorized(&headers("Bearer secre")));
+    assert!(!server.is_authorized(&headers("secret")));
+    assert!(!server.is_authorized(&HeaderMap::new()));
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context as _;
+use futures::{channel::mpsc, Sink, SinkExt, Stream, StreamExt};
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use rmcp::model::{
+    ClientJsonRpcMessage, ErrorData, JsonRpcError, JsonRpcMessage, JsonRpcVersion2_0,
+    ServerJsonRpcMessage,
+};
+
+const SESSION_HEADER: &str = "Mcp-Session-Id";
+
+/// Reads the `data` of server-sent events from a byte stream.
+#[derive(Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Feeds a chunk of the stream. Returns the data of the events it completed.
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = vec![];
+
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data
+                    .push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+            // Event names, ids and comments are not needed
+        }
+
+        events
+    }
+}
+
+struct Connection {
+    client: reqwest::Client,
+    url: String,
+    session_id: Mutex<Option<String>>,
+}
+
+impl Connection {
+    fn forward(
+        data: &[u8],
+        incoming: &mpsc::UnboundedSender<ServerJsonRpcMessage>,
+    ) -> anyhow::Result<()> {
+        let messages = match serde_json::from_slice::<serde_json::Value>(data)? {
+            serde_json::Value::Array(messages) => messages,
+            message => vec![message],
+        };
+
+        for message in messages {
+            incoming.unbounded_send(serde_json::from_value(message)?)?;
+        }
+
+        Ok(())
+    }
+
+    async fn try_post(
+        &self,
+        message: &ClientJsonRpcMessage,
+        incoming: &mpsc::UnboundedSender<ServerJsonRpcMessage>,
+    ) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(message);
+
+        let session_id = self.session_id.lock().unwrap().clone();
+
+        if let Some(session_id) = session_id {
+            request = request.header(SESSION_HEADER, session_id);
+        }
+
+        let res = request
+            .send()
+            .await
+            .context("Failed to send request")?
+            .error_for_status()?;
+
+        if let Some(session_id) = res
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            *self.session_id.lock().unwrap() = Some(session_id.to_string());
+        }
+
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        if content_type.starts_with("text/event-stream") {
+            let mut stream = res.bytes_stream();
+            let mut parser = SseParser::default();
+
+            while let Some(chunk) = stream.next().await {
+                for data in parser.push(&chunk?) {
+                    Self::forward(data.as_bytes(), incoming)?;
+                }
+            }
+        } else if content_type.starts_with("application/json") {
+            Self::forward(&res.bytes().await?, incoming)?;
+        }
+        // Notifications and responses are only accepted, without a body
+
+        Ok(())
+    }
+
+    async fn post(
+        &self,
+        message: ClientJsonRpcMessage,
+        incoming: mpsc::UnboundedSender<ServerJsonRpcMessage>,
+    ) {
+        if let Err(e) = self.try_post(&message, &incoming).await {
+            warn!("MCP request to {} failed - {:?}", self.url, e);
+
+            // Fails the request instead of leaving it waiting for an answer
+            if let JsonRpcMessage::Request(request) = message {
+                let _ = incoming.unbounded_send(JsonRpcMessage::Error(JsonRpcError {
+                    jsonrpc: JsonRpcVersion2_0,
+                    id: request.id,
+                    error: ErrorData::internal_error(format!("{:#}", e), None),
+                }));
+            }
+        }
+    }
+}
+
+/// Client side of the streamable HTTP transport. Every message is POSTed to the endpoint,
+/// which answers with JSON or with a stream of events.
+///
+/// Messages the server sends outside of an answer, on a GET stream, are not received.
+#[allow(clippy::type_complexity)]
+pub fn transport(
+    url: &str,
+    headers: &HashMap<String, String>,
+) -> anyhow::Result<(
+    impl Sink<ClientJsonRpcMessage, Error = io::Error> + Send + 'static,
+    impl Stream<Item = ServerJsonRpcMessage> + Send + 'static,
+)> {
+    let mut header_map = HeaderMap::new();
+
+    for (name, value) in headers {
+        header_map.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+    }
+
+    let connection = Arc::new(Connection {
+        client: reqwest::Client::builder()
+            .default_headers(header_map)
+            .build()?,
+        url: url.to_string(),
+        session_id: Mutex::new(None),
+    });
+
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<ClientJsonRpcMessage>(16);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded();
+
+    tokio::spawn(async move {
+        let mut initialized = false;
+
+        while let Some(message) = outgoing_rx.next().await {
+            let connection = connection.clone();
+            let incoming_tx = incoming_tx.clone();
+            let post = async move { connection.post(message, incoming_tx).await };
+
+            // The session id comes with the answer to the first message, which is `initialize`
+            if initialized {
+                tokio::spawn(post);
+            } else {
+                post.await;
+                initialized = true;
+            }
+        }
+    });
+
+    let outgoing_tx = outgoing_tx.sink_map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e));
+
+    Ok((outgoing_tx, incoming_rx))
+}
+
+#[test]
+fn test_sse_parser() {
+    let mut parser = SseParser::default();
+
+    assert!(parser.push(b"event: message\r\ndata: {\"a\":").is_empty());
+    assert_eq!(
+        parser.push(b"1}\r\n\r\n: keep alive\n\ndata: x\ndata: y\n\ndata: z"),
+        vec!["{\"a\":1}".to_string(), "x\ny".to_string()]
+    );
+    assert_eq!(parser.push(b"\n\n"), vec!["z".to_string()]);
+}
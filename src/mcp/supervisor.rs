@@ -0,0 +1,519 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use futures::future::join_all;
+use log::{error, info, warn};
+use rmcp::{
+    model::{
+        ClientRequest, GetPromptRequestParam, GetPromptResult, JsonObject, PingRequest,
+        PingRequestMethod, Prompt, ReadResourceRequestParam, SubscribeRequestParam,
+    },
+    Peer, RoleClient,
+};
+
+use crate::modules::llm::tools::ToolMetadata;
+
+use super::{
+    connect,
+    resources::{contents_text, template_matches, Listing, NotificationHandler, ResourceCache},
+    McpClient, McpServerConfig, McpServersConfig,
+};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(10 * 60);
+
+struct Tool {
+    name: Cow<'static, str>,
+    metadata: ToolMetadata,
+}
+
+enum ServerState {
+    Running { client: McpClient, tools: Vec<Tool> },
+    Down { retry_at: Instant },
+}
+
+struct Server {
+    config: McpServerConfig,
+    state: ServerState,
+    /// Failures since the server last answered a ping, so a server crashing right after
+    /// every restart waits longer each time.
+    failures: u32,
+}
+
+/// The running MCP servers and their tools, prompts and resources.
+/// Servers which stop answering are restarted with a growing delay,
+/// and their tools are left out until they are back.
+#[derive(Default)]
+pub struct McpRegistry {
+    servers: RwLock<BTreeMap<String, Server>>,
+    resources: Arc<ResourceCache>,
+}
+
+/// Delay before restarting a server which failed this many times in a row.
+fn backoff(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+
+    RESTART_BACKOFF_MIN
+        .saturating_mul(1 << exponent)
+        .min(RESTART_BACKOFF_MAX)
+}
+
+fn down(failures: u32) -> ServerState {
+    ServerState::Down {
+        retry_at: Instant::now() + backoff(failures),
+    }
+}
+
+/// Connects to a server and lists its tools, named `<server>_<tool>`.
+async fn start(
+    name: &str,
+    config: &McpServerConfig,
+    resources: &Arc<ResourceCache>,
+) -> anyhow::Result<ServerState> {
+    let client = connect(config, NotificationHandler::new(name, resources.clone())).await?;
+
+    // Servers with only prompts or resources don't answer the listing
+    let tools = if client.peer_info().capabilities.tools.is_some() {
+        match client.list_all_tools().await {
+            Ok(tools) => tools,
+            Err(e) => {
+                let _ = client.cancel().await;
+                return Err(anyhow!("Failed to list tools - {}", e));
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let tools = tools
+        .into_iter()
+        .map(|tool| Tool {
+            metadata: ToolMetadata::new(
+                format!("{}_{}", name, tool.name),
+                &tool.description,
+                tool.input_schema.as_ref().clone(),
+            ),
+            name: tool.name,
+        })
+        .collect();
+
+    Ok(ServerState::Running { client, tools })
+}
+
+async fn ping(peer: &Peer<RoleClient>) -> anyhow::Result<()> {
+    let request = ClientRequest::PingRequest(PingRequest {
+        method: PingRequestMethod,
+    });
+
+    tokio::time::timeout(PING_TIMEOUT, peer.send_request(request))
+        .await
+        .map_err(|_| anyhow!("No answer in {:?}", PING_TIMEOUT))??;
+
+    Ok(())
+}
+
+impl McpRegistry {
+    /// Starts every enabled server at once. Servers which fail to start are retried later.
+    pub async fn start(config: McpServersConfig) -> Self {
+        let servers = config
+            .servers
+            .into_iter()
+            .filter(|(name, server)| {
+                if server.disabled {
+                    info!("MCP server {} is disabled", name);
+                }
+
+                !server.disabled
+            })
+            .collect::<Vec<_>>();
+
+        let resources = Arc::new(ResourceCache::default());
+
+        let servers = join_all(servers.into_iter().map(|(name, config)| {
+            let resources = &resources;
+
+            async move {
+                let (state, failures) = match start(&name, &config, resources).await {
+                    Ok(state) => {
+                        info!(
+                            "Connected to MCP server {} over {:?}",
+                            name,
+                            config.transport()
+                        );
+                        (state, 0)
+                    }
+                    Err(e) => {
+                        error!("Failed to start MCP server {} - {:?}", name, e);
+                        (down(1), 1)
+                    }
+                };
+
+                (
+                    name,
+                    Server {
+                        config,
+                        state,
+                        failures,
+                    },
+                )
+            }
+        }))
+        .await;
+
+        Self {
+            servers: RwLock::new(servers.into_iter().collect()),
+            resources,
+        }
+    }
+
+    /// The tools of the servers which are running, by name.
+    pub fn tools_metadata(&self) -> Vec<ToolMetadata> {
+        let servers = self.servers.read().unwrap();
+
+        let mut tools = servers
+            .values()
+            .flat_map(|server| match &server.state {
+                ServerState::Running { tools, .. } => tools.as_slice(),
+                ServerState::Down { .. } => &[],
+            })
+            .map(|tool| tool.metadata.clone())
+            .collect::<Vec<_>>();
+
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+
+        tools
+    }
+
+    /// The server's own name for a tool and the connection to call it with.
+    pub fn tool(
+        &self,
+        unified_name: &str,
+    ) -> anyhow::Result<(Cow<'static, str>, Peer<RoleClient>)> {
+        let servers = self.servers.read().unwrap();
+
+        for server in servers.values() {
+            if let ServerState::Running { client, tools } = &server.state {
+                if let Some(tool) = tools.iter().find(|tool| tool.metadata.name == unified_name) {
+                    return Ok((tool.name.clone(), client.peer().clone()));
+                }
+            }
+        }
+
+        // The tool may be from an earlier prompt, listed before its server went down
+        for (name, server) in servers.iter() {
+            if let ServerState::Down { .. } = server.state {
+                if unified_name.starts_with(&format!("{}_", name)) {
+                    return Err(anyhow!("MCP server {} is unavailable", name));
+                }
+            }
+        }
+
+        Err(anyhow!("MCP tool not found"))
+    }
+
+    /// Pings the running servers and restarts those which are down, forever.
+    pub async fn supervise(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            self.check().await;
+        }
+    }
+
+    async fn check(&self) {
+        let peers = self.running_peers();
+
+        let pings = join_all(
+            peers
+                .iter()
+                .map(|(name, peer)| async move { (name, ping(peer).await) }),
+        )
+        .await;
+
+        for (name, result) in pings {
+            match result {
+                Ok(()) => {
+                    if let Some(server) = self.servers.write().unwrap().get_mut(name) {
+                        server.failures = 0;
+                    }
+                }
+                Err(e) => {
+                    warn!("MCP server {} is not answering - {}", name, e);
+                    self.stop(name).await;
+                }
+            }
+        }
+
+        let due = {
+            let servers = self.servers.read().unwrap();
+            let now = Instant::now();
+
+            servers
+                .iter()
+                .filter_map(|(name, server)| match server.state {
+                    ServerState::Down { retry_at } if retry_at <= now => {
+                        Some((name.clone(), server.config.clone()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (name, config) in due {
+            let result = start(&name, &config, &self.resources).await;
+
+            let mut servers = self.servers.write().unwrap();
+            let server = match servers.get_mut(&name) {
+                Some(server) => server,
+                None => continue,
+            };
+
+            match result {
+                Ok(state) => {
+                    info!("Restarted MCP server {}", name);
+                    server.state = state;
+                }
+                Err(e) => {
+                    server.failures += 1;
+                    server.state = down(server.failures);
+
+                    warn!(
+                        "Failed to restart MCP server {}, retrying in {:?} - {:?}",
+                        name,
+                        backoff(server.failures),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Marks a server down and closes its connection, killing the child process if there is one.
+    async fn stop(&self, name: &str) {
+        let state = {
+            let mut servers = self.servers.write().unwrap();
+
+            match servers.get_mut(name) {
+                Some(server) => {
+                    server.failures += 1;
+                    std::mem::replace(&mut server.state, down(server.failures))
+                }
+                None => return,
+            }
+        };
+
+        self.resources.forget_server(name);
+
+        if let ServerState::Running { client, .. } = state {
+            let _ = client.cancel().await;
+        }
+    }
+
+    fn running_peers(&self) -> Vec<(String, Peer<RoleClient>)> {
+        let servers = self.servers.read().unwrap();
+
+        servers
+            .iter()
+            .filter_map(|(name, server)| match &server.state {
+                ServerState::Running { client, .. } => Some((name.clone(), client.peer().clone())),
+                ServerState::Down { .. } => None,
+            })
+            .collect()
+    }
+
+    fn running_peer(&self, name: &str) -> anyhow::Result<Peer<RoleClient>> {
+        self.running_peers()
+            .into_iter()
+            .find(|(server, _)| server == name)
+            .map(|(_, peer)| peer)
+            .ok_or_else(|| anyhow!("MCP server {} is not running", name))
+    }
+
+    /// The prompts of the running servers, with the name of their server.
+    pub async fn prompts(&self) -> Vec<(String, Prompt)> {
+        let mut prompts = vec![];
+
+        for (name, peer) in self.running_peers() {
+            if peer.peer_info().capabilities.prompts.is_none() {
+                continue;
+            }
+
+            match peer.list_all_prompts().await {
+                Ok(server_prompts) => prompts.extend(
+                    server_prompts
+                        .into_iter()
+                        .map(|prompt| (name.clone(), prompt)),
+                ),
+                Err(e) => warn!("Failed to list the prompts of MCP server {} - {}", name, e),
+            }
+        }
+
+        prompts
+    }
+
+    pub async fn get_prompt(
+        &self,
+        server: &str,
+        name: &str,
+        arguments: JsonObject,
+    ) -> anyhow::Result<GetPromptResult> {
+        let peer = self.running_peer(server)?;
+
+        let prompt = peer
+            .get_prompt(GetPromptRequestParam {
+                name: name.to_string(),
+                arguments: Some(arguments),
+            })
+            .await?;
+
+        Ok(prompt)
+    }
+
+    /// What the server lists, from the cache unless it was listed a while ago.
+    async fn listing(&self, name: &str, peer: &Peer<RoleClient>) -> Listing {
+        if let Some(listing) = self.resources.listing(name) {
+            return listing;
+        }
+
+        let uris = match peer.list_all_resources().await {
+            Ok(resources) => resources
+                .into_iter()
+                .map(|resource| resource.raw.uri)
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to list the resources of MCP server {} - {}",
+                    name, e
+                );
+                // Not cached, so it is listed again next time
+                return Listing::default();
+            }
+        };
+
+        // Servers without templates may not answer the listing
+        let templates = match peer.list_all_resource_templates().await {
+            Ok(templates) => templates
+                .into_iter()
+                .map(|template| template.raw.uri_template)
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to list the resource templates of MCP server {} - {}",
+                    name, e
+                );
+                vec![]
+            }
+        };
+
+        let listing = Listing { uris, templates };
+        self.resources.insert_listing(name, listing.clone());
+
+        listing
+    }
+
+    /// The server with the resource, among those listing it first and then those with a matching template.
+    async fn resource_server(
+        &self,
+        uri: &str,
+        allows_server: impl Fn(&str) -> bool,
+    ) -> anyhow::Result<(String, Peer<RoleClient>)> {
+        let peers = self
+            .running_peers()
+            .into_iter()
+            .filter(|(name, peer)| {
+                allows_server(name) && peer.peer_info().capabilities.resources.is_some()
+            })
+            .collect::<Vec<_>>();
+
+        let mut listings = vec![];
+
+        for (name, peer) in peers {
+            let listing = self.listing(&name, &peer).await;
+            listings.push((name, peer, listing));
+        }
+
+        if let Some((name, peer, _)) = listings
+            .iter()
+            .find(|(_, _, listing)| listing.uris.iter().any(|listed| listed == uri))
+        {
+            return Ok((name.clone(), peer.clone()));
+        }
+
+        if let Some((name, peer, _)) = listings.iter().find(|(_, _, listing)| {
+            listing
+                .templates
+                .iter()
+                .any(|template| template_matches(template, uri))
+        }) {
+            return Ok((name.clone(), peer.clone()));
+        }
+
+        Err(anyhow!("No allowed MCP server has the resource {}", uri))
+    }
+
+    /// Reads a resource of the servers `allows_server` accepts as text. Resources of servers
+    /// which allow it are subscribed to, and read again only once the server says they changed.
+    pub async fn read_resource(
+        &self,
+        uri: &str,
+        allows_server: impl Fn(&str) -> bool,
+    ) -> anyhow::Result<String> {
+        if let Some((server, text)) = self.resources.get(uri) {
+            if allows_server(&server) {
+                return Ok(text);
+            }
+        }
+
+        let (server, peer) = self.resource_server(uri, allows_server).await?;
+
+        let result = peer
+            .read_resource(ReadResourceRequestParam {
+                uri: uri.to_string(),
+            })
+            .await?;
+        let text = contents_text(&result.contents);
+
+        let can_subscribe = peer
+            .peer_info()
+            .capabilities
+            .resources
+            .as_ref()
+            .and_then(|resources| resources.subscribe)
+            .unwrap_or(false);
+
+        if self.resources.is_subscribed(uri) {
+            self.resources.insert(uri, &server, text.clone());
+        } else if can_subscribe {
+            match peer
+                .subscribe(SubscribeRequestParam {
+                    uri: uri.to_string(),
+                })
+                .await
+            {
+                Ok(()) => self.resources.insert(uri, &server, text.clone()),
+                Err(e) => warn!("Failed to subscribe to {} of {} - {}", uri, server, e),
+            }
+        }
+
+        Ok(text)
+    }
+}
+
+#[test]
+fn test_backoff() {
+    assert_eq!(backoff(1), Duration::from_secs(5));
+    assert_eq!(backoff(2), Duration::from_secs(10));
+    assert_eq!(backoff(4), Duration::from_secs(40));
+    assert_eq!(backoff(8), RESTART_BACKOFF_MAX);
+    assert_eq!(backoff(u32::MAX), RESTART_BACKOFF_MAX);
+}
@@ -1,14 +1,34 @@
-use std::{borrow::Cow, collections::HashMap, env};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    env,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use futures::StreamExt;
 use log::{debug, error};
 use reqwest_eventsource::{Event, EventSource};
 use serde::{Deserialize, Serialize};
+use tokio::time::{self, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    slack::{BlockElement, PostMessageResponse, SectionBlock, ThreadMessageType},
+    config::RemoteMcpApproval,
+    slack::{BlockElement, ContextBlock, SectionBlock, ThreadMessageType},
     Bot, Message, ReplyMessageEvent,
 };
+
+use super::llm::{
+    add_usage, answer_blocks, answer_text, apply_citations, approval, call_tool, command,
+    post_final_answer, throttle::EditThrottle, tools_metadata, AnswerControls, AnswerVersion,
+    Citation, LlmOptions, LlmProvider, LlmRequest, ReasoningEffort, Thinking, ThinkingState,
+    TokenUsage, ToolCall, MAX_TOOL_ROUNDS, TOOL_ROUNDS_NOTICE,
+};
+use super::transcribe;
+
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 #[serde(rename_all = "snake_case")]
@@ -28,10 +48,49 @@ struct OpenAIResponsesBody {
     model: String,
     input: Vec<ResponsesInput>,
     temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<ReasoningBody>,
     previous_response_id: Option<String>,
     store: bool,
     stream: bool,
     tools: Vec<OpenAIResponsesTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponsesCompleteBody<'a> {
+    model: String,
+    instructions: &'a str,
+    input: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<TextBody>,
+    store: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TextBody {
+    format: TextFormat,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TextFormat {
+    JsonObject,
+}
+
+#[derive(Debug, Serialize)]
+struct ReasoningBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effort: Option<ReasoningEffort>,
+    summary: &'static str,
 }
 
 #[derive(Debug, Deserialize)]
@@ -63,6 +122,48 @@ pub enum ResponsesStreamingResponse {
     FunctionCallArgumentsDelta,
     #[serde(rename = "response.function_call_arguments.done")]
     FunctionCallArgumentsDone,
+    #[serde(rename = "response.output_text.annotation.added")]
+    OutputTextAnnotationAdded,
+    #[serde(rename = "response.web_search_call.in_progress")]
+    WebSearchCallInProgress,
+    #[serde(rename = "response.web_search_call.searching")]
+    WebSearchCallSearching,
+    #[serde(rename = "response.web_search_call.completed")]
+    WebSearchCallCompleted,
+    #[serde(rename = "response.code_interpreter_call.in_progress")]
+    CodeInterpreterCallInProgress,
+    #[serde(rename = "response.code_interpreter_call_code.delta")]
+    CodeInterpreterCallCodeDelta,
+    #[serde(rename = "response.code_interpreter_call_code.done")]
+    CodeInterpreterCallCodeDone,
+    #[serde(rename = "response.code_interpreter_call.interpreting")]
+    CodeInterpreterCallInterpreting,
+    #[serde(rename = "response.code_interpreter_call.completed")]
+    CodeInterpreterCallCompleted,
+    #[serde(rename = "response.mcp_list_tools.in_progress")]
+    McpListToolsInProgress,
+    #[serde(rename = "response.mcp_list_tools.completed")]
+    McpListToolsCompleted,
+    #[serde(rename = "response.mcp_list_tools.failed")]
+    McpListToolsFailed,
+    #[serde(rename = "response.mcp_call.in_progress")]
+    McpCallInProgress,
+    #[serde(rename = "response.mcp_call_arguments.delta")]
+    McpCallArgumentsDelta,
+    #[serde(rename = "response.mcp_call_arguments.done")]
+    McpCallArgumentsDone,
+    #[serde(rename = "response.mcp_call.completed")]
+    McpCallCompleted,
+    #[serde(rename = "response.mcp_call.failed")]
+    McpCallFailed,
+    #[serde(rename = "response.reasoning_summary_part.added")]
+    ReasoningSummaryPartAdded,
+    #[serde(rename = "response.reasoning_summary_part.done")]
+    ReasoningSummaryPartDone,
+    #[serde(rename = "response.reasoning_summary_text.delta")]
+    ReasoningSummaryTextDelta { delta: String },
+    #[serde(rename = "response.reasoning_summary_text.done")]
+    ReasoningSummaryTextDone,
     #[serde(other)]
     Unknown,
 }
@@ -70,11 +171,27 @@ pub enum ResponsesStreamingResponse {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct ResponsesCompletedResponse {
-    #[allow(dead_code)]
     id: String,
     // If empty, skip
     #[serde(default)]
     output: Vec<ResponsesStreamingOutput>,
+    #[serde(default)]
+    usage: Option<ResponsesUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResponsesUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl ResponsesCompletedResponse {
+    fn token_usage(&self) -> Option<TokenUsage> {
+        self.usage.as_ref().map(|usage| TokenUsage {
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -101,15 +218,67 @@ pub enum ResponsesStreamingOutput {
         call_id: String,
         name: String,
     },
+    CodeInterpreterCall {
+        #[serde(default)]
+        code: Option<String>,
+    },
+    McpApprovalRequest {
+        id: String,
+        server_label: String,
+        name: String,
+        arguments: String,
+    },
+    McpCall {
+        server_label: String,
+        name: String,
+        arguments: String,
+        #[serde(default)]
+        output: Option<String>,
+        #[serde(default)]
+        error: Option<serde_json::Value>,
+    },
+    McpListTools,
     #[serde(other)]
     Unknown,
 }
 
+impl ResponsesStreamingOutput {
+    /// A tool call which OpenAI made to a remote MCP server.
+    fn mcp_tool_call(&self) -> Option<ToolCall> {
+        match self {
+            ResponsesStreamingOutput::McpCall {
+                server_label,
+                name,
+                arguments,
+                output,
+                error,
+            } => Some(ToolCall {
+                name: format!("{}_{}", server_label, name),
+                arguments: arguments.clone(),
+                duration: None,
+                result: match error {
+                    Some(serde_json::Value::String(error)) => Err(format!("Error: {}", error)),
+                    Some(error) => Err(format!("Error: {}", error)),
+                    None => Ok(output.clone().unwrap_or_default()),
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ResponsesToolOutput {
-    FunctionCallOutput { call_id: String, output: String },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+    McpApprovalResponse {
+        approval_request_id: String,
+        approve: bool,
+    },
 }
 
 #[derive(Debug, Deserialize)]
@@ -118,7 +287,56 @@ pub struct ResponsesStreamingContent {
     #[allow(dead_code)]
     #[serde(rename = "type")]
     type_field: String,
+    // Missing for refusals
+    #[serde(default)]
     text: String,
+    #[serde(default)]
+    annotations: Vec<ResponsesAnnotation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ResponsesAnnotation {
+    UrlCitation {
+        start_index: usize,
+        end_index: usize,
+        url: String,
+        #[serde(default)]
+        title: String,
+    },
+    ContainerFileCitation {
+        container_id: String,
+        file_id: String,
+        filename: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+impl ResponsesStreamingContent {
+    fn citation_spans(&self) -> Vec<(usize, usize, Citation)> {
+        self.annotations
+            .iter()
+            .filter_map(|annotation| match annotation {
+                ResponsesAnnotation::UrlCitation {
+                    start_index,
+                    end_index,
+                    url,
+                    title,
+                } => Some((
+                    *start_index,
+                    *end_index,
+                    Citation {
+                        url: url.clone(),
+                        title: title.clone(),
+                    },
+                )),
+                ResponsesAnnotation::ContainerFileCitation { .. }
+                | ResponsesAnnotation::Unknown => None,
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -136,27 +354,10 @@ enum OpenAIResponsesTool {
 struct FunctionCallBody {
     name: String,
     description: String,
-    parameters: FunctionCallParameters,
+    parameters: serde_json::Value,
     strict: bool,
 }
 
-#[derive(Debug, Serialize)]
-struct FunctionCallParameters {
-    #[serde(rename = "type")]
-    type_field: String,
-    properties: HashMap<String, FunctionCallParameter>,
-    required: Vec<String>,
-    #[serde(rename = "additionalProperties")]
-    additional_properties: bool,
-}
-
-#[derive(Debug, Serialize)]
-struct FunctionCallParameter {
-    #[serde(rename = "type")]
-    type_field: Vec<String>,
-    description: String,
-}
-
 #[derive(Debug, Serialize)]
 struct CodeInterpreterBody {
     pub container: CodeInterpreterContainerType,
@@ -176,59 +377,25 @@ enum CodeInterpreterContainerType {
 struct McpBody {
     server_label: String,
     server_url: String,
-    require_approval: String,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<String, String>,
+    require_approval: RemoteMcpApproval,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_tools: Option<Vec<String>>,
 }
 
-pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::Result<()> {
-    let slack_bot_format = format!("<@{}>", bot.bot_id());
-    let is_bot_command = msg.text.contains(&slack_bot_format);
-
-    if !is_bot_command {
-        return Ok(());
-    }
-
-    let command_str = msg.text.replace(&slack_bot_format, "");
-
-    let slices = command_str.split_whitespace().collect::<Vec<&str>>();
-
-    if slices.is_empty() {
-        return Ok(());
-    }
-
-    let call_type = slices[0];
-
-    let gpt_split = call_type.split("gpt").collect::<Vec<_>>();
-
-    let gpt_prefix_exists = gpt_split[0] == "";
-
-    let call_prefix = if !gpt_prefix_exists {
-        format!("{} ", slack_bot_format)
-    } else {
-        format!("{} {} ", slack_bot_format, call_type)
-    };
-
-    debug!("GPT: bot command full text = {:?}", &msg.text);
-
-    let skip_count = if gpt_prefix_exists { 1 } else { 0 };
-
-    let input_text = slices
-        .iter()
-        .cloned()
-        .skip(skip_count)
-        .collect::<Vec<_>>()
-        .join(" ");
-
+/// `documents` is the text of the files shared in the thread and of the attached MCP resources,
+/// given before the conversation.
+pub async fn answer<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    documents: Option<&str>,
+) -> anyhow::Result<()> {
     let openai_req = reqwest::Client::builder()
         .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:94.0) Gecko/20100101 Firefox/94.0")
         .build()?;
 
-    let thread_ts = if let Some(thread_ts) = msg.thread_ts.clone() {
-        thread_ts
-    } else {
-        msg.ts.clone()
-    };
-
-    let conv_fut = bot.get_conversation_replies(&msg.channel, thread_ts.as_str());
+    let conv_fut = bot.get_conversation_replies(&request.channel, &request.thread_ts);
     let conv_result = conv_fut.await;
 
     let stream_mode_str = env::var("USE_GPT_STREAM").unwrap_or("true".to_string());
@@ -236,154 +403,130 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
     let stream_mode = stream_mode_str == "1" || stream_mode_str.to_lowercase() == "true";
 
-    let openai_model = env::var("OPENAI_MODEL").unwrap_or("gpt-4o-mini".to_string());
-    let temperature = if openai_model.starts_with("o") || openai_model.starts_with("gpt-5") {
-        1.0
-    } else {
-        gpt_split[1].parse::<f32>().unwrap_or(0.0)
-    };
+    let openai_model = request.options.model(LlmProvider::ChatGpt);
+    // Reasoning models only accept the default temperature
+    let temperature = request
+        .options
+        .temperature(LlmProvider::ChatGpt)
+        .unwrap_or(1.0);
 
     let mut tools = vec![];
 
-    // Unused for now
-    if false {
+    if request
+        .options
+        .code_interpreter(LlmProvider::ChatGpt, bot.config().channel(&request.channel))
+    {
         tools.push(OpenAIResponsesTool::CodeInterpreter(CodeInterpreterBody {
             container: CodeInterpreterContainerType::Auto { r#type: "auto" },
         }));
     }
 
-    if !openai_model.starts_with("o") {
+    if request.options.web_search(LlmProvider::ChatGpt) {
         tools.push(OpenAIResponsesTool::WebSearch);
     }
 
-    // Remote mcp tools
-    if false {
-        tools.push(OpenAIResponsesTool::Mcp(McpBody {
-            server_label: "deepwiki".to_string(),
-            server_url: "https://mcp.deepwiki.com/sse".to_string(),
-            require_approval: "never".to_string(),
-        }));
+    // Remote mcp tools, called by OpenAI itself
+    if !request.options.no_tools {
+        for server in bot.config().remote_mcp_servers(&request.channel) {
+            tools.push(OpenAIResponsesTool::Mcp(McpBody {
+                server_label: server.label.clone(),
+                server_url: server.url.clone(),
+                headers: server.headers.clone(),
+                require_approval: server.require_approval,
+                allowed_tools: server.allowed_tools.clone(),
+            }));
+        }
     }
 
-    let all_tools = bot.get_all_tools_metadata().await?;
+    let all_tools = if request.options.no_tools {
+        vec![]
+    } else {
+        tools_metadata(bot).await?
+    };
+
+    for tool in all_tools {
+        // Schemas using keywords strict mode doesn't support are sent as they are
+        let (parameters, strict) = match tool.strict_parameters() {
+            Some(parameters) => (parameters, true),
+            None => (tool.parameters, false),
+        };
 
-    for (unified_name, arguments, required) in all_tools {
         let tool_call_body = FunctionCallBody {
-            name: unified_name.clone(),
-            description: format!("Call tool {}", unified_name),
-            parameters: FunctionCallParameters {
-                type_field: "object".to_string(),
-                required: arguments.keys().cloned().collect(),
-                properties: arguments
-                    .iter()
-                    .map(|(arg_name, (arg_type, description))| {
-                        let is_optional = required.contains(arg_name);
-
-                        let type_field = if is_optional {
-                            vec![arg_type.clone(), "null".to_string()]
-                        } else {
-                            vec![arg_type.clone()]
-                        };
-
-                        (
-                            arg_name.clone(),
-                            FunctionCallParameter {
-                                type_field,
-                                description: description.clone(),
-                            },
-                        )
-                    })
-                    .collect::<HashMap<_, _>>(),
-                additional_properties: false,
-            },
-            strict: true,
+            name: tool.name,
+            description: tool.description,
+            parameters,
+            strict,
         };
 
         tools.push(OpenAIResponsesTool::Function(tool_call_body));
     }
 
+    let messages = conv_result
+        .ok()
+        .and_then(|conv_res| conv_res.messages)
+        .unwrap_or_default();
+
+    // Continue the stored conversation, with only what came after it
+    let continued = bot
+        .responses()
+        .previous(&request.channel, &request.thread_ts, &request.ts);
+    let documents_hash = documents_hash(documents);
+
+    // The stored conversation already holds the same documents
+    let new_documents = match &continued {
+        Some(continued) if continued.documents_hash == documents_hash => None,
+        _ => documents,
+    };
+
     let mut openai_body = OpenAIResponsesBody {
         model: openai_model,
-        input: vec![],
+        input: thread_input(
+            bot,
+            request,
+            &messages,
+            continued
+                .as_ref()
+                .map(|continued| continued.request_ts.as_str()),
+            new_documents,
+        ),
         temperature,
+        max_output_tokens: request.options.max_tokens,
+        reasoning: if request.options.reasoning(LlmProvider::ChatGpt) {
+            Some(ReasoningBody {
+                effort: request.options.reasoning_effort,
+                summary: "auto",
+            })
+        } else {
+            None
+        },
         stream: stream_mode,
         store: true,
-        previous_response_id: None,
+        previous_response_id: continued.map(|continued| continued.response_id),
         tools,
+        tool_choice: None,
     };
 
-    if let Ok(conv_res) = conv_result {
-        if let Some(messages) = conv_res.messages {
-            messages.iter().for_each(|msg| {
-                let (role, mut content) = match msg {
-                    ThreadMessageType::Unbroadcasted(val) => ("user", val.text.clone()),
-                    ThreadMessageType::Broadcasted(val) => {
-                        if val.user.is_some() {
-                            ("user", val.text.clone())
-                        } else {
-                            let speaker = "assistant";
-
-                            if val.blocks.len() < 2 {
-                                return;
-                            }
-
-                            let mut text = val.text.clone();
-
-                            let mut is_valid_response = false;
-
-                            if let BlockElement::Section(section) = &val.blocks[0] {
-                                if section.text.text == "`ChatGPT`" {
-                                    if let BlockElement::Section(real_text_section) = &val.blocks[1]
-                                    {
-                                        text = real_text_section.text.text.clone();
-                                        is_valid_response = true;
-                                    }
-                                }
-                            }
-
-                            if !is_valid_response {
-                                return;
-                            }
-
-                            (speaker, text)
-                        }
-                    }
-                    ThreadMessageType::None(_) => return,
-                };
+    // Stored responses expire after a while on OpenAI's side, then the whole thread is sent instead
+    let fall_back_to_thread = |openai_body: &mut OpenAIResponsesBody| {
+        let response_id = match openai_body.previous_response_id.take() {
+            Some(response_id) => response_id,
+            None => return false,
+        };
 
-                if role == "user" {
-                    let call_split = content.split(&call_prefix).collect::<Vec<_>>();
+        debug!(
+            "OpenAI response {} is gone, reading the thread",
+            response_id
+        );
 
-                    if call_split.len() == 2 {
-                        content = call_split[1].to_string();
-                    }
-                }
+        bot.responses()
+            .forget(&request.channel, &request.thread_ts, &response_id);
 
-                let role = role.to_string();
+        openai_body.input = thread_input(bot, request, &messages, None, documents);
 
-                openai_body
-                    .input
-                    .push(ResponsesInput::Text(OpenAIChatCompletionMessage {
-                        role,
-                        content,
-                    }));
-            });
-        }
+        true
     };
 
-    if openai_body.input.len() == 0 {
-        error!("Error! no thread found");
-
-        openai_body.input = vec![ResponsesInput::Text(OpenAIChatCompletionMessage {
-            role: "user".to_string(),
-            content: input_text,
-        })];
-    }
-
-    let reply_event = Some(ReplyMessageEvent {
-        msg: thread_ts,
-        broadcast: true,
-    });
+    let reply_event = request.reply_event();
 
     let chat_url = "https://api.openai.com/v1/responses";
 
@@ -393,159 +536,311 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
         .json(&openai_body);
 
     if stream_mode {
-        let mut gpt_message = GptMessageManager::new(&msg.channel, reply_event.clone());
-        let mut initial_received = false;
+        let mut gpt_message = GptMessageManager::new(request);
+
+        gpt_message
+            .stream_message(bot, Some("`Receiving...`"))
+            .await?;
+
+        let cancel_token = gpt_message.cancel_token();
+
+        let stream_fut = async {
+            let mut openai_sse = EventSource::new(openai_builder)?;
+            let mut uploaded_files = HashSet::new();
+            let mut tool_rounds = 0;
+
+            let mut flush_timer = time::interval(gpt_message.flush_interval());
+            flush_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                let event = tokio::select! {
+                    event = openai_sse.next() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                    _ = flush_timer.tick() => {
+                        gpt_message.flush(bot, false).await;
+                        continue;
+                    }
+                };
 
-        let mut openai_sse = EventSource::new(openai_builder)?;
+                match event {
+                    Ok(Event::Open) => {
+                        debug!("OpenAI SSE opened");
+                    }
+                    Ok(Event::Message(event)) => {
+                        let data = event.data.clone();
 
-        while let Some(event) = openai_sse.next().await {
-            match event {
-                Ok(Event::Open) => {
-                    debug!("OpenAI SSE opened");
-                }
-                Ok(Event::Message(event)) => {
-                    let data = event.data.clone();
+                        let sse_res = serde_json::from_str::<ResponsesStreamingResponse>(&data);
 
-                    let sse_res = serde_json::from_str::<ResponsesStreamingResponse>(&data);
+                        if sse_res.is_err() {
+                            error!("OpenAI SSE json parsing failed: {:?}", data);
+                            continue;
+                        }
 
-                    if sse_res.is_err() {
-                        error!("OpenAI SSE json parsing failed: {:?}", data);
-                        continue;
-                    }
+                        let sse_res = sse_res.unwrap();
 
-                    let sse_res = sse_res.unwrap();
+                        match sse_res {
+                            ResponsesStreamingResponse::Delta { item_id: _, delta } => {
+                                debug!("OpenAI SSE delta: {:?}", delta);
 
-                    match sse_res {
-                        ResponsesStreamingResponse::Delta { item_id: _, delta } => {
-                            debug!("OpenAI SSE delta: {:?}", delta);
+                                let sent = gpt_message.stream_delta(bot, &delta).await;
 
-                            if !initial_received {
-                                initial_received = true;
+                                if sent.is_err() {
+                                    error!("OpenAI SSE stream message sending failed: {:?}", sent);
 
-                                match gpt_message
-                                    .stream_message(bot, Some("`Receiving...`"))
-                                    .await
-                                {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        error!("OpenAI SSE stream message sending failed: {:?}", e);
-                                    }
+                                    return Ok(());
                                 }
                             }
-
-                            gpt_message.concat_message(&delta);
-
-                            if ![",", ".", "?", "!", "\n"].contains(&delta.as_str()) {
-                                continue;
+                            ResponsesStreamingResponse::ReasoningSummaryPartAdded => {
+                                gpt_message.new_thinking_part();
                             }
+                            ResponsesStreamingResponse::ReasoningSummaryTextDelta { delta } => {
+                                let sent = gpt_message.stream_thinking(bot, &delta).await;
 
-                            let sent = gpt_message.stream_message(bot, Some(" `[continue]`")).await;
-
-                            if sent.is_err() {
-                                error!("OpenAI SSE stream message sending failed: {:?}", sent);
+                                if sent.is_err() {
+                                    error!("OpenAI SSE stream message sending failed: {:?}", sent);
 
-                                return Ok(());
+                                    return Ok(());
+                                }
                             }
-                        }
-                        ResponsesStreamingResponse::Completed { response } => {
-                            debug!("OpenAI SSE received {}", data);
-
-                            let mut function_calls = vec![];
-
-                            for item in response.output {
-                                match item {
-                                    ResponsesStreamingOutput::Message {
-                                        id: _,
-                                        status: _,
-                                        role: _,
-                                        content: _,
-                                    } => {}
-                                    ResponsesStreamingOutput::Reasoning { id: _ } => {}
-                                    ResponsesStreamingOutput::FunctionCall {
-                                        id: _,
-                                        status: _,
-                                        arguments,
-                                        call_id,
-                                        name,
-                                    } => {
-                                        let function_call =
-                                            get_function_call(bot, &name, &call_id, &arguments)
-                                                .await?;
-
-                                        function_calls.push(function_call);
-                                    }
-                                    ResponsesStreamingOutput::Unknown => {
-                                        error!("OpenAI SSE unknown output: {:?}", data);
+                            ResponsesStreamingResponse::Completed { response } => {
+                                debug!("OpenAI SSE received {}", data);
+
+                                gpt_message.add_usage(response.token_usage());
+
+                                // Tools may take a while, so show what came before them
+                                gpt_message.flush(bot, true).await;
+
+                                post_code_interpreter_outputs(
+                                    bot,
+                                    &openai_req,
+                                    request,
+                                    &response.output,
+                                    &mut uploaded_files,
+                                )
+                                .await;
+
+                                let mut function_calls = vec![];
+
+                                for item in response.output {
+                                    match item {
+                                        ResponsesStreamingOutput::Message {
+                                            id: _,
+                                            status: _,
+                                            role: _,
+                                            content,
+                                        } => {
+                                            for content in &content {
+                                                gpt_message.cite(content);
+                                            }
+                                        }
+                                        ResponsesStreamingOutput::Reasoning { id: _ } => {}
+                                        ResponsesStreamingOutput::FunctionCall {
+                                            id: _,
+                                            status: _,
+                                            arguments,
+                                            call_id,
+                                            name,
+                                        } => {
+                                            let (function_call, tool_call) = get_function_call(
+                                                bot, request, &name, &call_id, &arguments,
+                                            )
+                                            .await?;
+
+                                            gpt_message.push_tool_call(bot, tool_call).await;
+                                            function_calls.push(function_call);
+                                        }
+                                        ResponsesStreamingOutput::McpApprovalRequest {
+                                            id,
+                                            server_label,
+                                            name,
+                                            arguments,
+                                        } => {
+                                            function_calls.push(
+                                                get_mcp_approval(
+                                                    bot,
+                                                    request,
+                                                    &id,
+                                                    &server_label,
+                                                    &name,
+                                                    &arguments,
+                                                )
+                                                .await?,
+                                            );
+                                        }
+                                        item @ ResponsesStreamingOutput::McpCall { .. } => {
+                                            if let Some(tool_call) = item.mcp_tool_call() {
+                                                gpt_message.push_tool_call(bot, tool_call).await;
+                                            }
+                                        }
+                                        ResponsesStreamingOutput::CodeInterpreterCall {
+                                            ..
+                                        }
+                                        | ResponsesStreamingOutput::McpListTools => {}
+                                        ResponsesStreamingOutput::Unknown => {
+                                            error!("OpenAI SSE unknown output: {:?}", data);
+                                        }
                                     }
                                 }
-                            }
 
-                            if function_calls.is_empty() {
-                                gpt_message.concat_message(&format!(" `{}`", "[DONE]"));
+                                if function_calls.is_empty() {
+                                    bot.responses().record(
+                                        &request.channel,
+                                        &request.thread_ts,
+                                        &request.ts,
+                                        &response.id,
+                                        documents_hash,
+                                    );
+
+                                    let sent = gpt_message.finish(bot, "[DONE]").await;
+
+                                    if sent.is_err() {
+                                        error!(
+                                            "OpenAI SSE stream {} sending failed: {:?}",
+                                            data, sent
+                                        );
+                                    }
 
-                                let sent = gpt_message.stream_message(bot, None).await;
+                                    break;
+                                } else if tool_rounds == MAX_TOOL_ROUNDS {
+                                    let marker = format!("[{}]", TOOL_ROUNDS_NOTICE);
+                                    let sent = gpt_message.finish(bot, &marker).await;
 
-                                if sent.is_err() {
-                                    error!("OpenAI SSE stream {} sending failed: {:?}", data, sent);
-                                }
+                                    if sent.is_err() {
+                                        error!(
+                                            "OpenAI SSE stream {} sending failed: {:?}",
+                                            data, sent
+                                        );
+                                    }
+
+                                    break;
+                                } else {
+                                    tool_rounds += 1;
+
+                                    if tool_rounds == MAX_TOOL_ROUNDS {
+                                        // The model has to answer with what it has
+                                        openai_body.tool_choice = Some("none");
+                                    }
 
-                                break;
-                            } else {
-                                openai_body.previous_response_id = Some(response.id);
-                                openai_body.input.extend(function_calls);
+                                    // The response already holds everything before its calls
+                                    openai_body.previous_response_id = Some(response.id);
+                                    openai_body.input = function_calls;
 
-                                let builder = openai_req
-                                    .post(chat_url)
-                                    .bearer_auth(bot.openai_key())
-                                    .json(&openai_body);
+                                    let builder = openai_req
+                                        .post(chat_url)
+                                        .bearer_auth(bot.openai_key())
+                                        .json(&openai_body);
 
-                                openai_sse = EventSource::new(builder)?;
+                                    openai_sse = EventSource::new(builder)?;
+                                }
+                            }
+                            ResponsesStreamingResponse::Created
+                            | ResponsesStreamingResponse::InProgress
+                            | ResponsesStreamingResponse::OutputItemAdded
+                            | ResponsesStreamingResponse::OutputItemDone
+                            | ResponsesStreamingResponse::OutputTextDone
+                            | ResponsesStreamingResponse::ContentPartAdded
+                            | ResponsesStreamingResponse::ContentPartDone
+                            | ResponsesStreamingResponse::FunctionCallArgumentsDelta
+                            | ResponsesStreamingResponse::FunctionCallArgumentsDone
+                            | ResponsesStreamingResponse::OutputTextAnnotationAdded
+                            | ResponsesStreamingResponse::WebSearchCallInProgress
+                            | ResponsesStreamingResponse::WebSearchCallSearching
+                            | ResponsesStreamingResponse::WebSearchCallCompleted
+                            | ResponsesStreamingResponse::CodeInterpreterCallInProgress
+                            | ResponsesStreamingResponse::CodeInterpreterCallCodeDelta
+                            | ResponsesStreamingResponse::CodeInterpreterCallCodeDone
+                            | ResponsesStreamingResponse::CodeInterpreterCallInterpreting
+                            | ResponsesStreamingResponse::CodeInterpreterCallCompleted
+                            | ResponsesStreamingResponse::McpListToolsInProgress
+                            | ResponsesStreamingResponse::McpListToolsCompleted
+                            | ResponsesStreamingResponse::McpListToolsFailed
+                            | ResponsesStreamingResponse::McpCallInProgress
+                            | ResponsesStreamingResponse::McpCallArgumentsDelta
+                            | ResponsesStreamingResponse::McpCallArgumentsDone
+                            | ResponsesStreamingResponse::McpCallCompleted
+                            | ResponsesStreamingResponse::McpCallFailed
+                            | ResponsesStreamingResponse::ReasoningSummaryPartDone
+                            | ResponsesStreamingResponse::ReasoningSummaryTextDone => {
+                                // Ignore
+                            }
+                            ResponsesStreamingResponse::Unknown => {
+                                error!("OpenAI SSE unknown response: {:?}", data);
                             }
-                        }
-                        ResponsesStreamingResponse::Created
-                        | ResponsesStreamingResponse::InProgress
-                        | ResponsesStreamingResponse::OutputItemAdded
-                        | ResponsesStreamingResponse::OutputItemDone
-                        | ResponsesStreamingResponse::OutputTextDone
-                        | ResponsesStreamingResponse::ContentPartAdded
-                        | ResponsesStreamingResponse::ContentPartDone
-                        | ResponsesStreamingResponse::FunctionCallArgumentsDelta
-                        | ResponsesStreamingResponse::FunctionCallArgumentsDone => {
-                            // Ignore
-                        }
-                        ResponsesStreamingResponse::Unknown => {
-                            error!("OpenAI SSE unknown response: {:?}", data);
                         }
                     }
-                }
-                Err(e) => {
-                    match e {
-                        reqwest_eventsource::Error::StreamEnded => {
-                            debug!("OpenAI SSE stream ended");
-                        }
-                        reqwest_eventsource::Error::InvalidStatusCode(code, err_res) => {
-                            error!("OpenAI SSE error code: {}", code);
-                            error!("OpenAI SSE body: {:?}", serde_json::to_string(&openai_body));
+                    Err(e) => {
+                        match e {
+                            reqwest_eventsource::Error::StreamEnded => {
+                                debug!("OpenAI SSE stream ended");
+                            }
+                            reqwest_eventsource::Error::InvalidStatusCode(code, err_res) => {
+                                let body = match err_res.text().await {
+                                    Ok(body) => body,
+                                    Err(e) => format!("Failed to read error res body: {:?}", e),
+                                };
+
+                                if tool_rounds == 0
+                                    && is_previous_response_not_found(&body)
+                                    && fall_back_to_thread(&mut openai_body)
+                                {
+                                    let builder = openai_req
+                                        .post(chat_url)
+                                        .bearer_auth(bot.openai_key())
+                                        .json(&openai_body);
+
+                                    openai_sse = EventSource::new(builder)?;
+
+                                    continue;
+                                }
 
-                            match err_res.text().await {
-                                Ok(body) => error!("OpenAI SSE error res body: {}", body),
-                                Err(e) => error!("Failed to read error res body: {:?}", e),
+                                error!("OpenAI SSE error code: {}", code);
+                                error!(
+                                    "OpenAI SSE body: {:?}",
+                                    serde_json::to_string(&openai_body)
+                                );
+                                error!("OpenAI SSE error res body: {}", body);
+                            }
+                            _ => {
+                                error!(
+                                    "OpenAI SSE body: {:?}",
+                                    serde_json::to_string(&openai_body)
+                                );
+                                error!("OpenAI SSE error: {:?}", e);
                             }
                         }
-                        _ => {
-                            error!("OpenAI SSE body: {:?}", serde_json::to_string(&openai_body));
-                            error!("OpenAI SSE error: {:?}", e);
-                        }
-                    }
 
-                    break;
+                        break;
+                    }
                 }
             }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        // Dropping the stream future closes the SSE connection and any pending tool call
+        let stream_res = tokio::select! {
+            _ = cancel_token.cancelled() => None,
+            res = stream_fut => Some(res),
+        };
+
+        if !gpt_message.is_finished() {
+            let marker = match stream_res {
+                None => "[stopped]",
+                Some(_) => "[ERROR]",
+            };
+
+            gpt_message.finish(bot, marker).await?;
         }
 
-        Ok(())
+        stream_res.unwrap_or(Ok(()))
     } else {
         let mut openai_res = openai_builder.send().await;
+        let mut uploaded_files = HashSet::new();
+        let mut usage = None;
+        let mut tool_calls = vec![];
+        let mut tool_rounds = 0;
 
         loop {
             if openai_res.is_err() {
@@ -554,7 +849,7 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
                 return bot
                     .send_message(
-                        &msg.channel,
+                        &request.channel,
                         Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(
                             debug_str,
                         ))]),
@@ -576,7 +871,7 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
                 return bot
                     .send_message(
-                        &msg.channel,
+                        &request.channel,
                         Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(
                             &debug_str,
                         ))]),
@@ -591,6 +886,21 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
             let res_body_result = serde_json::from_slice::<ResponsesCompletedResponse>(&res_bytes);
 
+            if res_body_result.is_err()
+                && tool_rounds == 0
+                && is_previous_response_not_found(&String::from_utf8_lossy(&res_bytes))
+                && fall_back_to_thread(&mut openai_body)
+            {
+                openai_res = openai_req
+                    .post(chat_url)
+                    .bearer_auth(bot.openai_key())
+                    .json(&openai_body)
+                    .send()
+                    .await;
+
+                continue;
+            }
+
             if res_body_result.is_err() {
                 let debug_str = format!(
                     "OpenAI result json parsing failed: {:?}",
@@ -601,7 +911,7 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
                 return bot
                     .send_message(
-                        &msg.channel,
+                        &request.channel,
                         Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(
                             &debug_str,
                         ))]),
@@ -614,7 +924,19 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
             let res_body = res_body_result.unwrap();
 
+            add_usage(&mut usage, res_body.token_usage());
+
+            post_code_interpreter_outputs(
+                bot,
+                &openai_req,
+                request,
+                &res_body.output,
+                &mut uploaded_files,
+            )
+            .await;
+
             let mut res_texts = vec![];
+            let mut citations = vec![];
             let mut function_calls = vec![];
 
             for output in &res_body.output {
@@ -624,7 +946,15 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
                         status: _,
                         role: _,
                         content,
-                    } => res_texts.push(content[0].text.clone()),
+                    } => {
+                        for content in content {
+                            res_texts.push(apply_citations(
+                                &content.text,
+                                &content.citation_spans(),
+                                &mut citations,
+                            ));
+                        }
+                    }
                     ResponsesStreamingOutput::Reasoning { id: _ } => {}
                     ResponsesStreamingOutput::FunctionCall {
                         id: _,
@@ -633,20 +963,45 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
                         call_id,
                         name,
                     } => {
-                        let function_call =
-                            get_function_call(bot, name, call_id, arguments).await?;
+                        let (function_call, tool_call) =
+                            get_function_call(bot, request, name, call_id, arguments).await?;
 
+                        tool_calls.push(tool_call);
                         function_calls.push(function_call);
                     }
+                    ResponsesStreamingOutput::McpApprovalRequest {
+                        id,
+                        server_label,
+                        name,
+                        arguments,
+                    } => {
+                        function_calls.push(
+                            get_mcp_approval(bot, request, id, server_label, name, arguments)
+                                .await?,
+                        );
+                    }
+                    item @ ResponsesStreamingOutput::McpCall { .. } => {
+                        tool_calls.extend(item.mcp_tool_call());
+                    }
+                    ResponsesStreamingOutput::CodeInterpreterCall { .. }
+                    | ResponsesStreamingOutput::McpListTools => {}
                     ResponsesStreamingOutput::Unknown => {
                         error!("OpenAI SSE unknown output: {:?}", res_body);
                     }
                 }
             }
 
-            if !function_calls.is_empty() {
+            if !function_calls.is_empty() && tool_rounds < MAX_TOOL_ROUNDS {
+                tool_rounds += 1;
+
+                if tool_rounds == MAX_TOOL_ROUNDS {
+                    // The model has to answer with what it has
+                    openai_body.tool_choice = Some("none");
+                }
+
+                // The response already holds everything before its calls
                 openai_body.previous_response_id = Some(res_body.id);
-                openai_body.input.extend(function_calls);
+                openai_body.input = function_calls;
 
                 let openai_builder = openai_req
                     .post(chat_url)
@@ -657,62 +1012,567 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
 
                 continue;
             } else {
-                let res_text = res_texts.join("\n");
+                let mut res_text = res_texts.join("\n");
+
+                // A response with unanswered calls can't be continued
+                if function_calls.is_empty() {
+                    bot.responses().record(
+                        &request.channel,
+                        &request.thread_ts,
+                        &request.ts,
+                        &res_body.id,
+                        documents_hash,
+                    );
+                } else {
+                    res_text += &format!(" `[{}]`", TOOL_ROUNDS_NOTICE);
+                }
 
-                return GptMessageManager::send_message_static(
+                return post_final_answer(
                     bot,
-                    &res_text,
-                    &msg.channel,
-                    &reply_event,
+                    request,
+                    AnswerVersion {
+                        provider: LlmProvider::ChatGpt,
+                        model: request.options.model(LlmProvider::ChatGpt),
+                        text: res_text,
+                        usage,
+                        thought_for: None,
+                        citations,
+                        tool_calls,
+                    },
+                    request.answer_ts.as_deref(),
                 )
-                .await
-                .and(Ok(()));
+                .await;
             }
         }
     }
 }
 
+/// Asks for a single answer, without tools or thread history.
+pub async fn complete<B: Bot>(
+    bot: &B,
+    options: &LlmOptions,
+    instructions: &str,
+    input: &str,
+    json: bool,
+) -> anyhow::Result<String> {
+    let body = ResponsesCompleteBody {
+        model: options.model(LlmProvider::ChatGpt),
+        instructions,
+        input,
+        temperature: options.temperature(LlmProvider::ChatGpt),
+        max_output_tokens: options.max_tokens,
+        text: json.then_some(TextBody {
+            format: TextFormat::JsonObject,
+        }),
+        store: false,
+    };
+
+    let res = reqwest::Client::new()
+        .post("https://api.openai.com/v1/responses")
+        .bearer_auth(bot.openai_key())
+        .json(&body)
+        .send()
+        .await?;
+
+    let status = res.status();
+
+    if !status.is_success() {
+        return Err(anyhow!(
+            "OpenAI API call failed: {} {}",
+            status,
+            res.text().await?
+        ));
+    }
+
+    let res = res.json::<ResponsesCompletedResponse>().await?;
+
+    let text = res
+        .output
+        .iter()
+        .filter_map(|output| match output {
+            ResponsesStreamingOutput::Message { content, .. } => Some(content),
+            _ => None,
+        })
+        .flatten()
+        .map(|content| content.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(text)
+}
+
+// Section text is limited to 3000 characters
+const MAX_CODE_LEN: usize = 2900;
+
+// Past these, the responses of the oldest threads and requests are forgotten
+const MAX_STORED_THREADS: usize = 500;
+const MAX_THREAD_RESPONSES: usize = 20;
+
+/// Posts the code run by the code interpreter and uploads the files it made to the thread.
+async fn post_code_interpreter_outputs<B: Bot>(
+    bot: &B,
+    client: &reqwest::Client,
+    request: &LlmRequest,
+    output: &[ResponsesStreamingOutput],
+    uploaded_files: &mut HashSet<String>,
+) {
+    let reply_event = Some(ReplyMessageEvent {
+        msg: request.thread_ts.clone(),
+        broadcast: false,
+    });
+
+    for item in output {
+        let code = match item {
+            ResponsesStreamingOutput::CodeInterpreterCall { code: Some(code) } => code,
+            _ => continue,
+        };
+
+        let code = match code.char_indices().nth(MAX_CODE_LEN) {
+            Some((end, _)) => format!("{}\n…", &code[..end]),
+            None => code.clone(),
+        };
+
+        let blocks = [
+            BlockElement::Context(ContextBlock::new_markdown(":snake: Code interpreter")),
+            BlockElement::Section(SectionBlock::new_markdown(&format!("```{}```", code))),
+        ];
+
+        if let Err(e) = bot
+            .send_message(
+                &request.channel,
+                Message::Blocks(&blocks),
+                reply_event.clone(),
+                None,
+            )
+            .await
+        {
+            error!("Failed to post code interpreter code: {:?}", e);
+        }
+    }
+
+    let files = output
+        .iter()
+        .filter_map(|item| match item {
+            ResponsesStreamingOutput::Message { content, .. } => Some(content),
+            _ => None,
+        })
+        .flatten()
+        .flat_map(|content| &content.annotations)
+        .filter_map(|annotation| match annotation {
+            ResponsesAnnotation::ContainerFileCitation {
+                container_id,
+                file_id,
+                filename,
+            } => Some((container_id, file_id, filename)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    for (container_id, file_id, filename) in files {
+        if !uploaded_files.insert(file_id.clone()) {
+            continue;
+        }
+
+        let uploaded = async {
+            let content = client
+                .get(format!(
+                    "https://api.openai.com/v1/containers/{}/files/{}/content",
+                    container_id, file_id
+                ))
+                .bearer_auth(bot.openai_key())
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+
+            bot.upload_file(
+                &request.channel,
+                Some(&request.thread_ts),
+                filename,
+                content.to_vec(),
+            )
+            .await
+        }
+        .await;
+
+        if let Err(e) = uploaded {
+            error!("Failed to upload container file {}: {:?}", filename, e);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAIErrorResponse {
+    error: OpenAIError,
+}
+
+#[derive(Deserialize)]
+struct OpenAIError {
+    code: Option<String>,
+}
+
+/// Whether an error body says the `previous_response_id` is unknown, as once it expired.
+fn is_previous_response_not_found(body: &str) -> bool {
+    serde_json::from_str::<OpenAIErrorResponse>(body)
+        .is_ok_and(|res| res.error.code.as_deref() == Some("previous_response_not_found"))
+}
+
+/// The thread as the input of a request. When continuing the stored conversation of the request
+/// at `continued_ts`, only the messages after it are given.
+fn thread_input<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    messages: &[ThreadMessageType],
+    continued_ts: Option<&str>,
+    documents: Option<&str>,
+) -> Vec<ResponsesInput> {
+    let mut input = vec![];
+
+    for msg in messages {
+        if !request.is_in_context(msg) {
+            continue;
+        }
+
+        // The stored conversation already has everything up to its request
+        if let Some(continued_ts) = continued_ts {
+            let is_new = msg.ts().is_some_and(|ts| ts_is_after(&ts, continued_ts));
+
+            if !is_new {
+                continue;
+            }
+        }
+
+        let (role, mut content) = match msg {
+            ThreadMessageType::Unbroadcasted(val) => ("user", val.text.clone()),
+            ThreadMessageType::Broadcasted(val) => {
+                if val.user.is_some() {
+                    ("user", val.text.clone())
+                } else {
+                    let speaker = "assistant";
+
+                    // Answers are part of the stored conversation
+                    if continued_ts.is_some() {
+                        continue;
+                    }
+
+                    let text = match answer_text(LlmProvider::ChatGpt, &val.blocks) {
+                        Some(text) => text,
+                        None => continue,
+                    };
+
+                    (speaker, text)
+                }
+            }
+            ThreadMessageType::Bot(val) => match transcribe::transcript_text(&val.blocks) {
+                Some(text) => ("user", text),
+                None => continue,
+            },
+            ThreadMessageType::None(_) => continue,
+        };
+
+        // The request itself may be more than its message, as with MCP prompts
+        if role == "user" {
+            content = if msg.ts().as_deref() == Some(request.ts.as_str()) {
+                request.input_text.clone()
+            } else {
+                command::strip_command(bot.bot_id(), content)
+            };
+        }
+
+        input.push(ResponsesInput::Text(OpenAIChatCompletionMessage {
+            role: role.to_string(),
+            content,
+        }));
+    }
+
+    if input.is_empty() {
+        error!("Error! no thread found");
+
+        input = vec![ResponsesInput::Text(OpenAIChatCompletionMessage {
+            role: "user".to_string(),
+            content: request.input_text.clone(),
+        })];
+    }
+
+    if let Some(documents) = documents {
+        input.insert(
+            0,
+            ResponsesInput::Text(OpenAIChatCompletionMessage {
+                role: "user".to_string(),
+                content: documents.to_string(),
+            }),
+        );
+    }
+
+    input
+}
+
+fn ts_is_after(ts: &str, other: &str) -> bool {
+    match (ts.parse::<f64>(), other.parse::<f64>()) {
+        (Ok(ts), Ok(other)) => ts > other,
+        _ => true,
+    }
+}
+
+/// The last OpenAI response made for each request, per thread.
+/// Lets a follow-up continue with `previous_response_id`, which keeps tool calls and reasoning.
+#[derive(Default)]
+pub struct ResponseStore {
+    // Sorted by request ts, keyed by (channel, thread ts)
+    responses: Mutex<HashMap<(String, String), Vec<StoredResponse>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredResponse {
+    pub request_ts: String,
+    pub response_id: String,
+    /// Of the documents given with the request, which the conversation then holds.
+    pub documents_hash: Option<u64>,
+}
+
+pub fn documents_hash(documents: Option<&str>) -> Option<u64> {
+    documents.map(|documents| {
+        let mut hasher = DefaultHasher::new();
+        documents.hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+impl ResponseStore {
+    pub fn record(
+        &self,
+        channel: &str,
+        thread_ts: &str,
+        request_ts: &str,
+        response_id: &str,
+        documents_hash: Option<u64>,
+    ) {
+        let mut responses = self.responses.lock().unwrap();
+        let key = (channel.to_string(), thread_ts.to_string());
+
+        if !responses.contains_key(&key) && responses.len() >= MAX_STORED_THREADS {
+            let oldest = responses
+                .keys()
+                .min_by(|(_, a), (_, b)| {
+                    let (a, b) = (a.parse::<f64>(), b.parse::<f64>());
+                    a.unwrap_or(0.0).total_cmp(&b.unwrap_or(0.0))
+                })
+                .cloned();
+
+            if let Some(oldest) = oldest {
+                responses.remove(&oldest);
+            }
+        }
+
+        let thread = responses.entry(key).or_default();
+
+        // Regenerated answers replace the previous response of the same request
+        thread.retain(|stored| stored.request_ts != request_ts);
+        thread.push(StoredResponse {
+            request_ts: request_ts.to_string(),
+            response_id: response_id.to_string(),
+            documents_hash,
+        });
+        thread.sort_by(|a, b| {
+            let parse = |stored: &StoredResponse| stored.request_ts.parse::<f64>().unwrap_or(0.0);
+
+            parse(a).total_cmp(&parse(b))
+        });
+
+        if thread.len() > MAX_THREAD_RESPONSES {
+            thread.drain(..thread.len() - MAX_THREAD_RESPONSES);
+        }
+    }
+
+    /// The latest response made for a request before `request_ts`.
+    pub fn previous(
+        &self,
+        channel: &str,
+        thread_ts: &str,
+        request_ts: &str,
+    ) -> Option<StoredResponse> {
+        let responses = self.responses.lock().unwrap();
+
+        responses
+            .get(&(channel.to_string(), thread_ts.to_string()))?
+            .iter()
+            .rev()
+            .find(|stored| ts_is_after(request_ts, &stored.request_ts))
+            .cloned()
+    }
+
+    pub fn forget(&self, channel: &str, thread_ts: &str, response_id: &str) {
+        let mut responses = self.responses.lock().unwrap();
+
+        if let Some(thread) = responses.get_mut(&(channel.to_string(), thread_ts.to_string())) {
+            thread.retain(|stored| stored.response_id != response_id);
+        }
+    }
+}
+
 async fn get_function_call<B: Bot>(
     bot: &B,
-    name: &String,
-    call_id: &String,
-    arguments: &String,
-) -> anyhow::Result<ResponsesInput> {
+    request: &LlmRequest,
+    name: &str,
+    call_id: &str,
+    arguments: &str,
+) -> anyhow::Result<(ResponsesInput, ToolCall)> {
     let arguments: HashMap<String, serde_json::Value> =
-        serde_json::from_str(&arguments).unwrap_or_else(|_| HashMap::new());
+        serde_json::from_str(arguments).unwrap_or_else(|_| HashMap::new());
 
-    let tool_result = bot.call_mcp_tool(name, arguments).await?;
+    let tool_call = call_tool(bot, request, name, arguments).await?;
 
     let tool_output = ResponsesToolOutput::FunctionCallOutput {
-        call_id: call_id.clone(),
-        output: tool_result,
+        call_id: call_id.to_string(),
+        output: tool_call.output(),
     };
 
-    Ok(ResponsesInput::FunctionCall(tool_output))
+    Ok((ResponsesInput::FunctionCall(tool_output), tool_call))
+}
+
+/// Asks the requester whether OpenAI may call a tool of a remote MCP server.
+async fn get_mcp_approval<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    approval_request_id: &str,
+    server_label: &str,
+    name: &str,
+    arguments: &str,
+) -> anyhow::Result<ResponsesInput> {
+    let arguments: HashMap<String, serde_json::Value> =
+        serde_json::from_str(arguments).unwrap_or_else(|_| HashMap::new());
+
+    let name = format!("{}_{}", server_label, name);
+    let decision = approval::ask(bot, request, &name, &arguments).await?;
+
+    Ok(ResponsesInput::FunctionCall(
+        ResponsesToolOutput::McpApprovalResponse {
+            approval_request_id: approval_request_id.to_string(),
+            approve: matches!(decision, approval::Decision::Approved),
+        },
+    ))
 }
 
 // TODO save bot as member?
 struct GptMessageManager<'a> {
-    channel: &'a String,
+    request: &'a LlmRequest,
     ts: String,
-    reply_event: Option<ReplyMessageEvent>,
     message: String,
+    tool_calls: Vec<ToolCall>,
+    citations: Vec<Citation>,
+    thinking: ThinkingState,
+    usage: Option<TokenUsage>,
+    throttle: EditThrottle,
+    cancel_token: CancellationToken,
+    registered: bool,
+    finished: bool,
 }
 
 impl<'a> GptMessageManager<'a> {
-    pub fn new(channel: &'a String, reply_event: Option<ReplyMessageEvent>) -> Self {
+    pub fn new(request: &'a LlmRequest) -> Self {
         Self {
-            channel,
+            request,
             message: String::new(),
-            ts: String::new(),
-            reply_event,
+            tool_calls: vec![],
+            citations: vec![],
+            thinking: ThinkingState::default(),
+            usage: None,
+            throttle: EditThrottle::from_env(),
+            ts: request.answer_ts.clone().unwrap_or_default(),
+            cancel_token: CancellationToken::new(),
+            registered: false,
+            finished: false,
         }
     }
 
-    pub fn concat_message(&mut self, diff_message: &String) {
+    pub fn concat_message(&mut self, diff_message: &str) {
         self.message += diff_message;
     }
 
+    /// Appends a streamed delta, editing the message only when the throttle allows it.
+    pub async fn stream_delta(&mut self, bot: &impl Bot, delta: &str) -> anyhow::Result<()> {
+        self.concat_message(delta);
+
+        // Condense the thinking block as soon as the answer starts
+        let thinking = matches!(self.thinking.display(), Some(Thinking::InProgress(_)));
+        self.thinking.finish();
+
+        if !self.throttle.push(delta) && !thinking {
+            return Ok(());
+        }
+
+        self.stream_message(bot, Some(" `[continue]`")).await
+    }
+
+    /// Replaces the cited spans of a finished output text with footnote markers.
+    pub fn cite(&mut self, content: &ResponsesStreamingContent) {
+        let spans = content.citation_spans();
+
+        if spans.is_empty() {
+            return;
+        }
+
+        let cited = apply_citations(&content.text, &spans, &mut self.citations);
+
+        self.message = self.message.replacen(&content.text, &cited, 1);
+    }
+
+    pub fn add_usage(&mut self, usage: Option<TokenUsage>) {
+        add_usage(&mut self.usage, usage);
+    }
+
+    pub fn new_thinking_part(&mut self) {
+        self.thinking.new_part();
+    }
+
+    pub async fn stream_thinking(&mut self, bot: &impl Bot, delta: &str) -> anyhow::Result<()> {
+        self.thinking.push(delta);
+
+        if !self.throttle.push(delta) {
+            return Ok(());
+        }
+
+        self.stream_message(bot, Some(" `[continue]`")).await
+    }
+
+    pub async fn push_tool_call(&mut self, bot: &impl Bot, tool_call: ToolCall) {
+        self.tool_calls.push(tool_call);
+
+        if let Err(e) = self.stream_message(bot, Some(" `[continue]`")).await {
+            error!("Tool call message sending failed: {:?}", e);
+        }
+    }
+
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.throttle.min_interval()
+    }
+
+    /// Shows the text held back by the throttle. Unless `now`, only once the interval has passed.
+    pub async fn flush(&mut self, bot: &impl Bot, now: bool) {
+        let due = if now {
+            self.throttle.has_pending()
+        } else {
+            self.throttle.is_due()
+        };
+
+        if !due {
+            return;
+        }
+
+        if let Err(e) = self.stream_message(bot, Some(" `[continue]`")).await {
+            error!("Flushing message failed: {:?}", e);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
     pub async fn stream_message(
         &mut self,
         bot: &impl Bot,
@@ -720,78 +1580,220 @@ impl<'a> GptMessageManager<'a> {
     ) -> anyhow::Result<()> {
         let mut message = Cow::from(&self.message);
 
-        match temp_message {
-            Some(temp_message) => message += temp_message,
-            None => {}
+        if let Some(temp_message) = temp_message {
+            message += temp_message;
         }
 
+        let blocks = answer_blocks(
+            LlmProvider::ChatGpt,
+            &self.request.options.model(LlmProvider::ChatGpt),
+            &message,
+            self.thinking.display(),
+            &self.citations,
+            &self.tool_calls,
+            AnswerControls::Stop,
+        );
+
         if !self.ts.is_empty() {
-            return self
-                .edit_message(bot, &message, self.channel, &self.ts)
-                .await;
-        } else {
-            let sent = self
-                .send_message(bot, &message, self.channel, &self.reply_event)
+            let sent = bot
+                .edit_message(&self.request.channel, Message::Blocks(&blocks), &self.ts)
                 .await;
 
             if sent.is_err() {
-                return Err(sent.err().unwrap());
+                error!("Edit message failed: {:?}", sent.err());
             }
+        } else {
+            let sent = bot
+                .send_message(
+                    &self.request.channel,
+                    Message::Blocks(&blocks),
+                    self.request.reply_event(),
+                    None,
+                )
+                .await?;
+
+            self.ts = String::from(&sent.ts.ok_or_else(|| anyhow!("No ts in response"))?);
+        }
+
+        self.throttle.edited();
 
-            self.ts = String::from(&sent.unwrap().ts.unwrap());
+        if !self.registered {
+            self.registered = true;
 
-            Ok(())
+            bot.generations().register(
+                &self.request.channel,
+                &self.ts,
+                &self.request.requester,
+                self.cancel_token.clone(),
+            );
         }
-    }
 
-    pub async fn send_message_static(
-        bot: &impl Bot,
-        message: &str,
-        channel: &str,
-        reply_event: &Option<ReplyMessageEvent>,
-    ) -> anyhow::Result<PostMessageResponse> {
-        let gpt_name_block = BlockElement::Section(SectionBlock::new_markdown("`ChatGPT`"));
-        let gpt_answer_block = BlockElement::Section(SectionBlock::new_markdown(&message));
+        Ok(())
+    }
 
-        let blocks = [gpt_name_block, gpt_answer_block];
+    pub async fn finish(&mut self, bot: &impl Bot, marker: &str) -> anyhow::Result<()> {
+        self.finished = true;
+        self.thinking.finish();
+        self.concat_message(&format!(" `{}`", marker));
+
+        let sent = post_final_answer(
+            bot,
+            self.request,
+            AnswerVersion {
+                provider: LlmProvider::ChatGpt,
+                model: self.request.options.model(LlmProvider::ChatGpt),
+                text: self.message.clone(),
+                usage: self.usage,
+                thought_for: self.thinking.thought_for(),
+                citations: self.citations.clone(),
+                tool_calls: self.tool_calls.clone(),
+            },
+            Some(&self.ts),
+        )
+        .await;
 
-        let sent = bot
-            .send_message(channel, Message::Blocks(&blocks), reply_event.clone(), None)
-            .await;
+        bot.generations()
+            .unregister(&self.request.channel, &self.ts);
 
         sent
     }
+}
 
-    async fn send_message(
-        &self,
-        bot: &impl Bot,
-        message: &str,
-        channel: &str,
-        reply_event: &Option<ReplyMessageEvent>,
-    ) -> anyhow::Result<PostMessageResponse> {
-        Self::send_message_static(bot, message, channel, reply_event).await
-    }
+#[test]
+fn test_response_store() {
+    let store = ResponseStore::default();
 
-    async fn edit_message(
-        &self,
-        bot: &impl Bot,
-        message: &str,
-        channel: &str,
-        ts: &str,
-    ) -> anyhow::Result<()> {
-        let gpt_name_block = BlockElement::Section(SectionBlock::new_markdown("`ChatGPT`"));
-        let gpt_answer_block = BlockElement::Section(SectionBlock::new_markdown(&message));
+    store.record("C123", "1.0", "1.0", "resp_1", None);
+    store.record("C123", "1.0", "3.0", "resp_3", None);
 
-        let blocks = [gpt_name_block, gpt_answer_block];
+    let previous = |thread_ts, request_ts| {
+        store
+            .previous("C123", thread_ts, request_ts)
+            .map(|stored| (stored.request_ts, stored.response_id))
+    };
 
-        let sent = bot
-            .edit_message(channel, Message::Blocks(&blocks), ts)
-            .await;
+    assert_eq!(previous("1.0", "1.0"), None);
+    assert_eq!(
+        previous("1.0", "2.0"),
+        Some(("1.0".to_string(), "resp_1".to_string()))
+    );
+    assert_eq!(
+        previous("1.0", "4.0"),
+        Some(("3.0".to_string(), "resp_3".to_string()))
+    );
+
+    // Regenerating the answer of 3.0 replaces its response
+    store.record("C123", "1.0", "3.0", "resp_3b", None);
+    store.forget("C123", "1.0", "resp_1");
+
+    assert_eq!(previous("1.0", "2.0"), None);
+    assert_eq!(
+        previous("1.0", "4.0"),
+        Some(("3.0".to_string(), "resp_3b".to_string()))
+    );
+    assert_eq!(previous("9.0", "4.0"), None);
+
+    // The documents of a request are remembered, so they are not given again
+    store.record("C123", "1.0", "5.0", "resp_5", documents_hash(Some("doc")));
+
+    assert_eq!(
+        store.previous("C123", "1.0", "6.0").unwrap().documents_hash,
+        documents_hash(Some("doc"))
+    );
+    assert_ne!(documents_hash(Some("doc")), documents_hash(Some("other")));
+    assert_eq!(documents_hash(None), None);
+
+    for i in 0..MAX_THREAD_RESPONSES {
+        store.record("C123", "1.0", &format!("{}.0", i + 10), "resp", None);
+    }
 
-        if sent.is_err() {
-            error!("Edit message failed: {:?}", sent.err());
-        }
+    assert_eq!(previous("1.0", "4.0"), None);
 
-        Ok(())
+    for i in 0..MAX_STORED_THREADS {
+        store.record("C123", &format!("{}.0", i + 2), "100.0", "resp", None);
     }
+
+    assert_eq!(previous("1.0", "100.0"), None);
+    assert!(previous("2.0", "200.0").is_some());
+}
+
+#[test]
+fn test_previous_response_not_found() {
+    assert!(is_previous_response_not_found(
+        r#"{"error": {"message": "Previous response with id 'resp_1' not found.", "type": "invalid_request_error", "param": "previous_response_id", "code": "previous_response_not_found"}}"#
+    ));
+    assert!(!is_previous_response_not_found(
+        r#"{"error": {"message": "Rate limit reached", "type": "requests", "code": null}}"#
+    ));
+    assert!(!is_previous_response_not_found("Bad gateway"));
+}
+
+#[test]
+fn test_url_citations() {
+    let data = r#"{
+        "id": "resp_1",
+        "output": [{
+            "type": "message",
+            "id": "msg_1",
+            "status": "completed",
+            "role": "assistant",
+            "content": [{
+                "type": "output_text",
+                "text": "Rust 1.80 is out ([blog.rust-lang.org](https://blog.rust-lang.org/a)). 러스트 ([rust-lang.org](https://www.rust-lang.org/b))",
+                "annotations": [
+                    {"type": "url_citation", "start_index": 17, "end_index": 69, "url": "https://blog.rust-lang.org/a", "title": "Announcing Rust"},
+                    {"type": "url_citation", "start_index": 75, "end_index": 121, "url": "https://www.rust-lang.org/b", "title": "Rust"},
+                    {"type": "file_citation", "index": 0, "file_id": "file_1"}
+                ]
+            }]
+        }]
+    }"#;
+
+    let response = serde_json::from_str::<ResponsesCompletedResponse>(data).unwrap();
+
+    let content = match &response.output[0] {
+        ResponsesStreamingOutput::Message { content, .. } => &content[0],
+        etc => panic!("Unexpected output {:?}", etc),
+    };
+
+    let mut citations = vec![];
+    let text = apply_citations(&content.text, &content.citation_spans(), &mut citations);
+
+    assert_eq!(text, "Rust 1.80 is out [1]. 러스트 [2]");
+    assert_eq!(citations.len(), 2);
+    assert_eq!(citations[1].domain(), "rust-lang.org");
+}
+
+#[test]
+fn test_remote_mcp_outputs() {
+    let data = r#"{
+        "id": "resp_1",
+        "output": [
+            {"type": "mcp_list_tools", "id": "mcpl_1", "server_label": "wiki", "tools": []},
+            {"type": "mcp_approval_request", "id": "mcpr_1", "server_label": "wiki", "name": "ask", "arguments": "{\"q\":\"rust\"}"},
+            {"type": "mcp_call", "id": "mcp_1", "server_label": "wiki", "name": "read", "arguments": "{}", "output": "contents", "error": null},
+            {"type": "mcp_call", "id": "mcp_2", "server_label": "wiki", "name": "read", "arguments": "{}", "output": null, "error": "Not found"}
+        ]
+    }"#;
+
+    let response = serde_json::from_str::<ResponsesCompletedResponse>(data).unwrap();
+
+    assert!(matches!(
+        response.output[0],
+        ResponsesStreamingOutput::McpListTools
+    ));
+    assert!(matches!(
+        &response.output[1],
+        ResponsesStreamingOutput::McpApprovalRequest { id, .. } if id == "mcpr_1"
+    ));
+
+    let tool_call = response.output[2].mcp_tool_call().unwrap();
+
+    assert_eq!(tool_call.name, "wiki_read");
+    assert_eq!(tool_call.result, Ok("contents".to_string()));
+
+    let tool_call = response.output[3].mcp_tool_call().unwrap();
+
+    assert_eq!(tool_call.result, Err("Error: Not found".to_string()));
+    assert!(response.output[1].mcp_tool_call().is_none());
 }
@@ -1,23 +1,68 @@
-use std::{borrow::Cow, env};
+use std::{borrow::Cow, collections::HashMap, env, time::Duration};
 
+use anyhow::anyhow;
 use futures::StreamExt;
 use log::{debug, error, info};
+use reqwest_eventsource::{Event, EventSource};
 use serde::{Deserialize, Serialize};
+use tokio::time::{self, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    slack::{BlockElement, PostMessageResponse, SectionBlock, ThreadMessageType},
-    Bot, Message, ReplyMessageEvent,
+    slack::{BlockElement, SectionBlock, ThreadMessageType},
+    Bot, Message,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GeminiChatStreamText {
-    text: String,
+use super::llm::{
+    add_usage, answer_blocks, answer_text, call_tool, command, post_final_answer,
+    throttle::EditThrottle, tools_metadata, AnswerControls, AnswerVersion, LlmOptions, LlmProvider,
+    LlmRequest, TokenUsage, ToolCall, MAX_TOOL_ROUNDS, TOOL_ROUNDS_NOTICE,
+};
+use super::transcribe;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiChatPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<GeminiFunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_response: Option<GeminiFunctionResponse>,
+    // Thinking models require the signature to be sent back with the function call part
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thought_signature: Option<String>,
+}
+
+impl GeminiChatPart {
+    fn new_text(text: String) -> Self {
+        Self {
+            text: Some(text),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GeminiFunctionCall {
+    name: String,
+    #[serde(default)]
+    args: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GeminiFunctionResponse {
+    name: String,
+    response: serde_json::Value,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct GeminiChatStreamMessage {
+    // System instructions have no role
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     role: String,
-    parts: Vec<GeminiChatStreamText>,
+    #[serde(default)]
+    parts: Vec<GeminiChatPart>,
 }
 
 #[derive(Debug, Serialize)]
@@ -26,6 +71,18 @@ struct GeminiChatStreamBody {
     contents: Vec<GeminiChatStreamMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     generation_config: Option<GeminiChatGenerationConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<GeminiToolConfig>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCompleteBody {
+    system_instruction: GeminiChatStreamMessage,
+    contents: Vec<GeminiChatStreamMessage>,
+    generation_config: GeminiChatGenerationConfig,
 }
 
 #[derive(Debug, Serialize)]
@@ -36,31 +93,134 @@ struct GeminiChatGenerationConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    max_output_tokens: Option<i32>,
+    max_output_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     top_p: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiTool {
+    function_declarations: Vec<GeminiFunctionDeclaration>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiToolConfig {
+    function_calling_config: GeminiFunctionCallingConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiFunctionCallingConfig {
+    mode: &'static str,
+}
+
+impl GeminiToolConfig {
+    /// Keeps the tools declared, as the conversation refers to them, but lets none be called.
+    fn no_calls() -> Self {
+        Self {
+            function_calling_config: GeminiFunctionCallingConfig { mode: "NONE" },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiFunctionDeclaration {
+    name: String,
+    description: String,
+    // Gemini rejects object parameters without properties, so omit them for argument-less tools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters_json_schema: Option<serde_json::Value>,
 }
 
 #[allow(dead_code)]
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ResChatCompletion {
+    #[serde(default)]
     candidates: Vec<ResChatCandidate>,
     prompt_feedback: Option<ResPromptFeedback>,
+    usage_metadata: Option<ResUsageMetadata>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResUsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
+    // Thinking models bill their thoughts as output
+    #[serde(default)]
+    thoughts_token_count: u64,
 }
 
 #[allow(dead_code)]
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ResChatCandidate {
-    content: GeminiChatStreamMessage,
-    finish_reason: String,
-    index: i32,
+    content: Option<GeminiChatStreamMessage>,
+    finish_reason: Option<String>,
+    index: Option<i32>,
+    #[serde(default)]
     safety_ratings: Vec<ResChatSafetyRating>,
 }
 
+impl ResChatCandidate {
+    fn parts(&self) -> &[GeminiChatPart] {
+        match &self.content {
+            Some(content) => &content.parts,
+            None => &[],
+        }
+    }
+
+    fn text(&self) -> String {
+        self.parts()
+            .iter()
+            .filter_map(|part| part.text.as_deref())
+            .collect()
+    }
+
+    fn finish_notice(&self) -> Option<String> {
+        finish_reason_notice(self.finish_reason.as_deref()?)
+    }
+}
+
+fn has_function_calls(parts: &[GeminiChatPart]) -> bool {
+    parts.iter().any(|part| part.function_call.is_some())
+}
+
+/// Adds a streamed part to the model turn, joining text split over chunks.
+/// Parts with a signature are kept as they came, as the signature belongs to them.
+fn push_part(parts: &mut Vec<GeminiChatPart>, part: GeminiChatPart) {
+    let is_plain_text = |part: &GeminiChatPart| {
+        part.text.is_some()
+            && part.function_call.is_none()
+            && part.function_response.is_none()
+            && part.thought_signature.is_none()
+    };
+
+    if is_plain_text(&part) {
+        let text = part.text.as_deref().unwrap_or_default();
+
+        if text.is_empty() {
+            return;
+        }
+
+        if let Some(last) = parts.last_mut().filter(|last| is_plain_text(last)) {
+            last.text.get_or_insert_with(String::new).push_str(text);
+            return;
+        }
+    }
+
+    parts.push(part);
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize)]
 struct ResChatSafetyRating {
@@ -71,81 +231,73 @@ struct ResChatSafetyRating {
 #[allow(dead_code)]
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-
 struct ResPromptFeedback {
+    block_reason: Option<String>,
+    #[serde(default)]
     safety_ratings: Vec<ResChatSafetyRating>,
 }
 
-pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::Result<()> {
-    let slack_bot_format = format!("<@{}>", bot.bot_id());
-    let is_bot_command = msg.text.contains(&slack_bot_format);
+impl ResChatCompletion {
+    fn block_notice(&self) -> Option<String> {
+        let block_reason = self.prompt_feedback.as_ref()?.block_reason.as_ref()?;
 
-    if !is_bot_command {
-        return Ok(());
+        Some(format!("Prompt blocked: {}", block_reason))
     }
 
-    let command_str = msg.text.replace(&slack_bot_format, "");
-
-    let slices = command_str.split_whitespace().collect::<Vec<&str>>();
-
-    if slices.is_empty() {
-        return Ok(());
+    fn token_usage(&self) -> Option<TokenUsage> {
+        self.usage_metadata.as_ref().map(|usage| TokenUsage {
+            input_tokens: usage.prompt_token_count,
+            output_tokens: usage.candidates_token_count + usage.thoughts_token_count,
+        })
     }
+}
 
-    let call_type = slices[0];
-
-    let gemini_split = call_type.split("gemini").collect::<Vec<_>>();
-
-    if gemini_split[0] != "" {
-        return Ok(());
-    }
-
-    let temperature = gemini_split[1].parse::<f32>().unwrap_or(0.0);
-
-    let call_prefix = format!("{} {} ", slack_bot_format, call_type);
-
-    debug!("Gemini: bot command full text = {:?}", &msg.text);
-
-    let input_text = slices.iter().cloned().skip(1).collect::<Vec<_>>().join(" ");
-
+/// `documents` is the text of the files shared in the thread and of the attached MCP resources,
+/// given before the conversation.
+pub async fn answer<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    documents: Option<&str>,
+) -> anyhow::Result<()> {
     let gemini_req = reqwest::Client::builder()
         .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:94.0) Gecko/20100101 Firefox/94.0")
         .build()?;
 
-    let thread_ts = if let Some(thread_ts) = msg.thread_ts.clone() {
-        thread_ts
-    } else {
-        msg.ts.clone()
-    };
-
-    let conv_fut = bot.get_conversation_replies(&msg.channel, thread_ts.as_str());
+    let conv_fut = bot.get_conversation_replies(&request.channel, &request.thread_ts);
     let conv_result = conv_fut.await;
 
     let stream_mode_str = env::var("USE_GEMINI_STREAM").unwrap_or("true".to_string());
     let stream_mode_str = stream_mode_str.to_lowercase();
 
-    let stream_mode = if stream_mode_str == "true" || stream_mode_str == "1" {
-        true
-    } else {
-        false
-    };
+    let stream_mode = stream_mode_str == "true" || stream_mode_str == "1";
 
-    let gemini_model = env::var("GEMINI_MODEL").unwrap_or("gemini-pro".to_string());
+    let gemini_model = request.options.model(LlmProvider::Gemini);
 
     let mut gemini_body = GeminiChatStreamBody {
         contents: vec![],
         generation_config: Some(GeminiChatGenerationConfig {
             stop_sequences: None,
-            temperature: Some(temperature),
-            max_output_tokens: None,
+            temperature: request.options.temperature(LlmProvider::Gemini),
+            max_output_tokens: request.options.max_tokens,
             top_p: None,
             top_k: None,
+            response_mime_type: None,
         }),
+        tools: if request.options.no_tools {
+            vec![]
+        } else {
+            get_tools(bot).await?
+        },
+        tool_config: None,
     };
 
     if let Ok(conv_res) = conv_result {
         if let Some(messages) = conv_res.messages {
             messages.iter().for_each(|msg| {
+                if !request.is_in_context(msg) {
+                    return;
+                }
+
                 let (role, mut content) = match msg {
                     ThreadMessageType::Unbroadcasted(val) => ("user", val.text.clone()),
                     ThreadMessageType::Broadcasted(val) => {
@@ -154,69 +306,64 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
                         } else {
                             let speaker = "model";
 
-                            if val.blocks.len() < 2 {
-                                return;
-                            }
-
-                            let mut text = val.text.clone();
-
-                            let mut is_valid_response = false;
-
-                            if let BlockElement::Section(section) = &val.blocks[0] {
-                                if section.text.text == "`Gemini`" {
-                                    if let BlockElement::Section(real_text_section) = &val.blocks[1]
-                                    {
-                                        text = real_text_section.text.text.clone();
-                                        is_valid_response = true;
-                                    }
-                                }
-                            }
-
-                            if !is_valid_response {
-                                return;
-                            }
+                            let text = match answer_text(LlmProvider::Gemini, &val.blocks) {
+                                Some(text) => text,
+                                None => return,
+                            };
 
                             (speaker, text)
                         }
                     }
+                    ThreadMessageType::Bot(val) => match transcribe::transcript_text(&val.blocks) {
+                        Some(text) => ("user", text),
+                        None => return,
+                    },
                     ThreadMessageType::None(_) => return,
                 };
 
+                // The request itself may be more than its message, as with MCP prompts
                 if role == "user" {
-                    let call_split = content.split(&call_prefix).collect::<Vec<_>>();
-
-                    if call_split.len() == 2 {
-                        content = call_split[1].to_string();
-                    }
+                    content = if msg.ts().as_deref() == Some(request.ts.as_str()) {
+                        request.input_text.clone()
+                    } else {
+                        command::strip_command(bot.bot_id(), content)
+                    };
                 }
 
                 let role = role.to_string();
 
                 gemini_body.contents.push(GeminiChatStreamMessage {
                     role,
-                    parts: vec![GeminiChatStreamText { text: content }],
+                    parts: vec![GeminiChatPart::new_text(content)],
                 });
             });
         }
     };
 
-    if gemini_body.contents.len() == 0 {
+    if gemini_body.contents.is_empty() {
         error!("Error! no thread found");
 
         gemini_body.contents = vec![GeminiChatStreamMessage {
             role: "user".to_string(),
-            parts: vec![GeminiChatStreamText { text: input_text }],
+            parts: vec![GeminiChatPart::new_text(request.input_text.clone())],
         }];
     }
 
-    let reply_event = Some(ReplyMessageEvent {
-        msg: thread_ts,
-        broadcast: true,
-    });
+    if let Some(documents) = documents {
+        gemini_body.contents.insert(
+            0,
+            GeminiChatStreamMessage {
+                role: "user".to_string(),
+                parts: vec![GeminiChatPart::new_text(documents.to_string())],
+            },
+        );
+    }
+
+    let reply_event = request.reply_event();
 
     let chat_url = if stream_mode {
         format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:streamGenerateContent",
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:streamGenerateContent?alt=sse",
             gemini_model
         )
     } else {
@@ -226,85 +373,175 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
         )
     };
 
-    let gemini_builder = gemini_req
-        .post(chat_url)
-        .header("x-goog-api-key", bot.gemini_key())
-        .json(&gemini_body);
-
     if stream_mode {
-        let mut gemini_message = GeminiMessageManager::new(&msg.channel, reply_event.clone());
-        let mut initial_received = false;
+        let mut gemini_message = GeminiMessageManager::new(request);
+        let mut finish_notice = None;
+
+        gemini_message
+            .stream_message(bot, Some("`Receiving...`"))
+            .await?;
+
+        let cancel_token = gemini_message.cancel_token();
+
+        let stream_fut = async {
+            let mut tool_rounds = 0;
+
+            loop {
+                let gemini_builder = gemini_req
+                    .post(&chat_url)
+                    .header("x-goog-api-key", bot.gemini_key())
+                    .json(&gemini_body);
+
+                let mut gemini_sse = EventSource::new(gemini_builder)?;
+                // The whole model turn, sent back with the results of its function calls
+                let mut model_parts = vec![];
+                // Every chunk reports the usage of the whole call so far
+                let mut call_usage = None;
+
+                let mut flush_timer = time::interval(gemini_message.flush_interval());
+                flush_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                loop {
+                    let event = tokio::select! {
+                        event = gemini_sse.next() => match event {
+                            Some(event) => event,
+                            None => break,
+                        },
+                        _ = flush_timer.tick() => {
+                            gemini_message.flush(bot, false).await;
+                            continue;
+                        }
+                    };
 
-        let gemini_res = gemini_builder.send().await;
+                    match event {
+                        Ok(Event::Open) => {
+                            debug!("Gemini SSE opened");
+                        }
+                        Ok(Event::Message(event)) => {
+                            let sse_res = serde_json::from_str::<ResChatCompletion>(&event.data);
 
-        if gemini_res.is_err() {
-            let debug_str = "Gemini API call failed";
-            debug!("{}", debug_str);
+                            if sse_res.is_err() {
+                                error!("Gemini SSE json parsing failed: {:?}", event.data);
+                                continue;
+                            }
 
-            return bot
-                .send_message(
-                    &msg.channel,
-                    Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(debug_str))]),
-                    reply_event,
-                    None,
-                )
-                .await
-                .and(Ok(()));
-        }
+                            let stream_res_json = sse_res.unwrap();
 
-        let mut bytes_stream = gemini_res.unwrap().bytes_stream();
+                            if let Some(notice) = stream_res_json.block_notice() {
+                                finish_notice = Some(notice);
+                            }
 
-        while let Some(Ok(next_bytes)) = bytes_stream.next().await {
-            let mut data = String::from_utf8(next_bytes.to_vec()).unwrap();
+                            if let Some(usage) = stream_res_json.token_usage() {
+                                call_usage = Some(usage);
+                            }
 
-            if data.starts_with("[") || data.starts_with(",") {
-                data = data[1..].to_string();
-            } else if data.ends_with("]") && data.len() > 2 {
-                data = data[..data.len() - 2].to_string();
-            }
+                            let candidate = match stream_res_json.candidates.first() {
+                                Some(candidate) => candidate,
+                                None => continue,
+                            };
 
-            let sse_res = serde_json::from_str::<ResChatCompletion>(&data);
+                            if let Some(notice) = candidate.finish_notice() {
+                                finish_notice = Some(notice);
+                            }
 
-            if sse_res.is_err() {
-                error!("Gemini SSE json parsing failed: {:?}", data);
-                continue;
-            }
+                            for part in candidate.parts() {
+                                push_part(&mut model_parts, part.clone());
+                            }
 
-            let stream_res_json = sse_res.unwrap();
-            let diff_message = stream_res_json.candidates[0].content.parts[0].text.clone();
+                            let diff_message = candidate.text();
 
-            if !initial_received {
-                initial_received = true;
+                            if diff_message.is_empty() {
+                                continue;
+                            }
 
-                match gemini_message
-                    .stream_message(bot, Some("`Receiving...`"))
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Gemini SSE stream message sending failed: {:?}", e);
+                            let sent = gemini_message.stream_delta(bot, &diff_message).await;
+
+                            if sent.is_err() {
+                                error!("Gemini SSE stream message sending failed: {:?}", sent);
+
+                                return Ok(());
+                            }
+                        }
+                        Err(e) => {
+                            match e {
+                                reqwest_eventsource::Error::StreamEnded => {
+                                    debug!("Gemini SSE stream ended");
+                                }
+                                reqwest_eventsource::Error::InvalidStatusCode(code, err_res) => {
+                                    error!("Gemini SSE error code: {}", code);
+
+                                    match err_res.text().await {
+                                        Ok(body) => error!("Gemini SSE error res body: {}", body),
+                                        Err(e) => error!("Failed to read error res body: {:?}", e),
+                                    }
+
+                                    finish_notice =
+                                        Some(format!("Gemini API call failed: {}", code));
+                                }
+                                _ => {
+                                    error!("Gemini SSE error: {:?}", e);
+
+                                    finish_notice = Some("Gemini API call failed".to_string());
+                                }
+                            }
+
+                            break;
+                        }
                     }
                 }
-            }
 
-            gemini_message.concat_message(&diff_message);
+                gemini_sse.close();
+                gemini_message.add_usage(call_usage);
 
-            let sent = gemini_message
-                .stream_message(bot, Some(" `[continue]`"))
-                .await;
+                if !has_function_calls(&model_parts) || finish_notice.is_some() {
+                    break;
+                }
 
-            if sent.is_err() {
-                error!("Gemini SSE stream message sending failed: {:?}", sent);
+                if tool_rounds == MAX_TOOL_ROUNDS {
+                    finish_notice = Some(TOOL_ROUNDS_NOTICE.to_string());
+                    break;
+                }
+
+                // Tools may take a while, so show what came before them
+                gemini_message.flush(bot, true).await;
 
-                return Ok(());
+                let (function_responses, function_tool_calls) =
+                    get_function_responses(bot, request, &model_parts).await?;
+
+                gemini_message
+                    .push_tool_calls(bot, function_tool_calls)
+                    .await;
+
+                gemini_body.contents.push(GeminiChatStreamMessage {
+                    role: "model".to_string(),
+                    parts: model_parts,
+                });
+                gemini_body.contents.push(function_responses);
+
+                tool_rounds += 1;
+
+                if tool_rounds == MAX_TOOL_ROUNDS {
+                    gemini_body.tool_config = Some(GeminiToolConfig::no_calls());
+                }
             }
-        }
 
-        let done_message = "[DONE]";
+            Ok::<_, anyhow::Error>(())
+        };
+
+        // Dropping the stream future closes the SSE connection and any pending tool call
+        let stream_res = tokio::select! {
+            _ = cancel_token.cancelled() => None,
+            res = stream_fut => Some(res),
+        };
 
-        gemini_message.concat_message(&format!(" `{}`", done_message));
+        let done_message = match (&stream_res, &finish_notice) {
+            (None, _) => "[stopped]".to_string(),
+            (Some(Err(_)), _) => "[ERROR]".to_string(),
+            (Some(Ok(_)), Some(notice)) => format!("[{}]", notice),
+            (Some(Ok(_)), None) => "[DONE]".to_string(),
+        };
 
-        let sent = gemini_message.stream_message(bot, None).await;
+        let sent = gemini_message.finish(bot, &done_message).await;
 
         if sent.is_err() {
             error!(
@@ -313,107 +550,379 @@ pub async fn handle<'a, B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::R
             );
         }
 
-        Ok(())
+        stream_res.unwrap_or(Ok(()))
     } else {
-        let gemini_res = gemini_builder.send().await;
+        let mut tool_calls = vec![];
+        let mut usage = None;
+        let mut tool_rounds = 0;
+
+        loop {
+            let gemini_res = gemini_req
+                .post(&chat_url)
+                .header("x-goog-api-key", bot.gemini_key())
+                .json(&gemini_body)
+                .send()
+                .await;
 
-        if gemini_res.is_err() {
-            let debug_str = "Gemini API call failed";
-            debug!("{}", debug_str);
+            if gemini_res.is_err() {
+                let debug_str = "Gemini API call failed";
+                debug!("{}", debug_str);
+
+                return bot
+                    .send_message(
+                        &request.channel,
+                        Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(
+                            debug_str,
+                        ))]),
+                        reply_event,
+                        None,
+                    )
+                    .await
+                    .and(Ok(()));
+            }
 
-            return bot
-                .send_message(
-                    &msg.channel,
-                    Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(debug_str))]),
-                    reply_event,
-                    None,
-                )
-                .await
-                .and(Ok(()));
-        }
+            let res = gemini_res.unwrap();
+            let res_len = res.content_length().unwrap_or(0);
 
-        let res = gemini_res.unwrap();
-        let res_len = res.content_length().unwrap_or(0);
+            let res_bytes = res.bytes().await;
 
-        let res_bytes = res.bytes().await;
+            if res_bytes.is_err() {
+                let debug_str = format!("Gemini result bytes error: {}", res_len);
+                debug!("{}", debug_str);
 
-        if res_bytes.is_err() {
-            let debug_str = format!("Gemini result bytes error: {}", res_len);
-            debug!("{}", debug_str);
+                return bot
+                    .send_message(
+                        &request.channel,
+                        Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(
+                            &debug_str,
+                        ))]),
+                        reply_event,
+                        None,
+                    )
+                    .await
+                    .and(Ok(()));
+            }
 
-            return bot
-                .send_message(
-                    &msg.channel,
-                    Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(&debug_str))]),
-                    reply_event,
-                    None,
-                )
-                .await
-                .and(Ok(()));
-        }
+            let res_bytes = res_bytes.unwrap();
+            info!("{:?}", res_bytes);
 
-        let res_bytes = res_bytes.unwrap();
-        info!("{:?}", res_bytes);
+            let res_body_result = serde_json::from_slice::<ResChatCompletion>(&res_bytes);
 
-        let res_body_result = serde_json::from_slice::<ResChatCompletion>(&res_bytes);
+            if res_body_result.is_err() {
+                let debug_str = format!(
+                    "Gemini result json parsing failed: {:?}",
+                    String::from_utf8(res_bytes.to_vec()).unwrap()
+                );
 
-        if res_body_result.is_err() {
-            let debug_str = format!(
-                "Gemini result json parsing failed: {:?}",
-                String::from_utf8(res_bytes.to_vec()).unwrap()
-            );
+                debug!("{}", debug_str);
 
-            debug!("{}", debug_str);
+                return bot
+                    .send_message(
+                        &request.channel,
+                        Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(
+                            &debug_str,
+                        ))]),
+                        reply_event,
+                        None,
+                    )
+                    .await
+                    .and(Ok(()));
+            }
 
-            return bot
-                .send_message(
-                    &msg.channel,
-                    Message::Blocks(&[BlockElement::Section(SectionBlock::new_text(&debug_str))]),
-                    reply_event,
-                    None,
-                )
-                .await
-                .and(Ok(()));
+            let res_body = res_body_result.unwrap();
+
+            add_usage(&mut usage, res_body.token_usage());
+
+            let candidate = match res_body.candidates.first() {
+                Some(candidate) => candidate,
+                None => {
+                    let error_text = match res_body.block_notice() {
+                        Some(notice) => format!("ditto_bot Error: `[{}]`", notice),
+                        None => "ditto_bot Error: ".to_string(),
+                    };
+
+                    return post_final_answer(
+                        bot,
+                        request,
+                        AnswerVersion {
+                            provider: LlmProvider::Gemini,
+                            model: gemini_model,
+                            text: error_text,
+                            usage,
+                            thought_for: None,
+                            citations: vec![],
+                            tool_calls,
+                        },
+                        request.answer_ts.as_deref(),
+                    )
+                    .await;
+                }
+            };
+
+            let has_calls = has_function_calls(candidate.parts());
+
+            if has_calls && tool_rounds < MAX_TOOL_ROUNDS {
+                let (function_responses, function_tool_calls) =
+                    get_function_responses(bot, request, candidate.parts()).await?;
+
+                tool_calls.extend(function_tool_calls);
+
+                // The whole model turn, so the model sees what it said before the calls
+                gemini_body.contents.push(GeminiChatStreamMessage {
+                    role: "model".to_string(),
+                    parts: candidate.parts().to_vec(),
+                });
+                gemini_body.contents.push(function_responses);
+
+                tool_rounds += 1;
+
+                if tool_rounds == MAX_TOOL_ROUNDS {
+                    gemini_body.tool_config = Some(GeminiToolConfig::no_calls());
+                }
+
+                continue;
+            }
+
+            let mut res_text = candidate.text().trim_start().to_string();
+
+            if let Some(notice) = candidate.finish_notice() {
+                res_text += &format!(" `[{}]`", notice);
+            } else if has_calls {
+                res_text += &format!(" `[{}]`", TOOL_ROUNDS_NOTICE);
+            }
+
+            return post_final_answer(
+                bot,
+                request,
+                AnswerVersion {
+                    provider: LlmProvider::Gemini,
+                    model: gemini_model,
+                    text: res_text,
+                    usage,
+                    thought_for: None,
+                    citations: vec![],
+                    tool_calls,
+                },
+                request.answer_ts.as_deref(),
+            )
+            .await;
         }
+    }
+}
 
-        let res_body = res_body_result.unwrap();
+fn finish_reason_notice(finish_reason: &str) -> Option<String> {
+    let notice = match finish_reason {
+        "STOP" | "FINISH_REASON_UNSPECIFIED" => return None,
+        "MAX_TOKENS" => "Stopped: max output tokens reached",
+        "SAFETY" => "Stopped: blocked by safety filter",
+        "RECITATION" => "Stopped: recitation detected",
+        "LANGUAGE" => "Stopped: unsupported language",
+        "BLOCKLIST" => "Stopped: blocked term detected",
+        "PROHIBITED_CONTENT" => "Stopped: prohibited content",
+        "SPII" => "Stopped: sensitive personal information",
+        "MALFORMED_FUNCTION_CALL" => "Stopped: malformed function call",
+        etc => return Some(format!("Stopped: {}", etc)),
+    };
 
-        let res_text = if res_body.candidates.len() == 0 {
-            "ditto_bot Error: "
-        } else {
-            &res_body.candidates[0].content.parts[0].text
-        };
+    Some(notice.to_string())
+}
 
-        let res_text = res_text.trim_start();
+async fn get_tools<B: Bot>(bot: &B) -> anyhow::Result<Vec<GeminiTool>> {
+    let all_tools = tools_metadata(bot).await?;
 
-        GeminiMessageManager::send_message_static(bot, &res_text, &msg.channel, &reply_event)
-            .await
-            .and(Ok(()))
+    let function_declarations = all_tools
+        .into_iter()
+        .map(|tool| GeminiFunctionDeclaration {
+            parameters_json_schema: tool.has_parameters().then_some(tool.parameters),
+            name: tool.name,
+            description: tool.description,
+        })
+        .collect::<Vec<_>>();
+
+    if function_declarations.is_empty() {
+        return Ok(vec![]);
+    }
+
+    Ok(vec![GeminiTool {
+        function_declarations,
+    }])
+}
+
+async fn get_function_responses<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    function_call_parts: &[GeminiChatPart],
+) -> anyhow::Result<(GeminiChatStreamMessage, Vec<ToolCall>)> {
+    let mut parts = vec![];
+    let mut tool_calls = vec![];
+
+    for function_call in function_call_parts
+        .iter()
+        .filter_map(|part| part.function_call.as_ref())
+    {
+        let tool_call = call_tool(
+            bot,
+            request,
+            &function_call.name,
+            function_call.args.clone(),
+        )
+        .await?;
+
+        parts.push(GeminiChatPart {
+            function_response: Some(GeminiFunctionResponse {
+                name: function_call.name.clone(),
+                response: serde_json::json!({ "result": tool_call.output() }),
+            }),
+            ..Default::default()
+        });
+        tool_calls.push(tool_call);
+    }
+
+    Ok((
+        GeminiChatStreamMessage {
+            role: "user".to_string(),
+            parts,
+        },
+        tool_calls,
+    ))
+}
+
+/// Asks for a single answer, without tools or thread history.
+pub async fn complete<B: Bot>(
+    bot: &B,
+    options: &LlmOptions,
+    instructions: &str,
+    input: &str,
+    json: bool,
+) -> anyhow::Result<String> {
+    let body = GeminiCompleteBody {
+        system_instruction: GeminiChatStreamMessage {
+            role: String::new(),
+            parts: vec![GeminiChatPart::new_text(instructions.to_string())],
+        },
+        contents: vec![GeminiChatStreamMessage {
+            role: "user".to_string(),
+            parts: vec![GeminiChatPart::new_text(input.to_string())],
+        }],
+        generation_config: GeminiChatGenerationConfig {
+            stop_sequences: None,
+            temperature: options.temperature(LlmProvider::Gemini),
+            max_output_tokens: options.max_tokens,
+            top_p: None,
+            top_k: None,
+            response_mime_type: json.then_some("application/json"),
+        },
+    };
+
+    let res = reqwest::Client::new()
+        .post(format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent",
+            options.model(LlmProvider::Gemini)
+        ))
+        .header("x-goog-api-key", bot.gemini_key())
+        .json(&body)
+        .send()
+        .await?;
+
+    let status = res.status();
+
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Gemini API call failed: {} {}",
+            status,
+            res.text().await?
+        ));
+    }
+
+    let res = res.json::<ResChatCompletion>().await?;
+
+    match res.candidates.first() {
+        Some(candidate) => Ok(candidate.text()),
+        None => Err(anyhow!(
+            "Gemini returned no answer: {}",
+            res.block_notice().unwrap_or_default()
+        )),
     }
 }
 
 // TODO save bot as member?
 struct GeminiMessageManager<'a> {
-    channel: &'a String,
+    request: &'a LlmRequest,
     ts: String,
-    reply_event: Option<ReplyMessageEvent>,
     message: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<TokenUsage>,
+    throttle: EditThrottle,
+    cancel_token: CancellationToken,
+    registered: bool,
 }
 
 impl<'a> GeminiMessageManager<'a> {
-    pub fn new(channel: &'a String, reply_event: Option<ReplyMessageEvent>) -> Self {
+    pub fn new(request: &'a LlmRequest) -> Self {
         Self {
-            channel,
+            request,
             message: String::new(),
-            ts: String::new(),
-            reply_event,
+            tool_calls: vec![],
+            usage: None,
+            throttle: EditThrottle::from_env(),
+            ts: request.answer_ts.clone().unwrap_or_default(),
+            cancel_token: CancellationToken::new(),
+            registered: false,
         }
     }
 
-    pub fn concat_message(&mut self, diff_message: &String) {
+    pub fn concat_message(&mut self, diff_message: &str) {
         self.message += diff_message;
     }
 
+    /// Appends a streamed delta, editing the message only when the throttle allows it.
+    pub async fn stream_delta(&mut self, bot: &impl Bot, delta: &str) -> anyhow::Result<()> {
+        self.concat_message(delta);
+
+        if !self.throttle.push(delta) {
+            return Ok(());
+        }
+
+        self.stream_message(bot, Some(" `[continue]`")).await
+    }
+
+    pub async fn push_tool_calls(&mut self, bot: &impl Bot, tool_calls: Vec<ToolCall>) {
+        self.tool_calls.extend(tool_calls);
+
+        if let Err(e) = self.stream_message(bot, Some(" `[continue]`")).await {
+            error!("Tool call message sending failed: {:?}", e);
+        }
+    }
+
+    pub fn add_usage(&mut self, usage: Option<TokenUsage>) {
+        add_usage(&mut self.usage, usage);
+    }
+
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.throttle.min_interval()
+    }
+
+    /// Shows the text held back by the throttle. Unless `now`, only once the interval has passed.
+    pub async fn flush(&mut self, bot: &impl Bot, now: bool) {
+        let due = if now {
+            self.throttle.has_pending()
+        } else {
+            self.throttle.is_due()
+        };
+
+        if !due {
+            return;
+        }
+
+        if let Err(e) = self.stream_message(bot, Some(" `[continue]`")).await {
+            error!("Flushing message failed: {:?}", e);
+        }
+    }
+
     pub async fn stream_message(
         &mut self,
         bot: &impl Bot,
@@ -421,78 +930,168 @@ impl<'a> GeminiMessageManager<'a> {
     ) -> anyhow::Result<()> {
         let mut message = Cow::from(&self.message);
 
-        match temp_message {
-            Some(temp_message) => message += temp_message,
-            None => {}
+        if let Some(temp_message) = temp_message {
+            message += temp_message;
         }
 
+        let blocks = answer_blocks(
+            LlmProvider::Gemini,
+            &self.request.options.model(LlmProvider::Gemini),
+            &message,
+            None,
+            &[],
+            &self.tool_calls,
+            AnswerControls::Stop,
+        );
+
         if !self.ts.is_empty() {
-            return self
-                .edit_message(bot, &message, self.channel, &self.ts)
-                .await;
-        } else {
-            let sent = self
-                .send_message(bot, &message, self.channel, &self.reply_event)
+            let sent = bot
+                .edit_message(&self.request.channel, Message::Blocks(&blocks), &self.ts)
                 .await;
 
             if sent.is_err() {
-                return Err(sent.err().unwrap());
+                error!("Edit message failed: {:?}", sent.err());
             }
+        } else {
+            let sent = bot
+                .send_message(
+                    &self.request.channel,
+                    Message::Blocks(&blocks),
+                    self.request.reply_event(),
+                    None,
+                )
+                .await?;
+
+            self.ts = String::from(&sent.ts.ok_or_else(|| anyhow!("No ts in response"))?);
+        }
 
-            self.ts = String::from(&sent.unwrap().ts.unwrap());
+        self.throttle.edited();
 
-            Ok(())
+        if !self.registered {
+            self.registered = true;
+
+            bot.generations().register(
+                &self.request.channel,
+                &self.ts,
+                &self.request.requester,
+                self.cancel_token.clone(),
+            );
         }
-    }
 
-    pub async fn send_message_static(
-        bot: &impl Bot,
-        message: &str,
-        channel: &str,
-        reply_event: &Option<ReplyMessageEvent>,
-    ) -> anyhow::Result<PostMessageResponse> {
-        let gemini_name_block = BlockElement::Section(SectionBlock::new_markdown("`Gemini`"));
-        let gemini_answer_block = BlockElement::Section(SectionBlock::new_markdown(&message));
+        Ok(())
+    }
 
-        let blocks = [gemini_name_block, gemini_answer_block];
+    pub async fn finish(&mut self, bot: &impl Bot, marker: &str) -> anyhow::Result<()> {
+        self.concat_message(&format!(" `{}`", marker));
+
+        let sent = post_final_answer(
+            bot,
+            self.request,
+            AnswerVersion {
+                provider: LlmProvider::Gemini,
+                model: self.request.options.model(LlmProvider::Gemini),
+                text: self.message.clone(),
+                usage: self.usage,
+                thought_for: None,
+                citations: vec![],
+                tool_calls: self.tool_calls.clone(),
+            },
+            Some(&self.ts),
+        )
+        .await;
 
-        let sent = bot
-            .send_message(channel, Message::Blocks(&blocks), reply_event.clone(), None)
-            .await;
+        bot.generations()
+            .unregister(&self.request.channel, &self.ts);
 
         sent
     }
+}
 
-    async fn send_message(
-        &self,
-        bot: &impl Bot,
-        message: &str,
-        channel: &str,
-        reply_event: &Option<ReplyMessageEvent>,
-    ) -> anyhow::Result<PostMessageResponse> {
-        Self::send_message_static(bot, message, channel, reply_event).await
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_sse_chunks() {
+        let text_chunk = serde_json::from_str::<ResChatCompletion>(
+            r#"{"candidates": [{"content": {"parts": [{"text": "Hello"}],"role": "model"},"index": 0}],"usageMetadata": {"promptTokenCount": 3,"totalTokenCount": 3}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(text_chunk.candidates[0].text(), "Hello");
+        assert_eq!(text_chunk.candidates[0].finish_notice(), None);
+
+        let last_chunk = serde_json::from_str::<ResChatCompletion>(
+            r#"{"candidates": [{"content": {"role": "model"},"finishReason": "MAX_TOKENS","index": 0}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(last_chunk.candidates[0].text(), "");
+        assert_eq!(
+            last_chunk.candidates[0].finish_notice().as_deref(),
+            Some("Stopped: max output tokens reached")
+        );
+
+        let blocked = serde_json::from_str::<ResChatCompletion>(
+            r#"{"promptFeedback": {"blockReason": "SAFETY"}}"#,
+        )
+        .unwrap();
+
+        assert!(blocked.candidates.is_empty());
+        assert_eq!(
+            blocked.block_notice().as_deref(),
+            Some("Prompt blocked: SAFETY")
+        );
     }
 
-    async fn edit_message(
-        &self,
-        bot: &impl Bot,
-        message: &str,
-        channel: &str,
-        ts: &str,
-    ) -> anyhow::Result<()> {
-        let gemini_name_block = BlockElement::Section(SectionBlock::new_markdown("`Gemini`"));
-        let gemini_answer_block = BlockElement::Section(SectionBlock::new_markdown(&message));
+    #[test]
+    fn test_deserialize_function_call_chunk() {
+        let chunk = serde_json::from_str::<ResChatCompletion>(
+            r#"{"candidates": [{"content": {"parts": [{"functionCall": {"name": "mcp-server-time_get_current_time","args": {"timezone": "Asia/Seoul"}},"thoughtSignature": "abc"}],"role": "model"},"finishReason": "STOP"}]}"#,
+        )
+        .unwrap();
+
+        let function_calls = chunk.candidates[0].parts();
+
+        assert!(has_function_calls(function_calls));
+        assert_eq!(function_calls.len(), 1);
+        assert_eq!(
+            function_calls[0].function_call.as_ref().unwrap().name,
+            "mcp-server-time_get_current_time"
+        );
+        assert_eq!(function_calls[0].thought_signature.as_deref(), Some("abc"));
+        assert_eq!(chunk.candidates[0].finish_notice(), None);
+    }
 
-        let blocks = [gemini_name_block, gemini_answer_block];
+    #[test]
+    fn test_push_part() {
+        let mut parts = vec![];
+        let call = GeminiChatPart {
+            function_call: Some(GeminiFunctionCall {
+                name: "fetch".to_string(),
+                args: HashMap::new(),
+            }),
+            thought_signature: Some("abc".to_string()),
+            ..Default::default()
+        };
 
-        let sent = bot
-            .edit_message(channel, Message::Blocks(&blocks), ts)
-            .await;
+        push_part(&mut parts, GeminiChatPart::new_text("Let me ".to_string()));
+        push_part(&mut parts, GeminiChatPart::new_text("check.".to_string()));
+        push_part(&mut parts, GeminiChatPart::new_text(String::new()));
+        push_part(&mut parts, call);
+        push_part(&mut parts, GeminiChatPart::new_text("Done".to_string()));
 
-        if sent.is_err() {
-            error!("Edit message failed: {:?}", sent.err());
-        }
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].text.as_deref(), Some("Let me check."));
+        assert_eq!(parts[1].thought_signature.as_deref(), Some("abc"));
+        assert!(has_function_calls(&parts));
+    }
 
-        Ok(())
+    #[test]
+    fn test_serialize_tool_config() {
+        assert_eq!(
+            serde_json::to_value(GeminiToolConfig::no_calls()).unwrap(),
+            serde_json::json!({"functionCallingConfig": {"mode": "NONE"}})
+        );
     }
 }
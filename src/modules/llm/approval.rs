@@ -0,0 +1,445 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::sync::oneshot;
+
+use crate::{
+    slack::{ActionBlock, BlockElement, ButtonBlock, ButtonStyle, SectionBlock},
+    Bot, Message, ReplyMessageEvent,
+};
+
+use super::{preview, LlmRequest};
+
+pub const APPROVE_ACTION_ID: &str = "tool_call_approve";
+pub const DENY_ACTION_ID: &str = "tool_call_deny";
+
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// Section text is limited to 3000 characters
+const MAX_ARGUMENTS_LEN: usize = 2900;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolPolicy {
+    Always,
+    Never,
+    Ask,
+}
+
+impl ToolPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "always" => Some(ToolPolicy::Always),
+            "never" => Some(ToolPolicy::Never),
+            "ask" => Some(ToolPolicy::Ask),
+            _ => None,
+        }
+    }
+}
+
+/// Approval policy of each tool, e.g. `mcp-server-fetch_fetch=ask,mcp-server-time_*=always,*=never`.
+///
+/// A pattern ending with `*` matches every tool with that prefix, and the longest match wins.
+/// Tools matching no pattern are always allowed.
+#[derive(Debug, Default)]
+pub struct ToolPolicies {
+    exact: HashMap<String, ToolPolicy>,
+    prefixes: Vec<(String, ToolPolicy)>,
+}
+
+impl ToolPolicies {
+    pub fn parse(config: &str) -> Self {
+        let mut policies = Self::default();
+
+        for entry in config.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (pattern, policy) = match entry
+                .split_once('=')
+                .and_then(|(pattern, policy)| Some((pattern.trim(), ToolPolicy::parse(policy)?)))
+            {
+                Some(parsed) => parsed,
+                None => {
+                    warn!("Invalid tool approval policy - {}", entry);
+                    continue;
+                }
+            };
+
+            match pattern.strip_suffix('*') {
+                Some(prefix) => policies.prefixes.push((prefix.to_string(), policy)),
+                None => {
+                    policies.exact.insert(pattern.to_string(), policy);
+                }
+            }
+        }
+
+        policies
+            .prefixes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        policies
+    }
+
+    pub fn get(&self, tool_name: &str) -> ToolPolicy {
+        if let Some(policy) = self.exact.get(tool_name) {
+            return *policy;
+        }
+
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| tool_name.starts_with(prefix.as_str()))
+            .map_or(ToolPolicy::Always, |(_, policy)| *policy)
+    }
+}
+
+pub enum Decision {
+    Approved,
+    Denied(String),
+    TimedOut,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveResult {
+    Resolved,
+    NotAllowed,
+    NotFound,
+}
+
+struct PendingApproval {
+    requester: String,
+    sender: oneshot::Sender<(bool, String)>,
+}
+
+/// A prompt whose answer was stopped while it waited, to be marked as cancelled.
+struct CancelledPrompt {
+    channel: String,
+    ts: String,
+    name: String,
+    arguments: String,
+}
+
+/// Tool calls waiting for a click, keyed by (channel, approval message ts).
+#[derive(Default)]
+pub struct ApprovalRegistry {
+    pending: Mutex<HashMap<(String, String), PendingApproval>>,
+    cancelled: Mutex<Vec<CancelledPrompt>>,
+}
+
+/// Withdraws the prompt if the answer is stopped while waiting, which drops `ask`.
+struct PromptGuard<'a> {
+    registry: &'a ApprovalRegistry,
+    prompt: Option<CancelledPrompt>,
+}
+
+impl PromptGuard<'_> {
+    fn disarm(mut self) {
+        self.prompt = None;
+    }
+}
+
+impl Drop for PromptGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(prompt) = self.prompt.take() {
+            self.registry.unregister(&prompt.channel, &prompt.ts);
+            self.registry.cancelled.lock().unwrap().push(prompt);
+        }
+    }
+}
+
+impl ApprovalRegistry {
+    pub fn register(
+        &self,
+        channel: &str,
+        ts: &str,
+        requester: &str,
+    ) -> oneshot::Receiver<(bool, String)> {
+        let mut pending = self.pending.lock().unwrap();
+        let (sender, receiver) = oneshot::channel();
+
+        // Answers which were stopped while waiting leave their entries behind
+        pending.retain(|_, approval| !approval.sender.is_closed());
+
+        pending.insert(
+            (channel.to_string(), ts.to_string()),
+            PendingApproval {
+                requester: requester.to_string(),
+                sender,
+            },
+        );
+
+        receiver
+    }
+
+    pub fn unregister(&self, channel: &str, ts: &str) {
+        let mut pending = self.pending.lock().unwrap();
+
+        pending.remove(&(channel.to_string(), ts.to_string()));
+    }
+
+    /// Marks the prompts of stopped answers as cancelled, as their buttons do nothing anymore.
+    pub async fn edit_cancelled<B: Bot>(&self, bot: &B) {
+        let cancelled = std::mem::take(&mut *self.cancelled.lock().unwrap());
+
+        for prompt in cancelled {
+            let edited = bot
+                .edit_message(
+                    &prompt.channel,
+                    Message::Blocks(&approval_blocks(
+                        &prompt.name,
+                        &prompt.arguments,
+                        Some("Cancelled"),
+                    )),
+                    &prompt.ts,
+                )
+                .await;
+
+            if let Err(e) = edited {
+                warn!(
+                    "Failed to mark approval {} as cancelled - {:?}",
+                    prompt.ts, e
+                );
+            }
+        }
+    }
+
+    pub fn resolve(
+        &self,
+        channel: &str,
+        ts: &str,
+        user: &str,
+        is_admin: bool,
+        approved: bool,
+    ) -> ResolveResult {
+        let mut pending = self.pending.lock().unwrap();
+        let key = (channel.to_string(), ts.to_string());
+
+        match pending.get(&key) {
+            Some(approval) if approval.requester != user && !is_admin => {
+                return ResolveResult::NotAllowed
+            }
+            Some(_) => {}
+            None => return ResolveResult::NotFound,
+        }
+
+        let approval = pending.remove(&key).unwrap();
+
+        match approval.sender.send((approved, user.to_string())) {
+            Ok(()) => ResolveResult::Resolved,
+            Err(_) => ResolveResult::NotFound,
+        }
+    }
+}
+
+fn approval_blocks(name: &str, arguments: &str, status: Option<&str>) -> Vec<BlockElement> {
+    let mut blocks = vec![
+        BlockElement::Section(SectionBlock::new_markdown(&format!(
+            "Tool call `{}` needs approval",
+            name
+        ))),
+        BlockElement::Section(SectionBlock::new_markdown(&format!(
+            "```{}```",
+            preview(arguments, MAX_ARGUMENTS_LEN)
+        ))),
+    ];
+
+    match status {
+        Some(status) => blocks.push(BlockElement::Section(SectionBlock::new_markdown(status))),
+        None => blocks.push(BlockElement::Actions(ActionBlock {
+            block_id: None,
+            elements: Some(vec![
+                BlockElement::Button(ButtonBlock::new(
+                    "Approve",
+                    APPROVE_ACTION_ID,
+                    APPROVE_ACTION_ID,
+                    Some(ButtonStyle::Primary),
+                )),
+                BlockElement::Button(ButtonBlock::new(
+                    "Deny",
+                    DENY_ACTION_ID,
+                    DENY_ACTION_ID,
+                    Some(ButtonStyle::Danger),
+                )),
+            ]),
+        })),
+    }
+
+    blocks
+}
+
+/// Posts an Approve/Deny prompt for the tool call in the thread and waits for the requester.
+pub async fn ask<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    name: &str,
+    arguments: &HashMap<String, serde_json::Value>,
+) -> anyhow::Result<Decision> {
+    let arguments = serde_json::to_string_pretty(arguments)?;
+
+    let res = bot
+        .send_message(
+            &request.channel,
+            Message::Blocks(&approval_blocks(name, &arguments, None)),
+            Some(ReplyMessageEvent {
+                msg: request.thread_ts.clone(),
+                broadcast: false,
+            }),
+            None,
+        )
+        .await?;
+
+    let ts = res
+        .ts
+        .as_ref()
+        .map(String::from)
+        .ok_or_else(|| anyhow::anyhow!("Failed to post approval request - {:?}", res.error))?;
+
+    let receiver = bot
+        .approvals()
+        .register(&request.channel, &ts, &request.requester);
+    let guard = PromptGuard {
+        registry: bot.approvals(),
+        prompt: Some(CancelledPrompt {
+            channel: request.channel.clone(),
+            ts: ts.clone(),
+            name: name.to_string(),
+            arguments: arguments.clone(),
+        }),
+    };
+
+    let waited = tokio::time::timeout(APPROVAL_TIMEOUT, receiver).await;
+    guard.disarm();
+
+    let (decision, status) = match waited {
+        Ok(Ok((true, user))) => (Decision::Approved, format!("Approved by <@{}>", user)),
+        Ok(Ok((false, user))) => {
+            let status = format!("Denied by <@{}>", user);
+            (Decision::Denied(user), status)
+        }
+        Ok(Err(_)) | Err(_) => {
+            bot.approvals().unregister(&request.channel, &ts);
+            (Decision::TimedOut, "Timed out".to_string())
+        }
+    };
+
+    bot.edit_message(
+        &request.channel,
+        Message::Blocks(&approval_blocks(name, &arguments, Some(&status))),
+        &ts,
+    )
+    .await?;
+
+    Ok(decision)
+}
+
+pub fn handle_action<B: Bot>(bot: &B, action: &crate::ActionEvent, approved: bool) {
+    match bot.approvals().resolve(
+        &action.channel,
+        &action.message_ts,
+        &action.user,
+        bot.is_admin(&action.user),
+        approved,
+    ) {
+        ResolveResult::Resolved => {
+            info!(
+                "Tool call {} in {} {} by {}",
+                action.message_ts,
+                action.channel,
+                if approved { "approved" } else { "denied" },
+                action.user
+            );
+        }
+        ResolveResult::NotAllowed => {
+            warn!(
+                "User {} is not allowed to approve tool call {} in {}",
+                action.user, action.message_ts, action.channel
+            );
+        }
+        ResolveResult::NotFound => {
+            debug!(
+                "No tool call waiting for approval for {} in {}",
+                action.message_ts, action.channel
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tool_policies() {
+        let policies = ToolPolicies::parse(
+            "mcp-server-fetch_fetch=ask, mcp-server-fetch_*=never,*=always,broken",
+        );
+
+        assert_eq!(policies.get("mcp-server-fetch_fetch"), ToolPolicy::Ask);
+        assert_eq!(policies.get("mcp-server-fetch_other"), ToolPolicy::Never);
+        assert_eq!(policies.get("mcp-server-time_now"), ToolPolicy::Always);
+        assert_eq!(ToolPolicies::parse("").get("anything"), ToolPolicy::Always);
+    }
+
+    #[test]
+    fn test_resolve_approval() {
+        let registry = ApprovalRegistry::default();
+        let mut receiver = registry.register("C123", "1.0", "U1");
+
+        assert_eq!(
+            registry.resolve("C123", "2.0", "U1", false, true),
+            ResolveResult::NotFound
+        );
+        assert_eq!(
+            registry.resolve("C123", "1.0", "U2", false, true),
+            ResolveResult::NotAllowed
+        );
+        assert_eq!(
+            registry.resolve("C123", "1.0", "U1", false, false),
+            ResolveResult::Resolved
+        );
+        assert_eq!(receiver.try_recv().unwrap(), (false, "U1".to_string()));
+        assert_eq!(
+            registry.resolve("C123", "1.0", "U1", false, true),
+            ResolveResult::NotFound
+        );
+    }
+
+    #[test]
+    fn test_prompt_guard() {
+        let registry = ApprovalRegistry::default();
+        let prompt = |ts: &str| CancelledPrompt {
+            channel: "C123".to_string(),
+            ts: ts.to_string(),
+            name: "fetch".to_string(),
+            arguments: "{}".to_string(),
+        };
+
+        let _receiver = registry.register("C123", "1.0", "U1");
+        PromptGuard {
+            registry: &registry,
+            prompt: Some(prompt("1.0")),
+        }
+        .disarm();
+
+        assert!(registry.cancelled.lock().unwrap().is_empty());
+
+        // Dropped while waiting, as when the answer is stopped
+        let _receiver = registry.register("C123", "2.0", "U1");
+        drop(PromptGuard {
+            registry: &registry,
+            prompt: Some(prompt("2.0")),
+        });
+
+        assert_eq!(
+            registry.resolve("C123", "2.0", "U1", false, true),
+            ResolveResult::NotFound
+        );
+        assert_eq!(registry.cancelled.lock().unwrap()[0].ts, "2.0");
+    }
+
+    #[test]
+    fn test_approval_blocks_length() {
+        let arguments = "x".repeat(10_000);
+        let blocks = approval_blocks("fetch", &arguments, None);
+
+        match &blocks[1] {
+            BlockElement::Section(section) => assert!(section.text.text.chars().count() <= 3000),
+            _ => panic!("The arguments must be a section"),
+        }
+    }
+}
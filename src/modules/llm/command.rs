@@ -0,0 +1,720 @@
+use std::time::Duration;
+
+use super::{LlmOptions, LlmProvider};
+
+// Each compared answer is a message of its own
+const MAX_COMPARE_MODELS: usize = 4;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum CommandError {
+    #[error("Help requested")]
+    HelpRequested,
+    #[error("Unknown option `{0}`")]
+    UnknownOption(String),
+    #[error("Option `{0}` requires a value")]
+    MissingValue(String),
+    #[error("Invalid value `{value}` for `{option}`: {reason}")]
+    InvalidValue {
+        option: String,
+        value: String,
+        reason: String,
+    },
+    #[error("`{model}` is not a {provider} model")]
+    InvalidModel {
+        provider: &'static str,
+        model: String,
+    },
+    #[error("`{model}` does not support `{option}`")]
+    Unsupported { model: String, option: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Ask(LlmCommand),
+    Compare(CompareCommand),
+    Summarize(SummarizeCommand),
+    /// Looks for past messages about the query.
+    Search(String),
+    Prompt(PromptCommand),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LlmCommand {
+    pub provider: LlmProvider,
+    pub options: LlmOptions,
+    pub prompt: String,
+}
+
+/// The same prompt asked to several models, each with its own options.
+#[derive(Debug, PartialEq)]
+pub struct CompareCommand {
+    pub targets: Vec<(LlmProvider, LlmOptions)>,
+    pub prompt: String,
+}
+
+/// Summarizes the thread, or the channel history if a channel or a period is given.
+#[derive(Debug, PartialEq)]
+pub struct SummarizeCommand {
+    pub provider: LlmProvider,
+    pub options: LlmOptions,
+    pub channel: Option<String>,
+    pub period: Option<Duration>,
+}
+
+/// Asks a prompt of an MCP server, or lists the prompts if none is given.
+#[derive(Debug, PartialEq)]
+pub struct PromptCommand {
+    pub provider: LlmProvider,
+    pub options: LlmOptions,
+    /// The server and the name of the prompt.
+    pub prompt: Option<(String, String)>,
+    pub arguments: Vec<(String, String)>,
+}
+
+pub fn usage() -> String {
+    format!(
+        "```Usage: @ditto <gpt|gemini> [options] <prompt>
+       @ditto compare [--models <a,b>] [options] <prompt>
+       @ditto summarize [options] [#channel] [period, e.g. 24h or 7d]
+       @ditto search <question>
+       @ditto prompt [options] [<server>/<name> [argument=value ...]]
+
+Options:
+  --model <name>      Model to use (default: {chatgpt} / {gemini})
+  --models <a,b>      Models to compare (default: {chatgpt},{gemini})
+  --temp <0.0-2.0>    Sampling temperature
+  --max-tokens <n>    Maximum number of output tokens
+  --effort <level>    Reasoning effort: minimal, low, medium or high
+  --no-tools          Do not let the model call tools
+  --no-search         Do not let the model search the web
+  --code              Let the model run Python code
+  --resource <uri>    Attach an MCP resource, can be repeated
+  --help              Show this message```",
+        chatgpt = LlmProvider::ChatGpt.default_model(),
+        gemini = LlmProvider::Gemini.default_model(),
+    )
+}
+
+/// Splits the text into whitespace separated tokens, keeping the byte offset of each.
+fn tokenize(text: &str) -> Vec<(usize, &str)> {
+    let mut tokens = vec![];
+    let mut start = None;
+
+    for (i, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((s, &text[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+
+    if let Some(s) = start {
+        tokens.push((s, &text[s..]));
+    }
+
+    tokens
+}
+
+/// Returns `None` if the text does not mention the bot.
+pub fn parse(bot_id: &str, text: &str) -> Option<Result<Command, CommandError>> {
+    let slack_bot_format = format!("<@{}>", bot_id);
+
+    if !text.contains(&slack_bot_format) {
+        return None;
+    }
+
+    let command_str = text.replace(&slack_bot_format, "");
+    let tokens = tokenize(&command_str);
+
+    let (_, call_type) = tokens.first()?;
+
+    match *call_type {
+        "compare" => return Some(parse_compare(&command_str, &tokens[1..]).map(Command::Compare)),
+        "summarize" => {
+            return Some(parse_summarize(&command_str, &tokens[1..]).map(Command::Summarize))
+        }
+        "prompt" => return Some(parse_prompt(&command_str, &tokens[1..]).map(Command::Prompt)),
+        "search" => {
+            let query = tokens
+                .get(1)
+                .map_or("", |(offset, _)| command_str[*offset..].trim());
+
+            return Some(match query {
+                "" | "--help" => Err(CommandError::HelpRequested),
+                query => Ok(Command::Search(query.to_string())),
+            });
+        }
+        _ => {}
+    }
+
+    // `gpt0.7` and `gemini0.7` are kept as a shorthand for `--temp 0.7`
+    let (provider, legacy_temp, options_start) = if let Some(temp) = call_type.strip_prefix("gpt") {
+        (LlmProvider::ChatGpt, temp, 1)
+    } else if let Some(temp) = call_type.strip_prefix("gemini") {
+        (LlmProvider::Gemini, temp, 1)
+    } else {
+        // Anything else is asked to ChatGPT as is
+        (LlmProvider::ChatGpt, "", 0)
+    };
+
+    Some(
+        parse_ask(
+            provider,
+            legacy_temp,
+            &command_str,
+            &tokens[options_start..],
+        )
+        .map(Command::Ask),
+    )
+}
+
+fn parse_ask(
+    provider: LlmProvider,
+    legacy_temp: &str,
+    command_str: &str,
+    tokens: &[(usize, &str)],
+) -> Result<LlmCommand, CommandError> {
+    let (mut options, models, prompt) = parse_options(command_str, tokens)?;
+
+    if models.is_some() {
+        return Err(CommandError::UnknownOption("--models".to_string()));
+    }
+
+    if !legacy_temp.is_empty() && options.temperature.is_none() {
+        options.temperature = Some(parse_value("--temp", legacy_temp)?);
+    }
+
+    options.validate(provider)?;
+
+    Ok(LlmCommand {
+        provider,
+        options,
+        prompt,
+    })
+}
+
+fn parse_compare(
+    command_str: &str,
+    tokens: &[(usize, &str)],
+) -> Result<CompareCommand, CommandError> {
+    let (options, models, prompt) = parse_options(command_str, tokens)?;
+
+    if options.model.is_some() {
+        return Err(CommandError::UnknownOption("--model".to_string()));
+    }
+
+    let models = models.unwrap_or_else(|| {
+        vec![
+            LlmProvider::ChatGpt.default_model(),
+            LlmProvider::Gemini.default_model(),
+        ]
+    });
+
+    if models.is_empty() || models.len() > MAX_COMPARE_MODELS {
+        return Err(CommandError::InvalidValue {
+            option: "--models".to_string(),
+            value: models.join(","),
+            reason: format!("give 1 to {} models", MAX_COMPARE_MODELS),
+        });
+    }
+
+    let targets = models
+        .into_iter()
+        .map(|model| {
+            let provider =
+                LlmProvider::of_model(&model).ok_or_else(|| CommandError::InvalidModel {
+                    provider: "supported",
+                    model: model.clone(),
+                })?;
+
+            let options = LlmOptions {
+                model: Some(model),
+                ..options.clone()
+            };
+
+            options.validate(provider)?;
+
+            Ok((provider, options))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(CompareCommand { targets, prompt })
+}
+
+fn parse_summarize(
+    command_str: &str,
+    tokens: &[(usize, &str)],
+) -> Result<SummarizeCommand, CommandError> {
+    let (options, models, arguments) = parse_options(command_str, tokens)?;
+
+    if models.is_some() {
+        return Err(CommandError::UnknownOption("--models".to_string()));
+    }
+
+    let provider = match &options.model {
+        Some(model) => LlmProvider::of_model(model).ok_or_else(|| CommandError::InvalidModel {
+            provider: "supported",
+            model: model.clone(),
+        })?,
+        None => LlmProvider::ChatGpt,
+    };
+
+    options.validate(provider)?;
+
+    let mut channel = None;
+    let mut period = None;
+
+    for argument in arguments.split_whitespace() {
+        // Slack sends channel mentions as `<#C0123456|name>`
+        if let Some(mention) = argument.strip_prefix("<#") {
+            let id = mention
+                .trim_end_matches('>')
+                .split('|')
+                .next()
+                .unwrap_or("");
+            channel = Some(id.to_string());
+        } else if let Some(parsed) = parse_period(argument) {
+            period = Some(parsed);
+        } else {
+            return Err(CommandError::InvalidValue {
+                option: "summarize".to_string(),
+                value: argument.to_string(),
+                reason: "expected a #channel or a period like 24h".to_string(),
+            });
+        }
+    }
+
+    Ok(SummarizeCommand {
+        provider,
+        options,
+        channel,
+        period,
+    })
+}
+
+fn parse_prompt(
+    command_str: &str,
+    tokens: &[(usize, &str)],
+) -> Result<PromptCommand, CommandError> {
+    let (options, models, arguments) = parse_options(command_str, tokens)?;
+
+    if models.is_some() {
+        return Err(CommandError::UnknownOption("--models".to_string()));
+    }
+
+    let provider = match &options.model {
+        Some(model) => LlmProvider::of_model(model).ok_or_else(|| CommandError::InvalidModel {
+            provider: "supported",
+            model: model.clone(),
+        })?,
+        None => LlmProvider::ChatGpt,
+    };
+
+    options.validate(provider)?;
+
+    let (prompt, arguments) = match arguments.split_once(char::is_whitespace) {
+        Some((prompt, arguments)) => (prompt, arguments),
+        None => (arguments.as_str(), ""),
+    };
+
+    let prompt = match prompt {
+        "" => None,
+        prompt => match prompt.split_once('/') {
+            Some((server, name)) if !server.is_empty() && !name.is_empty() => {
+                Some((server.to_string(), name.to_string()))
+            }
+            _ => {
+                return Err(CommandError::InvalidValue {
+                    option: "prompt".to_string(),
+                    value: prompt.to_string(),
+                    reason: "expected <server>/<name>".to_string(),
+                })
+            }
+        },
+    };
+
+    Ok(PromptCommand {
+        provider,
+        options,
+        prompt,
+        arguments: parse_prompt_arguments(arguments)?,
+    })
+}
+
+/// Parses `name=value` pairs, where values with spaces are in double quotes.
+fn parse_prompt_arguments(text: &str) -> Result<Vec<(String, String)>, CommandError> {
+    let mut arguments = vec![];
+    let mut chars = text.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        if chars.peek().is_none() {
+            return Ok(arguments);
+        }
+
+        let mut name = String::new();
+        let mut value = String::new();
+
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            name.push(c);
+        }
+
+        if chars.next() != Some('=') || name.is_empty() {
+            return Err(CommandError::InvalidValue {
+                option: "prompt".to_string(),
+                value: name,
+                reason: "expected argument=value".to_string(),
+            });
+        }
+
+        // Slack turns straight quotes into curly ones
+        if chars.next_if(|c| matches!(c, '"' | '“')).is_some() {
+            for c in chars.by_ref() {
+                if matches!(c, '"' | '”') {
+                    break;
+                }
+
+                value.push(c);
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+
+        arguments.push((name, value));
+    }
+}
+
+/// Parses periods like `30m`, `24h`, `7d` or `2w`.
+pub fn parse_period(text: &str) -> Option<Duration> {
+    let unit = match text.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    let count = text[..text.len() - 1].parse::<u64>().ok()?;
+
+    (count > 0).then(|| Duration::from_secs(count * unit))
+}
+
+/// Reads the options in front of the prompt. Returns the options, the `--models` list and the prompt.
+fn parse_options(
+    command_str: &str,
+    tokens: &[(usize, &str)],
+) -> Result<(LlmOptions, Option<Vec<String>>, String), CommandError> {
+    let mut options = LlmOptions::default();
+    let mut models = None;
+
+    let mut prompt_start = command_str.len();
+    let mut iter = tokens.iter();
+
+    while let Some((offset, token)) = iter.next() {
+        if *token == "--" {
+            prompt_start = iter.next().map_or(command_str.len(), |(offset, _)| *offset);
+            break;
+        }
+
+        if !token.starts_with("--") {
+            prompt_start = *offset;
+            break;
+        }
+
+        let mut value_of = |option: &str| {
+            iter.next()
+                .map(|(_, value)| *value)
+                .ok_or_else(|| CommandError::MissingValue(option.to_string()))
+        };
+
+        match *token {
+            "--help" => return Err(CommandError::HelpRequested),
+            "--model" => options.model = Some(value_of(token)?.to_string()),
+            "--models" => {
+                models = Some(
+                    value_of(token)?
+                        .split(',')
+                        .filter(|model| !model.is_empty())
+                        .map(String::from)
+                        .collect(),
+                )
+            }
+            "--temp" | "--temperature" => {
+                options.temperature = Some(parse_value(token, value_of(token)?)?)
+            }
+            "--max-tokens" => options.max_tokens = Some(parse_value(token, value_of(token)?)?),
+            "--effort" => options.reasoning_effort = Some(parse_value(token, value_of(token)?)?),
+            "--no-tools" => options.no_tools = true,
+            "--no-search" => options.no_search = true,
+            "--code" => options.code_interpreter = true,
+            "--resource" => {
+                // Slack sends links as `<https://example.com|example.com>`
+                let uri = value_of(token)?
+                    .trim_start_matches('<')
+                    .trim_end_matches('>');
+                let uri = uri.split('|').next().unwrap_or(uri);
+
+                options.resources.push(uri.to_string())
+            }
+            etc => return Err(CommandError::UnknownOption(etc.to_string())),
+        }
+    }
+
+    Ok((
+        options,
+        models,
+        command_str[prompt_start..].trim().to_string(),
+    ))
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, CommandError>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse::<T>().map_err(|e| CommandError::InvalidValue {
+        option: option.to_string(),
+        value: value.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Replaces an LLM command with its prompt, so thread history only contains what was asked.
+pub fn strip_command(bot_id: &str, text: String) -> String {
+    match parse(bot_id, &text) {
+        Some(Ok(Command::Ask(command))) => command.prompt,
+        Some(Ok(Command::Compare(command))) => command.prompt,
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::modules::llm::ReasoningEffort;
+
+    fn parse_ok(text: &str) -> LlmCommand {
+        match parse("BOT", text).unwrap().unwrap() {
+            Command::Ask(command) => command,
+            etc => panic!("Unexpected command {:?}", etc),
+        }
+    }
+
+    fn parse_compare_ok(text: &str) -> CompareCommand {
+        match parse("BOT", text).unwrap().unwrap() {
+            Command::Compare(command) => command,
+            etc => panic!("Unexpected command {:?}", etc),
+        }
+    }
+
+    fn parse_err(text: &str) -> CommandError {
+        parse("BOT", text).unwrap().unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert!(parse("BOT", "hello").is_none());
+
+        let command = parse_ok(
+            "<@BOT> gpt --model gpt-4.1 --temp 0.2 --max-tokens 800 --no-tools --no-search what is\nrust?",
+        );
+
+        assert_eq!(command.provider, LlmProvider::ChatGpt);
+        assert_eq!(command.options.model.as_deref(), Some("gpt-4.1"));
+        assert_eq!(command.options.temperature, Some(0.2));
+        assert_eq!(command.options.max_tokens, Some(800));
+        assert!(command.options.no_tools);
+        assert!(command.options.no_search);
+        assert_eq!(command.prompt, "what is\nrust?");
+
+        let command = parse_ok("<@BOT> gpt --model o3 --effort high hi");
+
+        assert_eq!(
+            command.options.reasoning_effort,
+            Some(ReasoningEffort::High)
+        );
+
+        let command = parse_ok("<@BOT> gemini0.7 -- --not an option");
+
+        assert_eq!(command.provider, LlmProvider::Gemini);
+        assert_eq!(command.options.temperature, Some(0.7));
+        assert_eq!(command.prompt, "--not an option");
+
+        let command = parse_ok("<@BOT> how are you?");
+
+        assert_eq!(command.provider, LlmProvider::ChatGpt);
+        assert_eq!(command.options, LlmOptions::default());
+        assert_eq!(command.prompt, "how are you?");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_err("<@BOT> gpt --help"), CommandError::HelpRequested);
+        assert_eq!(
+            parse_err("<@BOT> gpt --verbose hi"),
+            CommandError::UnknownOption("--verbose".to_string())
+        );
+        assert_eq!(
+            parse_err("<@BOT> gpt --temp"),
+            CommandError::MissingValue("--temp".to_string())
+        );
+        assert!(matches!(
+            parse_err("<@BOT> gpt --max-tokens many hi"),
+            CommandError::InvalidValue { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> gpt --temp 3 hi"),
+            CommandError::InvalidValue { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> gemini --model gpt-4o hi"),
+            CommandError::InvalidModel { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> gpt --model o3 --temp 0.2 hi"),
+            CommandError::Unsupported { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> gpt --model gpt-4o --effort high hi"),
+            CommandError::Unsupported { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> gemini --code hi"),
+            CommandError::Unsupported { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> gpt --model o3 --effort extreme hi"),
+            CommandError::InvalidValue { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_compare() {
+        let command =
+            parse_compare_ok("<@BOT> compare --models gpt-4.1,gemini-2.5-pro --no-tools hi");
+
+        assert_eq!(command.prompt, "hi");
+        assert_eq!(command.targets.len(), 2);
+        assert_eq!(command.targets[0].0, LlmProvider::ChatGpt);
+        assert_eq!(command.targets[0].1.model.as_deref(), Some("gpt-4.1"));
+        assert_eq!(command.targets[1].0, LlmProvider::Gemini);
+        assert!(command.targets[1].1.no_tools);
+
+        let command = parse_compare_ok("<@BOT> compare hi");
+
+        assert_eq!(command.targets[0].0, LlmProvider::ChatGpt);
+        assert_eq!(command.targets[1].0, LlmProvider::Gemini);
+
+        assert!(matches!(
+            parse_err("<@BOT> compare --models gpt-4o,claude hi"),
+            CommandError::InvalidModel { .. }
+        ));
+        assert!(matches!(
+            parse_err("<@BOT> compare --models gpt-4o,o3 --temp 0.2 hi"),
+            CommandError::Unsupported { .. }
+        ));
+        assert_eq!(
+            parse_err("<@BOT> gpt --models gpt-4o hi"),
+            CommandError::UnknownOption("--models".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_search() {
+        assert_eq!(
+            parse("BOT", "<@BOT> search  when is the deploy? "),
+            Some(Ok(Command::Search("when is the deploy?".to_string())))
+        );
+        assert_eq!(
+            parse("BOT", "<@BOT> search"),
+            Some(Err(CommandError::HelpRequested))
+        );
+    }
+
+    #[test]
+    fn test_parse_prompt() {
+        let command = match parse(
+            "BOT",
+            "<@BOT> prompt --resource <https://example.com/a|example.com/a> everything/complex_prompt temperature=0.5 style=\"very short\"",
+        )
+        .unwrap()
+        {
+            Ok(Command::Prompt(command)) => command,
+            etc => panic!("Unexpected command {:?}", etc),
+        };
+
+        assert_eq!(command.options.resources, vec!["https://example.com/a"]);
+        assert_eq!(
+            command.prompt,
+            Some(("everything".to_string(), "complex_prompt".to_string()))
+        );
+        assert_eq!(
+            command.arguments,
+            vec![
+                ("temperature".to_string(), "0.5".to_string()),
+                ("style".to_string(), "very short".to_string()),
+            ]
+        );
+
+        assert!(matches!(
+            parse("BOT", "<@BOT> prompt"),
+            Some(Ok(Command::Prompt(PromptCommand { prompt: None, .. })))
+        ));
+        assert!(matches!(
+            parse("BOT", "<@BOT> prompt everything"),
+            Some(Err(CommandError::InvalidValue { .. }))
+        ));
+        assert!(matches!(
+            parse("BOT", "<@BOT> prompt everything/simple_prompt oops"),
+            Some(Err(CommandError::InvalidValue { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_parse_summarize() {
+        let command = match parse("BOT", "<@BOT> summarize <#C123|general> 24h").unwrap() {
+            Ok(Command::Summarize(command)) => command,
+            etc => panic!("Unexpected command {:?}", etc),
+        };
+
+        assert_eq!(command.provider, LlmProvider::ChatGpt);
+        assert_eq!(command.channel.as_deref(), Some("C123"));
+        assert_eq!(command.period, Some(Duration::from_secs(24 * 60 * 60)));
+
+        let command = match parse("BOT", "<@BOT> summarize --model gemini-2.5-pro").unwrap() {
+            Ok(Command::Summarize(command)) => command,
+            etc => panic!("Unexpected command {:?}", etc),
+        };
+
+        assert_eq!(command.provider, LlmProvider::Gemini);
+        assert_eq!((command.channel, command.period), (None, None));
+
+        assert!(matches!(
+            parse_err("<@BOT> summarize yesterday"),
+            CommandError::InvalidValue { .. }
+        ));
+        assert_eq!(parse_period("0h"), None);
+        assert_eq!(
+            parse_period("7d"),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_strip_command() {
+        assert_eq!(
+            strip_command("BOT", "<@BOT> gpt --temp 0.2 hello".to_string()),
+            "hello"
+        );
+        assert_eq!(strip_command("BOT", "just text".to_string()), "just text");
+        assert_eq!(
+            strip_command("BOT", "<@BOT> compare --models gpt-4o hello".to_string()),
+            "hello"
+        );
+    }
+}
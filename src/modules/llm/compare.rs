@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use futures::future::join_all;
+use log::info;
+
+use crate::{
+    slack::{BlockElement, SectionBlock},
+    Bot, Message, ReplyMessageEvent,
+};
+
+use super::{
+    answer, answer_blocks, name_text, AnswerControls, LlmOptions, LlmProvider, LlmRequest,
+    TokenUsage,
+};
+
+/// How one of the compared models did.
+struct CompareResult {
+    provider: LlmProvider,
+    model: String,
+    latency: Duration,
+    result: Result<Option<TokenUsage>, String>,
+}
+
+impl CompareResult {
+    fn summary_line(&self) -> String {
+        let name = name_text(self.provider, &self.model);
+        let latency = self.latency.as_secs_f32();
+
+        match &self.result {
+            Ok(Some(usage)) => format!(
+                "{}: {:.2}s, {} input / {} output tokens",
+                name, latency, usage.input_tokens, usage.output_tokens
+            ),
+            Ok(None) => format!("{}: {:.2}s, token usage unknown", name, latency),
+            Err(e) => format!("{}: failed after {:.2}s - {}", name, latency, e),
+        }
+    }
+}
+
+fn summary_text(results: &[CompareResult]) -> String {
+    let lines = results
+        .iter()
+        .map(CompareResult::summary_line)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!("*Comparison*\n{}", lines)
+}
+
+/// Asks every target at once, each answering in its own message, then posts how they did.
+pub async fn run<B: Bot>(
+    bot: &B,
+    request: LlmRequest,
+    targets: Vec<(LlmProvider, LlmOptions)>,
+) -> anyhow::Result<()> {
+    let reply_event = Some(ReplyMessageEvent {
+        msg: request.thread_ts.clone(),
+        broadcast: false,
+    });
+
+    let mut requests = vec![];
+
+    // Posted one by one, so the answers keep the order of `--models`
+    for (provider, options) in targets {
+        let model = options.model(provider);
+        let blocks = answer_blocks(
+            provider,
+            &model,
+            "`Waiting...`",
+            None,
+            &[],
+            &[],
+            AnswerControls::Stop,
+        );
+
+        let sent = bot
+            .send_message(
+                &request.channel,
+                Message::Blocks(&blocks),
+                reply_event.clone(),
+                None,
+            )
+            .await?;
+
+        let ts =
+            sent.ts.as_ref().map(String::from).ok_or_else(|| {
+                anyhow!("Failed to post the answer of {} - {:?}", model, sent.error)
+            })?;
+
+        requests.push((
+            provider,
+            LlmRequest {
+                options,
+                answer_ts: Some(ts),
+                ..request.clone()
+            },
+        ));
+    }
+
+    let results = join_all(requests.iter().map(|(provider, request)| async move {
+        let started = Instant::now();
+        let result = answer(bot, *provider, request).await;
+        let latency = started.elapsed();
+
+        let usage = request
+            .answer_ts
+            .as_deref()
+            .and_then(|ts| bot.answers().get(&request.channel, ts))
+            .and_then(|history| history.versions.last()?.usage);
+
+        CompareResult {
+            provider: *provider,
+            model: request.options.model(*provider),
+            latency,
+            result: result.map(|_| usage).map_err(|e| e.to_string()),
+        }
+    }))
+    .await;
+
+    for result in &results {
+        info!("Compare in {} - {}", request.channel, result.summary_line());
+    }
+
+    bot.send_message(
+        &request.channel,
+        Message::Blocks(&[BlockElement::Section(SectionBlock::new_markdown(
+            &summary_text(&results),
+        ))]),
+        reply_event,
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_summary_text() {
+    let results = [
+        CompareResult {
+            provider: LlmProvider::ChatGpt,
+            model: "gpt-4o".to_string(),
+            latency: Duration::from_millis(1500),
+            result: Ok(Some(TokenUsage {
+                input_tokens: 120,
+                output_tokens: 450,
+            })),
+        },
+        CompareResult {
+            provider: LlmProvider::Gemini,
+            model: "gemini-2.5-pro".to_string(),
+            latency: Duration::from_millis(250),
+            result: Err("Timed out".to_string()),
+        },
+    ];
+
+    assert_eq!(
+        summary_text(&results),
+        "*Comparison*\n\
+         `ChatGPT` (gpt-4o): 1.50s, 120 input / 450 output tokens\n\
+         `Gemini` (gemini-2.5-pro): failed after 0.25s - Timed out"
+    );
+}
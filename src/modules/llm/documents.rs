@@ -0,0 +1,443 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context as _};
+use log::{info, warn};
+use tokio::{io::AsyncWriteExt, process::Command, sync::OnceCell};
+
+use crate::{
+    modules::search::{embedding::Embedder, store::cosine_similarity, EMBED_BATCH_SIZE},
+    slack::{HistoryMessage, SlackFile},
+    Bot,
+};
+
+use super::{summarize::fetch_messages, LlmRequest};
+
+// Documents are given whole while they fit in this, else only their relevant parts
+const MAX_INLINE_CHARS: usize = 30_000;
+const CHUNK_CHARS: usize = 2_000;
+const MAX_RETRIEVED_CHUNKS: usize = 12;
+// Only the beginning of longer documents is searched, to bound the embedding requests
+const MAX_EMBEDDED_CHUNKS: usize = 500;
+const MAX_FILE_SIZE: u64 = 20 * 1024 * 1024;
+// The cache is emptied once it holds this many documents
+const MAX_CACHED_DOCUMENTS: usize = 100;
+
+const TEXT_FILETYPES: &[&str] = &[
+    "text",
+    "markdown",
+    "csv",
+    "tsv",
+    "json",
+    "yaml",
+    "toml",
+    "xml",
+    "html",
+    "css",
+    "rust",
+    "python",
+    "javascript",
+    "typescript",
+    "go",
+    "java",
+    "kotlin",
+    "swift",
+    "c",
+    "cpp",
+    "csharp",
+    "ruby",
+    "php",
+    "shell",
+    "sql",
+    "lua",
+    "scala",
+    "haskell",
+    "diff",
+];
+
+/// The text of a shared file, cut into chunks.
+pub struct Document {
+    name: String,
+    chunks: Vec<String>,
+    // Only embedded when the documents of a thread are too long to give whole
+    vectors: OnceCell<Vec<Vec<f32>>>,
+}
+
+impl Document {
+    fn new(name: String, text: &str) -> Self {
+        Self {
+            name,
+            chunks: chunk(text, CHUNK_CHARS),
+            vectors: OnceCell::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.chars().count()).sum()
+    }
+}
+
+/// Extracted documents, keyed by Slack file id, so follow-up questions don't download them again.
+#[derive(Default)]
+pub struct DocumentCache {
+    documents: Mutex<HashMap<String, Arc<Document>>>,
+}
+
+impl DocumentCache {
+    fn get(&self, file_id: &str) -> Option<Arc<Document>> {
+        self.documents.lock().unwrap().get(file_id).cloned()
+    }
+
+    fn insert(&self, file_id: &str, document: Arc<Document>) {
+        let mut documents = self.documents.lock().unwrap();
+
+        if documents.len() >= MAX_CACHED_DOCUMENTS {
+            documents.clear();
+        }
+
+        documents.insert(file_id.to_string(), document);
+    }
+}
+
+enum FileKind {
+    Pdf,
+    Text,
+}
+
+fn file_kind(file: &SlackFile) -> Option<FileKind> {
+    if file.filetype == "pdf" || file.mimetype == "application/pdf" {
+        Some(FileKind::Pdf)
+    } else if file.mimetype.starts_with("text/") || TEXT_FILETYPES.contains(&file.filetype.as_str())
+    {
+        Some(FileKind::Text)
+    } else {
+        None
+    }
+}
+
+/// Runs `pdftotext` of poppler-utils over the file.
+async fn pdf_to_text(content: Vec<u8>) -> anyhow::Result<String> {
+    let mut child = Command::new("pdftotext")
+        .args(["-layout", "-enc", "UTF-8", "-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run pdftotext")?;
+
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?;
+    // Written concurrently, as pdftotext may fill its stdout before reading everything
+    let writer = tokio::spawn(async move { stdin.write_all(&content).await });
+
+    let output = child.wait_with_output().await?;
+    writer.await??;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "pdftotext failed - {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn extract<B: Bot>(bot: &B, file: &SlackFile, kind: FileKind) -> anyhow::Result<String> {
+    if file.size > MAX_FILE_SIZE {
+        return Err(anyhow!("The file is larger than {} bytes", MAX_FILE_SIZE));
+    }
+
+    let url = file
+        .url_private_download
+        .as_deref()
+        .ok_or_else(|| anyhow!("The file can't be downloaded"))?;
+
+    let content = bot.download_file(url).await?;
+
+    match kind {
+        FileKind::Pdf => pdf_to_text(content).await,
+        FileKind::Text => Ok(String::from_utf8_lossy(&content).into_owned()),
+    }
+}
+
+/// Cuts the text into chunks of about `size` characters, at line ends where possible.
+fn chunk(text: &str, size: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+
+    for line in text.lines() {
+        let mut line = line.trim_end();
+
+        loop {
+            let line_len = line.chars().count();
+
+            if current_len + line_len < size {
+                current.push_str(line);
+                current.push('\n');
+                current_len += line_len + 1;
+                break;
+            }
+
+            if current_len > 0 {
+                chunks.push(std::mem::take(&mut current));
+                current_len = 0;
+                continue;
+            }
+
+            // A single line longer than a chunk
+            let end = line
+                .char_indices()
+                .nth(size)
+                .map_or(line.len(), |(end, _)| end);
+            chunks.push(line[..end].to_string());
+            line = &line[end..];
+
+            if line.is_empty() {
+                break;
+            }
+        }
+    }
+
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks
+}
+
+/// Reads the file from the cache, or downloads and extracts it.
+async fn load<B: Bot>(bot: &B, file: &SlackFile) -> Option<Result<Arc<Document>, String>> {
+    let kind = file_kind(file)?;
+
+    if let Some(document) = bot.documents().get(&file.id) {
+        return Some(Ok(document));
+    }
+
+    match extract(bot, file, kind).await {
+        Ok(text) => {
+            let document = Arc::new(Document::new(file.name.clone(), &text));
+
+            info!(
+                "Extracted {} characters from {} ({})",
+                document.len(),
+                file.name,
+                file.id
+            );
+
+            bot.documents().insert(&file.id, document.clone());
+
+            Some(Ok(document))
+        }
+        Err(e) => {
+            warn!("Failed to read the file {} - {:?}", file.name, e);
+            Some(Err(format!("{:#}", e)))
+        }
+    }
+}
+
+/// Embeds the first `MAX_EMBEDDED_CHUNKS` chunks of the document, `EMBED_BATCH_SIZE` at a time.
+async fn embed_chunks(
+    embedder: &dyn Embedder,
+    document: &Document,
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    let chunks = &document.chunks[..document.chunks.len().min(MAX_EMBEDDED_CHUNKS)];
+    let mut vectors = vec![];
+
+    for batch in chunks.chunks(EMBED_BATCH_SIZE) {
+        vectors.extend(embedder.embed(batch).await?);
+    }
+
+    Ok(vectors)
+}
+
+/// The chunks most relevant to the question, as (document index, chunk index), in reading order.
+async fn retrieve<B: Bot>(
+    bot: &B,
+    documents: &[Arc<Document>],
+    question: &str,
+) -> anyhow::Result<Vec<(usize, usize)>> {
+    let embedder = bot.search_index().embedder();
+
+    let question = embedder
+        .embed(&[question.to_string()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+
+    let mut scored = vec![];
+
+    for (i, document) in documents.iter().enumerate() {
+        let vectors = document
+            .vectors
+            .get_or_try_init(|| embed_chunks(embedder, document))
+            .await?;
+
+        scored.extend(
+            vectors
+                .iter()
+                .enumerate()
+                .map(|(j, vector)| (cosine_similarity(&question, vector), (i, j))),
+        );
+    }
+
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    let mut chunks = scored
+        .into_iter()
+        .take(MAX_RETRIEVED_CHUNKS)
+        .map(|(_, chunk)| chunk)
+        .collect::<Vec<_>>();
+    chunks.sort();
+
+    Ok(chunks)
+}
+
+/// The documents whole, or cut to their first `max_chars` characters.
+fn inline(documents: &[Arc<Document>], max_chars: usize) -> String {
+    let mut text = String::new();
+
+    for document in documents {
+        text += &format!(
+            "\n<document name=\"{}\">\n{}</document>\n",
+            document.name,
+            document
+                .chunks
+                .concat()
+                .chars()
+                .take(max_chars)
+                .collect::<String>()
+        );
+    }
+
+    text
+}
+
+/// The documents shared in the thread up to the request, to put in front of the conversation.
+/// Long ones are cut down to the parts relevant to the question.
+pub async fn context<B: Bot>(bot: &B, request: &LlmRequest) -> anyhow::Result<Option<String>> {
+    let messages = fetch_messages(bot, &request.channel, Some(&request.thread_ts), None).await?;
+
+    let files = messages
+        .iter()
+        .filter(|message| {
+            let (ts, request_ts) = (message.ts.parse::<f64>(), request.ts.parse::<f64>());
+            matches!((ts, request_ts), (Ok(ts), Ok(request_ts)) if ts <= request_ts)
+        })
+        .flat_map(|message: &HistoryMessage| message.files.iter())
+        .collect::<Vec<_>>();
+
+    let mut documents = vec![];
+    let mut failures = vec![];
+
+    for file in files {
+        match load(bot, file).await {
+            Some(Ok(document)) => documents.push(document),
+            Some(Err(e)) => failures.push(format!("{}: {}", file.name, e)),
+            None => {}
+        }
+    }
+
+    if documents.is_empty() && failures.is_empty() {
+        return Ok(None);
+    }
+
+    let total_len = documents
+        .iter()
+        .map(|document| document.len())
+        .sum::<usize>();
+
+    let mut text = String::new();
+
+    if total_len <= MAX_INLINE_CHARS {
+        text += "These documents were shared in the thread.\n";
+        text += &inline(&documents, MAX_INLINE_CHARS);
+    } else {
+        match retrieve(bot, &documents, &request.input_text).await {
+            Ok(chunks) => {
+                text += "These are the parts of the documents shared in the thread \
+                         which are the most relevant to the question.\n";
+
+                for (i, j) in chunks {
+                    let document = &documents[i];
+
+                    text += &format!(
+                        "\n<document name=\"{}\" part=\"{}/{}\">\n{}</document>\n",
+                        document.name,
+                        j + 1,
+                        document.chunks.len(),
+                        document.chunks[j]
+                    );
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to find the relevant parts of the documents of {} - {:?}",
+                    request.thread_ts, e
+                );
+
+                text += "These are the beginnings of the documents shared in the thread, \
+                         which are too long to give whole.\n";
+                text += &inline(&documents, MAX_INLINE_CHARS / documents.len());
+            }
+        }
+    }
+
+    for failure in failures {
+        text += &format!("\nThis document could not be read - {}\n", failure);
+    }
+
+    Ok(Some(text))
+}
+
+#[test]
+fn test_chunk() {
+    assert_eq!(chunk("", 10), Vec::<String>::new());
+    assert_eq!(chunk("ab\ncd\n\nef", 10), vec!["ab\ncd\n\nef\n"]);
+    assert_eq!(chunk("abcd\nefgh\nij", 8), vec!["abcd\n", "efgh\nij\n"]);
+    // Long lines are split anywhere, counting characters
+    assert_eq!(chunk("가나다라마바", 4), vec!["가나다라", "마바\n"]);
+}
+
+#[tokio::test]
+async fn test_embed_chunks() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingEmbedder {
+        requests: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Embedder for CountingEmbedder {
+        fn name(&self) -> String {
+            "counting".to_string()
+        }
+
+        async fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+            assert!(texts.len() <= EMBED_BATCH_SIZE);
+            self.requests.fetch_add(1, Ordering::SeqCst);
+
+            Ok(texts.iter().map(|_| vec![1.0]).collect())
+        }
+    }
+
+    let embedder = CountingEmbedder::default();
+    let document = Document {
+        name: "long.txt".to_string(),
+        chunks: vec!["line".to_string(); MAX_EMBEDDED_CHUNKS + 10],
+        vectors: OnceCell::new(),
+    };
+
+    let vectors = embed_chunks(&embedder, &document).await.unwrap();
+
+    assert_eq!(vectors.len(), MAX_EMBEDDED_CHUNKS);
+    assert_eq!(
+        embedder.requests.load(Ordering::SeqCst),
+        MAX_EMBEDDED_CHUNKS.div_ceil(EMBED_BATCH_SIZE)
+    );
+}
@@ -0,0 +1,1319 @@
+use std::{
+    collections::HashMap,
+    env,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    config::ChannelConfig,
+    generation::stop_button_block,
+    slack::{
+        ActionBlock, BlockElement, ButtonBlock, ButtonStyle, ContextBlock, SectionBlock,
+        ThreadMessageType,
+    },
+    Bot, Message, ReplyMessageEvent,
+};
+
+use super::{chatgpt, gemini, search};
+
+pub mod approval;
+pub mod command;
+pub mod compare;
+pub mod documents;
+pub mod prompts;
+pub mod summarize;
+pub mod throttle;
+pub mod tools;
+
+pub const REGENERATE_ACTION_ID: &str = "answer_regenerate";
+pub const OTHER_MODEL_ACTION_ID: &str = "answer_other_model";
+pub const PREV_VERSION_ACTION_ID: &str = "answer_prev_version";
+pub const NEXT_VERSION_ACTION_ID: &str = "answer_next_version";
+const VERSION_LABEL_ACTION_ID: &str = "answer_version_label";
+
+// Slack allows 50 blocks per message
+const MAX_TOOL_CALL_BLOCKS: usize = 10;
+const TOOL_ARGUMENTS_PREVIEW_LEN: usize = 150;
+const TOOL_RESULT_PREVIEW_LEN: usize = 300;
+// Only the tail of a long reasoning summary is shown while thinking
+const THINKING_PREVIEW_LEN: usize = 1500;
+
+// A model calling tools this many times in a row has to answer with what it has
+pub const MAX_TOOL_ROUNDS: usize = 10;
+pub const TOOL_ROUNDS_NOTICE: &str = "Stopped: too many tool calls";
+
+// Past these, the oldest answers and versions are forgotten
+const MAX_STORED_ANSWERS: usize = 500;
+const MAX_ANSWER_VERSIONS: usize = 10;
+
+// Used when regenerating an answer which was made deterministically
+const REGENERATE_TEMPERATURE: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmProvider {
+    ChatGpt,
+    Gemini,
+}
+
+impl LlmProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LlmProvider::ChatGpt => "ChatGPT",
+            LlmProvider::Gemini => "Gemini",
+        }
+    }
+
+    pub fn other(&self) -> Self {
+        match self {
+            LlmProvider::ChatGpt => LlmProvider::Gemini,
+            LlmProvider::Gemini => LlmProvider::ChatGpt,
+        }
+    }
+
+    pub fn default_model(&self) -> String {
+        match self {
+            LlmProvider::ChatGpt => env::var("OPENAI_MODEL").unwrap_or("gpt-4o-mini".to_string()),
+            LlmProvider::Gemini => env::var("GEMINI_MODEL").unwrap_or("gemini-pro".to_string()),
+        }
+    }
+
+    /// The provider serving the model, if any.
+    pub fn of_model(model: &str) -> Option<Self> {
+        [LlmProvider::ChatGpt, LlmProvider::Gemini]
+            .iter()
+            .copied()
+            .find(|provider| provider.capabilities(model).is_some())
+    }
+
+    pub fn capabilities(&self, model: &str) -> Option<ModelCapabilities> {
+        match self {
+            LlmProvider::ChatGpt => {
+                let is_o_series =
+                    model.starts_with('o') && model[1..].starts_with(|c: char| c.is_ascii_digit());
+                let is_reasoning = is_o_series || model.starts_with("gpt-5");
+
+                if !is_o_series && !model.starts_with("gpt-") && !model.starts_with("chatgpt-") {
+                    return None;
+                }
+
+                Some(ModelCapabilities {
+                    temperature: !is_reasoning,
+                    web_search: !is_o_series,
+                    code_interpreter: true,
+                    reasoning: is_reasoning,
+                    min_output_tokens: 16,
+                })
+            }
+            LlmProvider::Gemini => {
+                if !model.starts_with("gemini-") && !model.starts_with("gemma-") {
+                    return None;
+                }
+
+                Some(ModelCapabilities {
+                    temperature: true,
+                    web_search: false,
+                    code_interpreter: false,
+                    reasoning: false,
+                    min_output_tokens: 1,
+                })
+            }
+        }
+    }
+}
+
+pub struct ModelCapabilities {
+    pub temperature: bool,
+    pub web_search: bool,
+    pub code_interpreter: bool,
+    pub reasoning: bool,
+    pub min_output_tokens: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningEffort {
+    Minimal,
+    Low,
+    Medium,
+    High,
+}
+
+impl FromStr for ReasoningEffort {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "minimal" => Ok(ReasoningEffort::Minimal),
+            "low" => Ok(ReasoningEffort::Low),
+            "medium" => Ok(ReasoningEffort::Medium),
+            "high" => Ok(ReasoningEffort::High),
+            _ => Err("must be one of minimal, low, medium or high".to_string()),
+        }
+    }
+}
+
+/// Options given with a command, shared by every provider.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LlmOptions {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub reasoning_effort: Option<ReasoningEffort>,
+    pub no_tools: bool,
+    pub no_search: bool,
+    pub code_interpreter: bool,
+    /// URIs of MCP resources to put in front of the conversation.
+    pub resources: Vec<String>,
+}
+
+impl LlmOptions {
+    pub fn model(&self, provider: LlmProvider) -> String {
+        self.model
+            .clone()
+            .unwrap_or_else(|| provider.default_model())
+    }
+
+    pub fn validate(&self, provider: LlmProvider) -> Result<(), command::CommandError> {
+        let model = self.model(provider);
+
+        let capabilities =
+            provider
+                .capabilities(&model)
+                .ok_or_else(|| command::CommandError::InvalidModel {
+                    provider: provider.name(),
+                    model: model.clone(),
+                })?;
+
+        if let Some(temperature) = self.temperature {
+            if !capabilities.temperature {
+                return Err(command::CommandError::Unsupported {
+                    model,
+                    option: "--temp".to_string(),
+                });
+            }
+
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(command::CommandError::InvalidValue {
+                    option: "--temp".to_string(),
+                    value: temperature.to_string(),
+                    reason: "must be between 0.0 and 2.0".to_string(),
+                });
+            }
+        }
+
+        if self.code_interpreter && !capabilities.code_interpreter {
+            return Err(command::CommandError::Unsupported {
+                model,
+                option: "--code".to_string(),
+            });
+        }
+
+        if self.reasoning_effort.is_some() && !capabilities.reasoning {
+            return Err(command::CommandError::Unsupported {
+                model,
+                option: "--effort".to_string(),
+            });
+        }
+
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens < capabilities.min_output_tokens {
+                return Err(command::CommandError::InvalidValue {
+                    option: "--max-tokens".to_string(),
+                    value: max_tokens.to_string(),
+                    reason: format!("must be at least {}", capabilities.min_output_tokens),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn temperature(&self, provider: LlmProvider) -> Option<f32> {
+        let capabilities = provider.capabilities(&self.model(provider))?;
+
+        if capabilities.temperature {
+            Some(self.temperature.unwrap_or(0.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn reasoning(&self, provider: LlmProvider) -> bool {
+        provider
+            .capabilities(&self.model(provider))
+            .is_some_and(|capabilities| capabilities.reasoning)
+    }
+
+    /// Either asked with `--code` or turned on for the whole channel.
+    pub fn code_interpreter(&self, provider: LlmProvider, channel: Option<&ChannelConfig>) -> bool {
+        let enabled =
+            self.code_interpreter || channel.is_some_and(|channel| channel.code_interpreter);
+
+        enabled
+            && provider
+                .capabilities(&self.model(provider))
+                .is_some_and(|capabilities| capabilities.code_interpreter)
+    }
+
+    pub fn web_search(&self, provider: LlmProvider) -> bool {
+        let capabilities = provider.capabilities(&self.model(provider));
+
+        !self.no_search && capabilities.is_some_and(|capabilities| capabilities.web_search)
+    }
+}
+
+/// Everything needed to (re)build an answer for a request message.
+#[derive(Debug, Clone)]
+pub struct LlmRequest {
+    pub channel: String,
+    pub requester: String,
+    pub ts: String,
+    pub thread_ts: String,
+    pub input_text: String,
+    pub options: LlmOptions,
+    // If given, the answer is written over this message instead of a new one
+    pub answer_ts: Option<String>,
+}
+
+impl LlmRequest {
+    pub fn reply_event(&self) -> Option<ReplyMessageEvent> {
+        Some(ReplyMessageEvent {
+            msg: self.thread_ts.clone(),
+            broadcast: true,
+        })
+    }
+
+    /// Messages posted after the request are not part of its context.
+    pub fn is_in_context(&self, message: &ThreadMessageType) -> bool {
+        let ts = match message.ts() {
+            Some(ts) => ts,
+            None => return true,
+        };
+
+        match (ts.parse::<f64>(), self.ts.parse::<f64>()) {
+            (Ok(ts), Ok(request_ts)) => ts <= request_ts,
+            _ => true,
+        }
+    }
+}
+
+/// A tool call made while generating an answer, shown under the answer.
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: String,
+    // None if the tool was not run, or was run by the LLM provider
+    pub duration: Option<Duration>,
+    pub result: Result<String, String>,
+}
+
+impl ToolCall {
+    /// What the model gets back as the tool output.
+    pub fn output(&self) -> String {
+        match &self.result {
+            Ok(output) => output.clone(),
+            Err(error) => error.clone(),
+        }
+    }
+
+    fn block(&self) -> BlockElement {
+        let mut text = format!(
+            "{} `{}` `{}`",
+            if self.result.is_ok() {
+                ":hammer_and_wrench:"
+            } else {
+                ":warning:"
+            },
+            self.name,
+            preview(&self.arguments, TOOL_ARGUMENTS_PREVIEW_LEN)
+        );
+
+        if let Some(duration) = self.duration {
+            text += &format!(" ({:.2}s)", duration.as_secs_f32());
+        }
+
+        text += &format!("\n> {}", preview(&self.output(), TOOL_RESULT_PREVIEW_LEN));
+
+        BlockElement::Context(ContextBlock::new_markdown(&text))
+    }
+}
+
+/// Shortens a text to a single line which is safe to put inside a code span.
+pub fn preview(text: &str, max_len: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let text = text.replace('`', "'");
+
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+fn tools_footer_block(tool_calls: &[ToolCall]) -> BlockElement {
+    let mut counts: Vec<(&str, usize)> = vec![];
+
+    for tool_call in tool_calls {
+        match counts.iter_mut().find(|(name, _)| *name == tool_call.name) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((&tool_call.name, 1)),
+        }
+    }
+
+    let tools = counts
+        .into_iter()
+        .map(|(name, count)| match count {
+            1 => format!("`{}`", name),
+            count => format!("`{}` ×{}", name, count),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    BlockElement::Context(ContextBlock::new_markdown(&format!(
+        "Tools used: {}",
+        tools
+    )))
+}
+
+/// The reasoning summary shown above the answer.
+#[derive(Debug, Clone, Copy)]
+pub enum Thinking<'a> {
+    InProgress(&'a str),
+    // Condensed once the answer text arrives
+    Done(Duration),
+}
+
+impl Thinking<'_> {
+    fn block(&self) -> BlockElement {
+        let text = match self {
+            Thinking::InProgress("") => ":thought_balloon: _Thinking…_".to_string(),
+            Thinking::InProgress(summary) => {
+                // Summaries come in Markdown, Slack only knows single asterisks
+                let summary = summary.replace("**", "*");
+
+                let tail = match summary.char_indices().rev().nth(THINKING_PREVIEW_LEN) {
+                    Some((start, _)) => format!("…{}", &summary[start..]),
+                    None => summary,
+                };
+
+                format!(":thought_balloon: _Thinking…_\n{}", tail)
+            }
+            Thinking::Done(duration) => format!(
+                ":thought_balloon: Thought for {}s",
+                duration.as_secs().max(1)
+            ),
+        };
+
+        BlockElement::Context(ContextBlock::new_markdown(&text))
+    }
+}
+
+/// Reasoning summary of an answer which is being streamed.
+#[derive(Default)]
+pub struct ThinkingState {
+    summary: String,
+    started: Option<Instant>,
+    thought_for: Option<Duration>,
+}
+
+impl ThinkingState {
+    pub fn push(&mut self, delta: &str) {
+        self.started.get_or_insert_with(Instant::now);
+        // Models think again after tool calls
+        self.thought_for = None;
+        self.summary += delta;
+    }
+
+    pub fn new_part(&mut self) {
+        if !self.summary.is_empty() {
+            self.summary += "\n\n";
+        }
+    }
+
+    /// Condenses the summary once the answer text starts.
+    pub fn finish(&mut self) {
+        if let (Some(started), None) = (self.started, self.thought_for) {
+            self.thought_for = Some(started.elapsed());
+        }
+    }
+
+    pub fn thought_for(&self) -> Option<Duration> {
+        self.thought_for
+    }
+
+    pub fn display(&self) -> Option<Thinking<'_>> {
+        match (self.started, self.thought_for) {
+            (None, _) => None,
+            (_, Some(thought_for)) => Some(Thinking::Done(thought_for)),
+            (Some(_), None) => Some(Thinking::InProgress(&self.summary)),
+        }
+    }
+}
+
+/// A web source cited by an answer, shown as a numbered footnote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Citation {
+    pub url: String,
+    pub title: String,
+}
+
+impl Citation {
+    pub fn domain(&self) -> String {
+        let host = url::Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_else(|| self.url.clone());
+
+        host.trim_start_matches("www.").to_string()
+    }
+}
+
+/// Replaces each cited span of the text with a footnote marker like `[1]`.
+/// Spans are (start, end) char offsets. Urls cited more than once share their number.
+pub fn apply_citations(
+    text: &str,
+    spans: &[(usize, usize, Citation)],
+    citations: &mut Vec<Citation>,
+) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut spans = spans.to_vec();
+    spans.sort_by_key(|(start, _, _)| *start);
+
+    let mut result = String::new();
+    let mut position = 0;
+
+    for (start, end, citation) in spans {
+        // Overlapping or broken spans are left as they are
+        if start < position || end < start || end > chars.len() {
+            continue;
+        }
+
+        let number = match citations.iter().position(|cited| cited.url == citation.url) {
+            Some(index) => index + 1,
+            None => {
+                citations.push(citation);
+                citations.len()
+            }
+        };
+
+        result.extend(&chars[position..start]);
+        result.truncate(result.trim_end().len());
+        result.push_str(&format!(" [{}]", number));
+        position = end;
+    }
+
+    result.extend(&chars[position..]);
+
+    result
+}
+
+fn citation_blocks(citations: &[Citation]) -> Vec<BlockElement> {
+    let footnotes = citations
+        .iter()
+        .enumerate()
+        .map(|(i, citation)| {
+            // `|` and `>` would end the link early
+            let title = citation.title.replace(['|', '>'], " ");
+
+            format!("[{}] <{}|{}>", i + 1, citation.url, title)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut domains: Vec<String> = vec![];
+
+    for domain in citations.iter().map(Citation::domain) {
+        if !domains.contains(&domain) {
+            domains.push(domain);
+        }
+    }
+
+    vec![
+        BlockElement::Section(SectionBlock::new_markdown(&footnotes)),
+        BlockElement::Context(ContextBlock::new_markdown(&format!(
+            "Sources: {}",
+            domains.join(", ")
+        ))),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+/// Adds the usage of an API call to the total, if the API reported it.
+pub fn add_usage(total: &mut Option<TokenUsage>, usage: Option<TokenUsage>) {
+    if let Some(usage) = usage {
+        *total.get_or_insert_with(TokenUsage::default) += usage;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnswerVersion {
+    pub provider: LlmProvider,
+    pub model: String,
+    pub text: String,
+    // Summed over every API call made for the answer
+    pub usage: Option<TokenUsage>,
+    pub thought_for: Option<Duration>,
+    pub citations: Vec<Citation>,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnswerHistory {
+    pub request: LlmRequest,
+    pub versions: Vec<AnswerVersion>,
+    pub current: usize,
+}
+
+/// The versions of the latest answers, keyed by (channel, answer ts).
+#[derive(Default)]
+pub struct AnswerStore {
+    answers: Mutex<HashMap<(String, String), AnswerHistory>>,
+}
+
+impl AnswerStore {
+    /// Adds a new version and makes it current. Returns (current index, version count).
+    pub fn push(
+        &self,
+        channel: &str,
+        ts: &str,
+        request: &LlmRequest,
+        version: AnswerVersion,
+    ) -> (usize, usize) {
+        let mut answers = self.answers.lock().unwrap();
+        let key = (channel.to_string(), ts.to_string());
+
+        if !answers.contains_key(&key) && answers.len() >= MAX_STORED_ANSWERS {
+            let oldest = answers
+                .keys()
+                .min_by(|(_, a), (_, b)| {
+                    let (a, b) = (a.parse::<f64>(), b.parse::<f64>());
+                    a.unwrap_or(0.0).total_cmp(&b.unwrap_or(0.0))
+                })
+                .cloned();
+
+            if let Some(oldest) = oldest {
+                answers.remove(&oldest);
+            }
+        }
+
+        let history = answers.entry(key).or_insert_with(|| AnswerHistory {
+            request: request.clone(),
+            versions: vec![],
+            current: 0,
+        });
+
+        if history.versions.len() >= MAX_ANSWER_VERSIONS {
+            history.versions.remove(0);
+        }
+
+        history.versions.push(version);
+        history.current = history.versions.len() - 1;
+
+        (history.current, history.versions.len())
+    }
+
+    pub fn contains(&self, channel: &str, ts: &str) -> bool {
+        let answers = self.answers.lock().unwrap();
+
+        answers.contains_key(&(channel.to_string(), ts.to_string()))
+    }
+
+    pub fn get(&self, channel: &str, ts: &str) -> Option<AnswerHistory> {
+        let answers = self.answers.lock().unwrap();
+
+        answers.get(&(channel.to_string(), ts.to_string())).cloned()
+    }
+
+    /// Moves the current version by `offset`. Returns the new current version, its index and the version count.
+    pub fn navigate(
+        &self,
+        channel: &str,
+        ts: &str,
+        offset: isize,
+    ) -> Option<(AnswerVersion, usize, usize)> {
+        let mut answers = self.answers.lock().unwrap();
+
+        let history = answers.get_mut(&(channel.to_string(), ts.to_string()))?;
+
+        let current = history.current as isize + offset;
+
+        if current < 0 || current >= history.versions.len() as isize {
+            return None;
+        }
+
+        history.current = current as usize;
+
+        Some((
+            history.versions[history.current].clone(),
+            history.current,
+            history.versions.len(),
+        ))
+    }
+}
+
+pub enum AnswerControls {
+    Stop,
+    Final { current: usize, count: usize },
+}
+
+/// The name block of an answer, e.g. "`ChatGPT` (gpt-4o)".
+fn name_text(provider: LlmProvider, model: &str) -> String {
+    format!("`{}` ({})", provider.name(), model)
+}
+
+pub fn answer_blocks(
+    provider: LlmProvider,
+    model: &str,
+    message: &str,
+    thinking: Option<Thinking>,
+    citations: &[Citation],
+    tool_calls: &[ToolCall],
+    controls: AnswerControls,
+) -> Vec<BlockElement> {
+    let name_block = BlockElement::Section(SectionBlock::new_markdown(&name_text(provider, model)));
+    let answer_block = BlockElement::Section(SectionBlock::new_markdown(message));
+
+    // The name and the answer are read back from the thread by `answer_text`
+    let mut blocks = vec![name_block];
+
+    if let Some(thinking) = thinking {
+        blocks.push(thinking.block());
+    }
+
+    blocks.push(answer_block);
+
+    if !citations.is_empty() {
+        blocks.extend(citation_blocks(citations));
+    }
+
+    blocks.extend(
+        tool_calls
+            .iter()
+            .take(MAX_TOOL_CALL_BLOCKS)
+            .map(ToolCall::block),
+    );
+
+    match controls {
+        AnswerControls::Stop => blocks.push(stop_button_block()),
+        AnswerControls::Final { current, count } => {
+            if !tool_calls.is_empty() {
+                blocks.push(tools_footer_block(tool_calls));
+            }
+
+            let mut buttons = vec![];
+
+            if count > 1 {
+                if current > 0 {
+                    buttons.push(ButtonBlock::new("◀", PREV_VERSION_ACTION_ID, "prev", None));
+                }
+
+                buttons.push(ButtonBlock::new(
+                    &format!("{}/{}", current + 1, count),
+                    VERSION_LABEL_ACTION_ID,
+                    "current",
+                    None,
+                ));
+
+                if current + 1 < count {
+                    buttons.push(ButtonBlock::new("▶", NEXT_VERSION_ACTION_ID, "next", None));
+                }
+            }
+
+            buttons.push(ButtonBlock::new(
+                "Regenerate",
+                REGENERATE_ACTION_ID,
+                "regenerate",
+                None,
+            ));
+            buttons.push(ButtonBlock::new(
+                "Try other model",
+                OTHER_MODEL_ACTION_ID,
+                "other_model",
+                Some(ButtonStyle::Primary),
+            ));
+
+            blocks.push(BlockElement::Actions(ActionBlock {
+                block_id: None,
+                elements: Some(buttons.into_iter().map(BlockElement::Button).collect()),
+            }));
+        }
+    }
+
+    blocks
+}
+
+/// The answer text of a message posted with `answer_blocks` by the provider.
+pub fn answer_text(provider: LlmProvider, blocks: &[BlockElement]) -> Option<String> {
+    match blocks.first()? {
+        // Older answers were labelled without the model
+        BlockElement::Section(section)
+            if section.text.text == format!("`{}`", provider.name())
+                || section
+                    .text
+                    .text
+                    .starts_with(&format!("`{}` (", provider.name())) => {}
+        _ => return None,
+    }
+
+    blocks[1..].iter().find_map(|block| match block {
+        BlockElement::Section(section) => Some(section.text.text.clone()),
+        _ => None,
+    })
+}
+
+fn final_answer_blocks(version: &AnswerVersion, current: usize, count: usize) -> Vec<BlockElement> {
+    answer_blocks(
+        version.provider,
+        &version.model,
+        &version.text,
+        version.thought_for.map(Thinking::Done),
+        &version.citations,
+        &version.tool_calls,
+        AnswerControls::Final { current, count },
+    )
+}
+
+/// Posts (or overwrites) the final answer and records it as a new version.
+pub async fn post_final_answer<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    version: AnswerVersion,
+    ts: Option<&str>,
+) -> anyhow::Result<()> {
+    match ts {
+        Some(ts) => {
+            let (current, count) =
+                bot.answers()
+                    .push(&request.channel, ts, request, version.clone());
+            let blocks = final_answer_blocks(&version, current, count);
+
+            bot.edit_message(&request.channel, Message::Blocks(&blocks), ts)
+                .await?;
+        }
+        None => {
+            let blocks = final_answer_blocks(&version, 0, 1);
+
+            let sent = bot
+                .send_message(
+                    &request.channel,
+                    Message::Blocks(&blocks),
+                    request.reply_event(),
+                    None,
+                )
+                .await?;
+
+            if let Some(ts) = &sent.ts {
+                bot.answers()
+                    .push(&request.channel, &String::from(ts), request, version);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn answer<B: Bot>(
+    bot: &B,
+    provider: LlmProvider,
+    request: &LlmRequest,
+) -> anyhow::Result<()> {
+    // Read for every answer, as the relevant parts of long documents depend on the question
+    let documents = documents::context(bot, request).await.unwrap_or_else(|e| {
+        warn!(
+            "Failed to read the documents of {} - {:?}",
+            request.thread_ts, e
+        );
+        None
+    });
+    let resources =
+        prompts::resources_context(bot, &request.requester, &request.options.resources).await;
+
+    let documents = match (documents, resources) {
+        (Some(documents), Some(resources)) => Some(format!("{}\n{}", resources, documents)),
+        (documents, resources) => documents.or(resources),
+    };
+    let documents = documents.as_deref();
+
+    let answered = match provider {
+        LlmProvider::ChatGpt => chatgpt::answer(bot, request, documents).await,
+        LlmProvider::Gemini => gemini::answer(bot, request, documents).await,
+    };
+
+    // Stopping the answer drops the tool calls waiting for approval
+    bot.approvals().edit_cancelled(bot).await;
+
+    answered
+}
+
+/// Asks for a single answer, without tools or thread history.
+/// With `json`, the model is asked to answer with a JSON object.
+pub async fn complete<B: Bot>(
+    bot: &B,
+    provider: LlmProvider,
+    options: &LlmOptions,
+    instructions: &str,
+    input: &str,
+    json: bool,
+) -> anyhow::Result<String> {
+    match provider {
+        LlmProvider::ChatGpt => chatgpt::complete(bot, options, instructions, input, json).await,
+        LlmProvider::Gemini => gemini::complete(bot, options, instructions, input, json).await,
+    }
+}
+
+/// The tools of the MCP servers and of the bot itself, leaving out those never allowed.
+pub async fn tools_metadata<B: Bot>(bot: &B) -> anyhow::Result<Vec<tools::ToolMetadata>> {
+    let mut tools = bot.get_all_tools_metadata().await?;
+    if bot.search_index().is_indexing() {
+        tools.push(search::tool_metadata());
+    }
+
+    let policies = bot.tool_policies();
+    tools.retain(|tool| policies.get(&tool.name) != approval::ToolPolicy::Never);
+
+    Ok(tools)
+}
+
+/// Runs a tool the model asked for, following its approval policy.
+/// Refused and failed calls are reported back to the model as the tool output.
+pub async fn call_tool<B: Bot>(
+    bot: &B,
+    request: &LlmRequest,
+    name: &str,
+    arguments: HashMap<String, serde_json::Value>,
+) -> anyhow::Result<ToolCall> {
+    let mut tool_call = ToolCall {
+        name: name.to_string(),
+        arguments: serde_json::to_string(&arguments)?,
+        duration: None,
+        result: Err(String::new()),
+    };
+
+    let decision = match bot.tool_policies().get(name) {
+        approval::ToolPolicy::Always => approval::Decision::Approved,
+        approval::ToolPolicy::Never => {
+            tool_call.result = Err(format!("Calling the tool {} is not allowed.", name));
+            return Ok(tool_call);
+        }
+        approval::ToolPolicy::Ask => approval::ask(bot, request, name, &arguments).await?,
+    };
+
+    tool_call.result = match decision {
+        approval::Decision::Approved => {
+            let started = Instant::now();
+            let result = if name == search::TOOL_NAME {
+                search::call_tool(bot, &request.channel, &arguments).await
+            } else {
+                bot.call_mcp_tool(name, arguments).await
+            };
+
+            tool_call.duration = Some(started.elapsed());
+
+            result.map_err(|e| format!("Error: {:#}", e))
+        }
+        approval::Decision::Denied(user) => Err(format!(
+            "The user {} denied the call to the tool {}. Do not call it again for this request.",
+            user, name
+        )),
+        approval::Decision::TimedOut => Err(format!(
+            "The call to the tool {} was not approved in time.",
+            name
+        )),
+    };
+
+    info!(
+        "Tool call {} {} in {} took {:?} - {}",
+        tool_call.name,
+        tool_call.arguments,
+        request.channel,
+        tool_call.duration,
+        if tool_call.result.is_ok() {
+            "ok"
+        } else {
+            "failed"
+        }
+    );
+
+    Ok(tool_call)
+}
+
+pub async fn handle<B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::Result<()> {
+    let command = match command::parse(bot.bot_id(), &msg.text) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    debug!("LLM: bot command full text = {:?}", &msg.text);
+
+    let thread_ts = msg.thread_ts.clone().unwrap_or_else(|| msg.ts.clone());
+
+    let command = match command {
+        Ok(command) => command,
+        Err(e) => {
+            let text = match e {
+                command::CommandError::HelpRequested => command::usage(),
+                e => format!("{}\n{}", e, command::usage()),
+            };
+
+            return bot
+                .send_message(
+                    &msg.channel,
+                    Message::Blocks(&[BlockElement::Section(SectionBlock::new_markdown(&text))]),
+                    Some(ReplyMessageEvent {
+                        msg: thread_ts,
+                        broadcast: false,
+                    }),
+                    None,
+                )
+                .await
+                .and(Ok(()));
+        }
+    };
+
+    let request = |input_text: String, options: LlmOptions| LlmRequest {
+        channel: msg.channel.clone(),
+        requester: msg.user.clone(),
+        ts: msg.ts.clone(),
+        thread_ts: thread_ts.clone(),
+        input_text,
+        options,
+        answer_ts: None,
+    };
+
+    match command {
+        command::Command::Ask(command) => {
+            let request = request(command.prompt, command.options);
+
+            answer(bot, command.provider, &request).await
+        }
+        command::Command::Compare(command) => {
+            let request = request(command.prompt, LlmOptions::default());
+
+            compare::run(bot, request, command.targets).await
+        }
+        command::Command::Summarize(command) => summarize::run(bot, msg, command).await,
+        command::Command::Search(query) => search::run(bot, msg, &query).await,
+        command::Command::Prompt(command) => {
+            let request = request(String::new(), LlmOptions::default());
+
+            prompts::run(bot, request, command).await
+        }
+    }
+}
+
+pub async fn handle_action<B: Bot>(bot: &B, action: &crate::ActionEvent) -> anyhow::Result<()> {
+    match action.action_id.as_str() {
+        REGENERATE_ACTION_ID => rerun(bot, action, false).await,
+        OTHER_MODEL_ACTION_ID => rerun(bot, action, true).await,
+        PREV_VERSION_ACTION_ID => navigate(bot, action, -1).await,
+        NEXT_VERSION_ACTION_ID => navigate(bot, action, 1).await,
+        approval::APPROVE_ACTION_ID => {
+            approval::handle_action(bot, action, true);
+            Ok(())
+        }
+        approval::DENY_ACTION_ID => {
+            approval::handle_action(bot, action, false);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn rerun<B: Bot>(
+    bot: &B,
+    action: &crate::ActionEvent,
+    other_model: bool,
+) -> anyhow::Result<()> {
+    let history = match bot.answers().get(&action.channel, &action.message_ts) {
+        Some(history) => history,
+        None => return answer_unavailable(bot, action).await,
+    };
+
+    // Held until the answer registers its own generation, so a second click doesn't start another
+    let _claim =
+        match bot
+            .generations()
+            .try_register(&action.channel, &action.message_ts, &action.user)
+        {
+            Some(claim) => claim,
+            None => {
+                debug!("Answer {} is already being generated", action.message_ts);
+                return Ok(());
+            }
+        };
+
+    let current_provider = history.versions[history.current].provider;
+
+    // Whoever clicked runs the new version, and can stop it or approve its tool calls
+    let mut request = history.request;
+    request.requester = action.user.clone();
+    request.answer_ts = Some(action.message_ts.clone());
+
+    let provider = if other_model {
+        let provider = current_provider.other();
+
+        // The options were validated against the previous provider
+        request.options.model = None;
+
+        if request.options.temperature(provider).is_none() {
+            request.options.temperature = None;
+        }
+
+        if !request.options.reasoning(provider) {
+            request.options.reasoning_effort = None;
+        }
+
+        if !request.options.code_interpreter(provider, None) {
+            request.options.code_interpreter = false;
+        }
+
+        provider
+    } else {
+        if let Some(temperature) = request.options.temperature(current_provider) {
+            if temperature <= 0.0 {
+                request.options.temperature = Some(REGENERATE_TEMPERATURE);
+            }
+        }
+
+        current_provider
+    };
+
+    answer(bot, provider, &request).await
+}
+
+/// Tells the user who clicked that the answer was forgotten, as after a restart.
+async fn answer_unavailable<B: Bot>(bot: &B, action: &crate::ActionEvent) -> anyhow::Result<()> {
+    warn!(
+        "No answer history for {} in {}",
+        action.message_ts, action.channel
+    );
+
+    bot.send_ephemeral(
+        &action.channel,
+        &action.user,
+        "This answer is no longer available to regenerate or browse. Please ask again.",
+        action.thread_ts.as_deref(),
+    )
+    .await
+}
+
+async fn navigate<B: Bot>(
+    bot: &B,
+    action: &crate::ActionEvent,
+    offset: isize,
+) -> anyhow::Result<()> {
+    let _claim =
+        match bot
+            .generations()
+            .try_register(&action.channel, &action.message_ts, &action.user)
+        {
+            Some(claim) => claim,
+            None => return Ok(()),
+        };
+
+    let (version, current, count) =
+        match bot
+            .answers()
+            .navigate(&action.channel, &action.message_ts, offset)
+        {
+            Some(navigated) => navigated,
+            None if !bot.answers().contains(&action.channel, &action.message_ts) => {
+                return answer_unavailable(bot, action).await
+            }
+            None => return Ok(()),
+        };
+
+    let blocks = final_answer_blocks(&version, current, count);
+
+    bot.edit_message(
+        &action.channel,
+        Message::Blocks(&blocks),
+        &action.message_ts,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_answer_store_navigation() {
+    let store = AnswerStore::default();
+    let request = LlmRequest {
+        channel: "C123".to_string(),
+        requester: "U123".to_string(),
+        ts: "1.0".to_string(),
+        thread_ts: "1.0".to_string(),
+        input_text: "hello".to_string(),
+        options: LlmOptions::default(),
+        answer_ts: None,
+    };
+
+    for (provider, text) in [
+        (LlmProvider::ChatGpt, "first"),
+        (LlmProvider::ChatGpt, "second"),
+        (LlmProvider::Gemini, "third"),
+    ] {
+        store.push(
+            "C123",
+            "2.0",
+            &request,
+            AnswerVersion {
+                provider,
+                model: provider.default_model(),
+                text: text.to_string(),
+                usage: None,
+                thought_for: None,
+                citations: vec![],
+                tool_calls: vec![],
+            },
+        );
+    }
+
+    assert!(store.navigate("C123", "2.0", 1).is_none());
+
+    let (version, current, count) = store.navigate("C123", "2.0", -1).unwrap();
+    assert_eq!(version.text, "second");
+    assert_eq!((current, count), (1, 3));
+
+    let (version, current, _) = store.navigate("C123", "2.0", -1).unwrap();
+    assert_eq!(version.text, "first");
+    assert_eq!(current, 0);
+
+    assert!(store.navigate("C123", "2.0", -1).is_none());
+    assert_eq!(store.get("C123", "2.0").unwrap().current, 0);
+    assert!(store.get("C123", "3.0").is_none());
+}
+
+#[test]
+fn test_answer_store_eviction() {
+    let store = AnswerStore::default();
+    let request = LlmRequest {
+        channel: "C123".to_string(),
+        requester: "U123".to_string(),
+        ts: "1.0".to_string(),
+        thread_ts: "1.0".to_string(),
+        input_text: "hello".to_string(),
+        options: LlmOptions::default(),
+        answer_ts: None,
+    };
+    let version = |text: String| AnswerVersion {
+        provider: LlmProvider::ChatGpt,
+        model: "gpt-4o".to_string(),
+        text,
+        usage: None,
+        thought_for: None,
+        citations: vec![],
+        tool_calls: vec![],
+    };
+
+    for i in 0..=MAX_STORED_ANSWERS {
+        store.push(
+            "C123",
+            &format!("{}.0", i + 2),
+            &request,
+            version(i.to_string()),
+        );
+    }
+
+    assert!(!store.contains("C123", "2.0"));
+    assert!(store.contains("C123", "3.0"));
+
+    for i in 0..=MAX_ANSWER_VERSIONS {
+        store.push("C123", "3.0", &request, version(i.to_string()));
+    }
+
+    let history = store.get("C123", "3.0").unwrap();
+    assert_eq!(history.versions.len(), MAX_ANSWER_VERSIONS);
+    assert_eq!(history.versions[0].text, "1");
+}
+
+#[test]
+fn test_tool_call_blocks() {
+    assert_eq!(preview("a `b`\n  c", 10), "a 'b' c");
+    assert_eq!(preview("가나다라마", 3), "가나다…");
+
+    let tool_call = |name: &str| ToolCall {
+        name: name.to_string(),
+        arguments: "{}".to_string(),
+        duration: None,
+        result: Ok("result".to_string()),
+    };
+
+    let blocks = answer_blocks(
+        LlmProvider::ChatGpt,
+        "gpt-4o",
+        "answer",
+        None,
+        &[],
+        &[tool_call("fetch"), tool_call("time"), tool_call("fetch")],
+        AnswerControls::Final {
+            current: 0,
+            count: 1,
+        },
+    );
+
+    // name, answer, 3 tool calls, footer, actions
+    assert_eq!(blocks.len(), 7);
+
+    match &blocks[5] {
+        BlockElement::Context(context) => match &context.elements[0] {
+            crate::slack::ContextElement::Text(text) => {
+                assert_eq!(text.text, "Tools used: `fetch` ×2, `time`")
+            }
+            etc => panic!("Unexpected context element {:?}", etc),
+        },
+        etc => panic!("Unexpected block {:?}", etc),
+    }
+}
+
+#[test]
+fn test_thinking_state() {
+    let mut thinking = ThinkingState::default();
+
+    assert!(thinking.display().is_none());
+
+    thinking.push("**Planning**");
+    thinking.new_part();
+    thinking.push("Checking");
+
+    match thinking.display() {
+        Some(Thinking::InProgress(summary)) => assert_eq!(summary, "**Planning**\n\nChecking"),
+        etc => panic!("Unexpected thinking {:?}", etc),
+    }
+
+    thinking.finish();
+
+    assert!(matches!(thinking.display(), Some(Thinking::Done(_))));
+    assert!(thinking.thought_for().is_some());
+
+    let blocks = answer_blocks(
+        LlmProvider::ChatGpt,
+        "gpt-4o",
+        "answer",
+        thinking.display(),
+        &[],
+        &[],
+        AnswerControls::Stop,
+    );
+
+    assert_eq!(
+        answer_text(LlmProvider::ChatGpt, &blocks).as_deref(),
+        Some("answer")
+    );
+    assert!(answer_text(LlmProvider::Gemini, &blocks).is_none());
+}
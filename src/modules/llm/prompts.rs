@@ -0,0 +1,193 @@
+use log::warn;
+use rmcp::model::{GetPromptResult, PromptMessageContent, PromptMessageRole};
+
+use crate::{
+    slack::{BlockElement, SectionBlock},
+    Bot, Message, ReplyMessageEvent,
+};
+
+use super::{answer, command::PromptCommand, LlmRequest};
+
+/// Answers the prompt of an MCP server, or lists the prompts if none is given.
+pub async fn run<B: Bot>(
+    bot: &B,
+    mut request: LlmRequest,
+    command: PromptCommand,
+) -> anyhow::Result<()> {
+    let (server, name) = match command.prompt {
+        Some(prompt) => prompt,
+        None => return reply(bot, &request, &prompt_list(bot).await).await,
+    };
+
+    let arguments = command
+        .arguments
+        .into_iter()
+        .map(|(name, value)| (name, serde_json::Value::String(value)))
+        .collect();
+
+    let prompt = match bot.mcp().get_prompt(&server, &name, arguments).await {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            warn!("Failed to get the prompt {}/{} - {:?}", server, name, e);
+
+            return reply(
+                bot,
+                &request,
+                &format!("Failed to get the prompt {}/{} - {}", server, name, e),
+            )
+            .await;
+        }
+    };
+
+    request.input_text = prompt_text(&prompt);
+    request.options = command.options;
+
+    answer(bot, command.provider, &request).await
+}
+
+async fn prompt_list<B: Bot>(bot: &B) -> String {
+    let prompts = bot.mcp().prompts().await;
+
+    if prompts.is_empty() {
+        return "No prompts found.".to_string();
+    }
+
+    prompts
+        .iter()
+        .map(|(server, prompt)| {
+            let mut line = format!("`{}/{}`", server, prompt.name);
+
+            if let Some(description) = &prompt.description {
+                line += &format!(" - {}", description);
+            }
+
+            let arguments = prompt
+                .arguments
+                .iter()
+                .flatten()
+                .map(|argument| {
+                    if argument.required.unwrap_or(false) {
+                        argument.name.clone()
+                    } else {
+                        format!("[{}]", argument.name)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            if !arguments.is_empty() {
+                line += &format!(" ({})", arguments.join(", "));
+            }
+
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn reply<B: Bot>(bot: &B, request: &LlmRequest, text: &str) -> anyhow::Result<()> {
+    bot.send_message(
+        &request.channel,
+        Message::Blocks(&[BlockElement::Section(SectionBlock::new_markdown(text))]),
+        Some(ReplyMessageEvent {
+            msg: request.thread_ts.clone(),
+            broadcast: false,
+        }),
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The messages of a prompt as one input. Conversations are written out with their speakers.
+fn prompt_text(prompt: &GetPromptResult) -> String {
+    let only_user = prompt
+        .messages
+        .iter()
+        .all(|message| message.role == PromptMessageRole::User);
+
+    prompt
+        .messages
+        .iter()
+        .map(|message| {
+            let text = match &message.content {
+                PromptMessageContent::Text { text } => text.clone(),
+                PromptMessageContent::Image { .. } => "(image)".to_string(),
+                PromptMessageContent::Resource { resource } => resource.get_text(),
+            };
+
+            match (only_user, &message.role) {
+                (true, _) => text,
+                (false, PromptMessageRole::User) => format!("User: {}", text),
+                (false, PromptMessageRole::Assistant) => format!("Assistant: {}", text),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The MCP resources attached to the request, to put in front of the conversation.
+/// Only admins may attach the resources of servers not in `resource_servers` of the config.
+pub async fn resources_context<B: Bot>(
+    bot: &B,
+    requester: &str,
+    uris: &[String],
+) -> Option<String> {
+    if uris.is_empty() {
+        return None;
+    }
+
+    let is_admin = bot.is_admin(requester);
+    let allows_server = |server: &str| {
+        is_admin
+            || bot
+                .config()
+                .resource_servers
+                .iter()
+                .any(|allowed| allowed == server)
+    };
+
+    let mut text = "These are the resources attached to the request.\n".to_string();
+
+    for uri in uris {
+        match bot.mcp().read_resource(uri, allows_server).await {
+            Ok(contents) => {
+                text += &format!("\n<resource uri=\"{}\">\n{}\n</resource>\n", uri, contents)
+            }
+            Err(e) => {
+                warn!("Failed to read the resource {} - {:?}", uri, e);
+                text += &format!("\nThis resource could not be read - {}: {}\n", uri, e);
+            }
+        }
+    }
+
+    Some(text)
+}
+
+#[cfg(test)]
+mod test {
+    use rmcp::model::{GetPromptResult, PromptMessage, PromptMessageRole};
+
+    use super::prompt_text;
+
+    #[test]
+    fn test_prompt_text() {
+        let prompt = GetPromptResult {
+            description: None,
+            messages: vec![
+                PromptMessage::new_text(PromptMessageRole::User, "Review this code"),
+                PromptMessage::new_text(PromptMessageRole::User, "fn main() {}"),
+            ],
+        };
+        assert_eq!(prompt_text(&prompt), "Review this code\n\nfn main() {}");
+
+        let prompt = GetPromptResult {
+            description: None,
+            messages: vec![
+                PromptMessage::new_text(PromptMessageRole::User, "Hi"),
+                PromptMessage::new_text(PromptMessageRole::Assistant, "Hello"),
+            ],
+        };
+        assert_eq!(prompt_text(&prompt), "User: Hi\n\nAssistant: Hello");
+    }
+}
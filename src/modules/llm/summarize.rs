@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    slack::{BlockElement, ContextBlock, HistoryMessage, SectionBlock},
+    Bot, Message, ReplyMessageEvent,
+};
+
+use super::{command::SummarizeCommand, complete, name_text, LlmOptions, LlmProvider};
+
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+// Each page has up to 200 messages
+const MAX_PAGES: usize = 10;
+// Threads of a channel are fetched one by one
+const MAX_THREADS: usize = 20;
+// The oldest messages are left out beyond this
+const MAX_TRANSCRIPT_CHARS: usize = 100_000;
+// Section text is limited to 3000 characters
+const MAX_SECTION_LEN: usize = 2900;
+
+const INSTRUCTIONS: &str = "You summarize Slack conversations for people who missed them. \
+Reply in the language mostly used in the conversation, with a JSON object of this shape: \
+{\"overview\": \"two or three sentences\", \
+\"decisions\": [\"decision\"], \
+\"open_questions\": [\"question\"], \
+\"action_items\": [{\"task\": \"task\", \"owner\": \"name or null\"}]}. \
+Only list what the conversation says. Use empty arrays when there is nothing to list.";
+
+static MENTION_REGEX: OnceCell<Regex> = OnceCell::new();
+
+#[derive(Debug, Default, Deserialize)]
+struct Summary {
+    #[serde(default)]
+    overview: String,
+    #[serde(default)]
+    decisions: Vec<String>,
+    #[serde(default)]
+    open_questions: Vec<String>,
+    #[serde(default)]
+    action_items: Vec<ActionItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActionItem {
+    task: String,
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+pub enum Target {
+    Thread(String),
+    Channel { channel: String, period: Duration },
+}
+
+impl Target {
+    fn title(&self) -> String {
+        match self {
+            Target::Thread(_) => "*Summary of this thread*".to_string(),
+            Target::Channel { channel, period } => format!(
+                "*Summary of <#{}> for the last {}*",
+                channel,
+                format_period(*period)
+            ),
+        }
+    }
+}
+
+fn format_period(period: Duration) -> String {
+    let secs = period.as_secs();
+
+    match secs {
+        _ if secs % (24 * 60 * 60) == 0 => format!("{}d", secs / (24 * 60 * 60)),
+        _ if secs % (60 * 60) == 0 => format!("{}h", secs / (60 * 60)),
+        _ => format!("{}m", secs / 60),
+    }
+}
+
+/// Reads every page of a thread or of the channel history since `oldest`, oldest first.
+pub async fn fetch_messages<B: Bot>(
+    bot: &B,
+    channel: &str,
+    thread_ts: Option<&str>,
+    oldest: Option<&str>,
+) -> anyhow::Result<Vec<HistoryMessage>> {
+    let mut messages = vec![];
+    let mut cursor = None;
+
+    for _ in 0..MAX_PAGES {
+        let res = bot
+            .get_messages(channel, thread_ts, oldest, cursor.as_deref())
+            .await?;
+
+        messages.extend(res.messages.iter().cloned());
+
+        cursor = match res.next_cursor() {
+            Some(cursor) => Some(cursor.to_string()),
+            None => break,
+        };
+    }
+
+    // Channel history comes newest first
+    messages.sort_by(|a, b| {
+        let ts = |message: &HistoryMessage| message.ts.parse::<f64>().unwrap_or_default();
+        ts(a).total_cmp(&ts(b))
+    });
+
+    Ok(messages)
+}
+
+/// Lines of `Name: text`, with the replies of a thread indented under it.
+async fn transcript<B: Bot>(
+    bot: &B,
+    channel: &str,
+    target: &Target,
+    skip_ts: Option<&str>,
+) -> anyhow::Result<(String, usize)> {
+    let messages = match target {
+        Target::Thread(thread_ts) => fetch_messages(bot, channel, Some(thread_ts), None)
+            .await?
+            .into_iter()
+            .map(|message| (message, false))
+            .collect::<Vec<_>>(),
+        Target::Channel { channel, period } => {
+            let oldest = SystemTime::now()
+                .duration_since(UNIX_EPOCH)?
+                .saturating_sub(*period)
+                .as_secs()
+                .to_string();
+
+            let mut messages = vec![];
+            let mut threads = 0;
+
+            for message in fetch_messages(bot, channel, None, Some(&oldest)).await? {
+                let has_replies = message.reply_count.unwrap_or(0) > 0;
+                let ts = message.ts.clone();
+
+                messages.push((message, false));
+
+                if !has_replies || threads >= MAX_THREADS {
+                    continue;
+                }
+
+                threads += 1;
+
+                match fetch_messages(bot, channel, Some(&ts), None).await {
+                    // The first reply is the parent message
+                    Ok(replies) => messages.extend(
+                        replies
+                            .into_iter()
+                            .filter(|reply| reply.ts != ts)
+                            .map(|reply| (reply, true)),
+                    ),
+                    Err(e) => warn!("Failed to read the thread {} - {:?}", ts, e),
+                }
+            }
+
+            messages
+        }
+    };
+
+    let mut names = HashMap::new();
+    let mut lines = vec![];
+
+    for (message, is_reply) in messages {
+        if Some(message.ts.as_str()) == skip_ts || message.text.trim().is_empty() {
+            continue;
+        }
+
+        if matches!(
+            message.subtype.as_deref(),
+            Some("channel_join" | "channel_leave")
+        ) {
+            continue;
+        }
+
+        let speaker = match (&message.user, &message.username) {
+            (Some(user), _) => user_name(bot, &mut names, user).await,
+            (None, Some(username)) => username.clone(),
+            (None, None) => "bot".to_string(),
+        };
+
+        let text = replace_mentions(bot, &mut names, &message.text).await?;
+
+        lines.push(format!(
+            "{}{}: {}",
+            if is_reply { "  ↳ " } else { "" },
+            speaker,
+            text
+        ));
+    }
+
+    let count = lines.len();
+    let mut transcript = lines.join("\n");
+
+    if transcript.len() > MAX_TRANSCRIPT_CHARS {
+        let mut start = transcript.len() - MAX_TRANSCRIPT_CHARS;
+
+        while !transcript.is_char_boundary(start) {
+            start += 1;
+        }
+
+        transcript = transcript.split_off(start);
+    }
+
+    Ok((transcript, count))
+}
+
+pub async fn user_name<B: Bot>(bot: &B, names: &mut HashMap<String, String>, user: &str) -> String {
+    if let Some(name) = names.get(user) {
+        return name.clone();
+    }
+
+    let name = bot.get_user_name(user).await.unwrap_or_else(|e| {
+        warn!("Failed to get the name of {} - {:?}", user, e);
+        user.to_string()
+    });
+
+    names.insert(user.to_string(), name.clone());
+
+    name
+}
+
+/// Replaces `<@U0123456>` with `@name`.
+pub async fn replace_mentions<B: Bot>(
+    bot: &B,
+    names: &mut HashMap<String, String>,
+    text: &str,
+) -> anyhow::Result<String> {
+    let regex = MENTION_REGEX.get_or_try_init(|| Regex::new(r"<@([UW][A-Z0-9]+)>"))?;
+
+    let users = regex
+        .captures_iter(text)
+        .map(|captures| captures[1].to_string())
+        .collect::<Vec<_>>();
+
+    let mut text = text.to_string();
+
+    for user in users {
+        let name = user_name(bot, names, &user).await;
+        text = text.replace(&format!("<@{}>", user), &format!("@{}", name));
+    }
+
+    Ok(text)
+}
+
+fn truncate(text: String) -> String {
+    match text.char_indices().nth(MAX_SECTION_LEN) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+fn list_section(title: &str, items: &[String]) -> BlockElement {
+    let items = match items {
+        [] => "• None".to_string(),
+        items => items
+            .iter()
+            .map(|item| format!("• {}", item))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+
+    BlockElement::Section(SectionBlock::new_markdown(&truncate(format!(
+        "*{}*\n{}",
+        title, items
+    ))))
+}
+
+fn summary_blocks(title: &str, summary: &Summary, footer: &str) -> Vec<BlockElement> {
+    let mut blocks = vec![BlockElement::Section(SectionBlock::new_markdown(title))];
+
+    if !summary.overview.is_empty() {
+        blocks.push(BlockElement::Section(SectionBlock::new_markdown(
+            &truncate(summary.overview.clone()),
+        )));
+    }
+
+    let action_items = summary
+        .action_items
+        .iter()
+        .map(|item| match &item.owner {
+            Some(owner) if !owner.is_empty() => format!("{} — {}", item.task, owner),
+            _ => format!("{} — _unassigned_", item.task),
+        })
+        .collect::<Vec<_>>();
+
+    blocks.push(list_section(
+        ":white_check_mark: Decisions",
+        &summary.decisions,
+    ));
+    blocks.push(list_section(
+        ":question: Open questions",
+        &summary.open_questions,
+    ));
+    blocks.push(list_section(":pushpin: Action items", &action_items));
+    blocks.push(BlockElement::Context(ContextBlock::new_markdown(footer)));
+
+    blocks
+}
+
+/// Models sometimes wrap the JSON in a code block despite the JSON mode.
+fn parse_summary(text: &str) -> Option<Summary> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("```json")
+        .or_else(|| text.strip_prefix("```"))
+        .and_then(|text| text.strip_suffix("```"))
+        .unwrap_or(text);
+
+    serde_json::from_str(text).ok()
+}
+
+/// The summary as a message to post. Failures are reported in the message.
+pub async fn summary_message<B: Bot>(
+    bot: &B,
+    provider: LlmProvider,
+    options: &LlmOptions,
+    channel: &str,
+    target: &Target,
+    skip_ts: Option<&str>,
+) -> Vec<BlockElement> {
+    let text = match transcript(bot, channel, target, skip_ts).await {
+        Ok((_, 0)) => Err("There is nothing to summarize.".to_string()),
+        Ok((transcript, count)) => {
+            match complete(bot, provider, options, INSTRUCTIONS, &transcript, true).await {
+                Ok(answer) => Ok((answer, count)),
+                Err(e) => {
+                    error!("Failed to summarize - {:?}", e);
+                    Err(format!("Failed to summarize: {}", e))
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed to read the messages to summarize - {:?}", e);
+            Err(format!("Failed to read the messages: {}", e))
+        }
+    };
+
+    match text {
+        Ok((answer, count)) => {
+            let footer = format!(
+                "{} · {} messages",
+                name_text(provider, &options.model(provider)),
+                count
+            );
+
+            match parse_summary(&answer) {
+                Some(summary) => summary_blocks(&target.title(), &summary, &footer),
+                None => vec![
+                    BlockElement::Section(SectionBlock::new_markdown(&target.title())),
+                    BlockElement::Section(SectionBlock::new_markdown(&truncate(answer))),
+                    BlockElement::Context(ContextBlock::new_markdown(&footer)),
+                ],
+            }
+        }
+        Err(error) => vec![BlockElement::Section(SectionBlock::new_markdown(&error))],
+    }
+}
+
+async fn is_member<B: Bot>(bot: &B, channel: &str, user: &str) -> bool {
+    match bot.get_channel_members(channel).await {
+        Ok(members) => members.iter().any(|member| member == user),
+        Err(e) => {
+            warn!("Failed to read the members of {} - {:?}", channel, e);
+            false
+        }
+    }
+}
+
+pub async fn run<B: Bot>(
+    bot: &B,
+    msg: &crate::MessageEvent,
+    command: SummarizeCommand,
+) -> anyhow::Result<()> {
+    let reply_event = Some(ReplyMessageEvent {
+        msg: msg.thread_ts.clone().unwrap_or_else(|| msg.ts.clone()),
+        broadcast: false,
+    });
+
+    let target = match (&command.channel, command.period, &msg.thread_ts) {
+        (None, None, Some(thread_ts)) => Target::Thread(thread_ts.clone()),
+        (channel, period, _) => Target::Channel {
+            channel: channel.clone().unwrap_or_else(|| msg.channel.clone()),
+            period: period.unwrap_or(DEFAULT_PERIOD),
+        },
+    };
+
+    // The bot can read channels the requester can't, and the summary is posted here
+    if let Target::Channel { channel, .. } = &target {
+        if *channel != msg.channel && !is_member(bot, channel, &msg.user).await {
+            let text = format!(
+                "Only members of <#{}> can summarize it from another channel.",
+                channel
+            );
+
+            bot.send_message(
+                &msg.channel,
+                Message::Blocks(&[BlockElement::Section(SectionBlock::new_markdown(&text))]),
+                reply_event,
+                None,
+            )
+            .await?;
+
+            return Ok(());
+        }
+    }
+
+    let blocks = summary_message(
+        bot,
+        command.provider,
+        &command.options,
+        &msg.channel,
+        &target,
+        Some(&msg.ts),
+    )
+    .await;
+
+    bot.send_message(&msg.channel, Message::Blocks(&blocks), reply_event, None)
+        .await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_summary() {
+    let summary = parse_summary(
+        "```json\n{\"overview\": \"Release planning\", \"decisions\": [\"Ship on Friday\"], \
+         \"action_items\": [{\"task\": \"Write notes\", \"owner\": \"Alice\"}, {\"task\": \"Tag\", \"owner\": null}]}\n```",
+    )
+    .unwrap();
+
+    assert_eq!(summary.overview, "Release planning");
+    assert!(summary.open_questions.is_empty());
+
+    let blocks = summary_blocks("*Summary*", &summary, "footer");
+
+    // title, overview, 3 lists, footer
+    assert_eq!(blocks.len(), 6);
+
+    match &blocks[4] {
+        BlockElement::Section(section) => assert_eq!(
+            section.text.text,
+            "*:pushpin: Action items*\n• Write notes — Alice\n• Tag — _unassigned_"
+        ),
+        etc => panic!("Unexpected block {:?}", etc),
+    }
+
+    assert!(parse_summary("Not JSON").is_none());
+    assert_eq!(format_period(Duration::from_secs(2 * 24 * 60 * 60)), "2d");
+    assert_eq!(format_period(Duration::from_secs(90 * 60)), "90m");
+}
@@ -0,0 +1,118 @@
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MIN_INTERVAL_MS: u64 = 1000;
+const DEFAULT_MIN_CHARS: usize = 20;
+
+/// Decides when a streaming answer is worth another Slack edit.
+///
+/// The message is edited once at least `min_chars` characters arrived since the last edit,
+/// and `min_interval` has passed. Fewer characters are flushed by a timer once `min_interval`
+/// has passed, so text isn't held back while the stream stalls or a tool runs.
+pub struct EditThrottle {
+    min_interval: Duration,
+    min_chars: usize,
+    last_edit: Option<Instant>,
+    pending_chars: usize,
+}
+
+impl EditThrottle {
+    pub fn new(min_interval: Duration, min_chars: usize) -> Self {
+        Self {
+            min_interval,
+            min_chars,
+            last_edit: None,
+            pending_chars: 0,
+        }
+    }
+
+    /// Reads `STREAM_EDIT_INTERVAL_MS` and `STREAM_EDIT_MIN_CHARS`.
+    pub fn from_env() -> Self {
+        let min_interval = env::var("STREAM_EDIT_INTERVAL_MS")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_MIN_INTERVAL_MS);
+
+        let min_chars = env::var("STREAM_EDIT_MIN_CHARS")
+            .ok()
+            .and_then(|chars| chars.parse().ok())
+            .unwrap_or(DEFAULT_MIN_CHARS);
+
+        Self::new(Duration::from_millis(min_interval), min_chars)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pending_chars > 0
+    }
+
+    /// Whether text is waiting and enough time has passed to edit the message for it.
+    pub fn is_due(&self) -> bool {
+        self.is_due_at(Instant::now())
+    }
+
+    fn is_due_at(&self, now: Instant) -> bool {
+        self.pending_chars > 0
+            && self
+                .last_edit
+                .is_none_or(|last_edit| now - last_edit >= self.min_interval)
+    }
+
+    /// Records a streamed delta. Returns true if the message should be edited now.
+    pub fn push(&mut self, delta: &str) -> bool {
+        self.push_at(delta, Instant::now())
+    }
+
+    fn push_at(&mut self, delta: &str, now: Instant) -> bool {
+        self.pending_chars += delta.chars().count();
+
+        self.pending_chars >= self.min_chars
+            && self
+                .last_edit
+                .is_none_or(|last_edit| now - last_edit >= self.min_interval)
+    }
+
+    pub fn edited(&mut self) {
+        self.edited_at(Instant::now());
+    }
+
+    fn edited_at(&mut self, now: Instant) {
+        self.last_edit = Some(now);
+        self.pending_chars = 0;
+    }
+}
+
+#[test]
+fn test_edit_throttle() {
+    let mut throttle = EditThrottle::new(Duration::from_millis(500), 5);
+    let start = Instant::now();
+
+    // Korean output rarely has standalone punctuation deltas
+    assert!(!throttle.push_at("안녕", start));
+    assert!(throttle.push_at("하세요", start));
+    throttle.edited_at(start);
+
+    assert!(!throttle.push_at("반갑습니다", start + Duration::from_millis(100)));
+    assert!(throttle.push_at("", start + Duration::from_millis(500)));
+    throttle.edited_at(start + Duration::from_millis(500));
+
+    assert!(!throttle.push_at("a", start + Duration::from_secs(10)));
+}
+
+#[test]
+fn test_edit_throttle_due() {
+    let mut throttle = EditThrottle::new(Duration::from_millis(500), 5);
+    let start = Instant::now();
+
+    throttle.edited_at(start);
+    assert!(!throttle.is_due_at(start + Duration::from_secs(10)));
+
+    assert!(!throttle.push_at("a", start + Duration::from_millis(100)));
+    assert!(!throttle.is_due_at(start + Duration::from_millis(200)));
+    assert!(throttle.is_due_at(start + Duration::from_millis(500)));
+}
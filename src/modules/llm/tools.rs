@@ -0,0 +1,258 @@
+use serde_json::{json, Map, Value};
+
+/// Keywords OpenAI accepts in strict mode. Schemas using any other are sent without strict mode.
+const STRICT_KEYWORDS: &[&str] = &[
+    "type",
+    "description",
+    "title",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "enum",
+    "const",
+    "anyOf",
+    "$ref",
+    "$defs",
+    "definitions",
+];
+
+/// A tool the models can call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolMetadata {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the arguments, always an object.
+    pub parameters: Value,
+}
+
+impl ToolMetadata {
+    pub fn new(name: String, description: &str, input_schema: Map<String, Value>) -> Self {
+        let mut parameters = input_schema;
+
+        // Only describes the schema itself, and some APIs reject it
+        parameters.remove("$schema");
+        parameters.entry("type").or_insert_with(|| json!("object"));
+        parameters.entry("properties").or_insert_with(|| json!({}));
+
+        let description = match description.trim() {
+            "" => format!("Call tool {}", name),
+            description => description.to_string(),
+        };
+
+        Self {
+            name,
+            description,
+            parameters: Value::Object(parameters),
+        }
+    }
+
+    pub fn has_parameters(&self) -> bool {
+        self.parameters["properties"]
+            .as_object()
+            .is_some_and(|properties| !properties.is_empty())
+    }
+
+    /// The parameters in the form OpenAI's strict mode wants, if they can be.
+    pub fn strict_parameters(&self) -> Option<Value> {
+        let mut parameters = self.parameters.clone();
+
+        make_strict(&mut parameters).then_some(parameters)
+    }
+}
+
+fn make_nullable(schema: &mut Value) {
+    let schema_map = match schema.as_object_mut() {
+        Some(schema) => schema,
+        None => return,
+    };
+
+    if let Some(values) = schema_map.get_mut("enum").and_then(Value::as_array_mut) {
+        if !values.contains(&Value::Null) {
+            values.push(Value::Null);
+        }
+    }
+
+    match schema_map.get_mut("type") {
+        Some(Value::String(ty)) => {
+            let ty = ty.clone();
+            schema_map.insert("type".to_string(), json!([ty, "null"]));
+        }
+        Some(Value::Array(types)) => {
+            if !types.contains(&json!("null")) {
+                types.push(json!("null"));
+            }
+        }
+        _ => {
+            let inner = schema.take();
+            *schema = json!({ "anyOf": [inner, { "type": "null" }] });
+        }
+    }
+}
+
+/// Strict mode wants every property listed in `required`, with optional ones made nullable,
+/// and no other properties allowed. Returns false if the schema can't be written that way.
+fn make_strict(schema: &mut Value) -> bool {
+    let schema = match schema.as_object_mut() {
+        Some(schema) => schema,
+        // `true` and `false` schemas
+        None => return false,
+    };
+
+    if schema
+        .keys()
+        .any(|keyword| !STRICT_KEYWORDS.contains(&keyword.as_str()))
+    {
+        return false;
+    }
+
+    for keyword in ["$defs", "definitions"].iter().copied() {
+        if let Some(definitions) = schema.get_mut(keyword).and_then(Value::as_object_mut) {
+            if !definitions.values_mut().all(make_strict) {
+                return false;
+            }
+        }
+    }
+
+    if let Some(variants) = schema.get_mut("anyOf").and_then(Value::as_array_mut) {
+        if !variants.iter_mut().all(make_strict) {
+            return false;
+        }
+    }
+
+    if let Some(items) = schema.get_mut("items") {
+        if !make_strict(items) {
+            return false;
+        }
+    }
+
+    match schema.get("additionalProperties") {
+        None | Some(Value::Bool(false)) => {}
+        Some(_) => return false,
+    }
+
+    let required = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|required| {
+            required
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let properties = match schema.get_mut("properties") {
+        Some(Value::Object(properties)) => properties,
+        Some(_) => return false,
+        // Free-form objects can't be strict
+        None => return schema.get("type") != Some(&json!("object")),
+    };
+
+    for (name, property) in properties.iter_mut() {
+        if !make_strict(property) {
+            return false;
+        }
+
+        if !required.contains(name) {
+            make_nullable(property);
+        }
+    }
+
+    let names = properties.keys().cloned().map(Value::String).collect();
+
+    schema.insert("required".to_string(), Value::Array(names));
+    schema.insert("additionalProperties".to_string(), Value::Bool(false));
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tool(schema: Value) -> ToolMetadata {
+        ToolMetadata::new(
+            "server_tool".to_string(),
+            "",
+            schema.as_object().cloned().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_strict_parameters() {
+        let tool = tool(json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "url": { "type": "string", "description": "The URL" },
+                "format": { "type": "string", "enum": ["html", "text"] },
+                "headers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "name": { "type": "string" } },
+                    },
+                },
+            },
+            "required": ["url"],
+        }));
+
+        assert_eq!(tool.description, "Call tool server_tool");
+        assert!(tool.has_parameters());
+        assert!(tool.parameters.get("$schema").is_none());
+
+        let strict = tool.strict_parameters().unwrap();
+
+        assert_eq!(strict["additionalProperties"], json!(false));
+        assert_eq!(strict["required"], json!(["format", "headers", "url"]));
+        assert_eq!(strict["properties"]["url"]["type"], json!("string"));
+        assert_eq!(
+            strict["properties"]["format"]["type"],
+            json!(["string", "null"])
+        );
+        assert_eq!(
+            strict["properties"]["format"]["enum"],
+            json!(["html", "text", null])
+        );
+        assert_eq!(
+            strict["properties"]["headers"]["items"]["required"],
+            json!(["name"])
+        );
+        assert_eq!(
+            strict["properties"]["headers"]["items"]["properties"]["name"]["type"],
+            json!(["string", "null"])
+        );
+    }
+
+    #[test]
+    fn test_non_strict_parameters() {
+        let with_default = tool(json!({
+            "type": "object",
+            "properties": { "count": { "type": "integer", "default": 5 } },
+        }));
+        let free_form = tool(json!({
+            "type": "object",
+            "properties": { "env": { "type": "object" } },
+        }));
+        let no_arguments = tool(json!({}));
+
+        assert_eq!(with_default.strict_parameters(), None);
+        assert_eq!(
+            with_default.parameters["properties"]["count"]["default"],
+            json!(5)
+        );
+        assert_eq!(free_form.strict_parameters(), None);
+        assert!(!no_arguments.has_parameters());
+        assert_eq!(
+            no_arguments.strict_parameters(),
+            Some(json!({
+                "type": "object",
+                "properties": {},
+                "required": [],
+                "additionalProperties": false,
+            }))
+        );
+    }
+}
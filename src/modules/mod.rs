@@ -1,7 +1,11 @@
 pub mod chatgpt;
 pub mod gemini;
+pub mod llm;
 pub mod mhw;
 pub mod namuwiki;
+pub mod search;
+pub mod transcribe;
+pub mod translate;
 pub mod twitter;
 
 pub async fn invoke_all_modules<B: super::Bot>(bot: &B, message: crate::MessageEvent) {
@@ -30,9 +34,32 @@ pub async fn invoke_all_modules<B: super::Bot>(bot: &B, message: crate::MessageE
         (bot, message) => [
             mhw::handle,
             namuwiki::handle,
-            chatgpt::handle,
-            twitter::handle,
-            gemini::handle
+            llm::handle,
+            search::handle,
+            transcribe::handle,
+            twitter::handle
         ]
     );
 }
+
+pub async fn invoke_all_action_handlers<B: super::Bot>(bot: &B, action: crate::ActionEvent) {
+    if let Err(e) = llm::handle_action(bot, &action).await {
+        log::error!("Action handler {} returned error - {}", action.action_id, e);
+    }
+}
+
+pub async fn invoke_all_edit_handlers<B: super::Bot>(bot: &B, edit: crate::EditEvent) {
+    if let Err(e) = search::handle_edit(bot, &edit).await {
+        log::error!("Edit handler search::handle_edit returned error - {}", e);
+    }
+}
+
+pub async fn invoke_all_reaction_handlers<B: super::Bot>(bot: &B, reaction: crate::ReactionEvent) {
+    if let Err(e) = translate::handle_reaction(bot, &reaction).await {
+        log::error!(
+            "Reaction handler {} returned error - {}",
+            reaction.reaction,
+            e
+        );
+    }
+}
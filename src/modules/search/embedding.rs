@@ -0,0 +1,188 @@
+use std::env;
+
+use anyhow::{anyhow, Context as _};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_OPENAI_URL: &str = "https://api.openai.com/v1/embeddings";
+const DEFAULT_OPENAI_MODEL: &str = "text-embedding-3-small";
+const HASH_DIMENSIONS: usize = 512;
+
+/// Turns texts into vectors. Vectors of different embedders can't be compared.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Identifies the embedder and its model, so an index made by another one is not reused.
+    fn name(&self) -> String;
+
+    async fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>>;
+}
+
+/// Reads `EMBEDDING_BACKEND`, one of `openai` (default), `http` or `hash`.
+///
+/// `http` is any server speaking the OpenAI embeddings API, like llama.cpp or Ollama,
+/// at `EMBEDDING_URL`. `EMBEDDING_MODEL` overrides the model of both.
+/// `hash` needs nothing and works offline, but only matches words. Empty values count as missing.
+pub fn from_env(openai_key: &str) -> anyhow::Result<Box<dyn Embedder>> {
+    let var = |name| {
+        env::var(name)
+            .ok()
+            .filter(|value: &String| !value.is_empty())
+    };
+
+    let backend = var("EMBEDDING_BACKEND").unwrap_or("openai".to_string());
+    let model = var("EMBEDDING_MODEL");
+
+    match backend.as_str() {
+        "openai" => Ok(Box::new(OpenAiEmbedder::new(
+            DEFAULT_OPENAI_URL.to_string(),
+            Some(openai_key.to_string()),
+            model.unwrap_or(DEFAULT_OPENAI_MODEL.to_string()),
+        ))),
+        "http" => Ok(Box::new(OpenAiEmbedder::new(
+            var("EMBEDDING_URL").context("EMBEDDING_URL is not given")?,
+            var("EMBEDDING_KEY"),
+            model.unwrap_or_default(),
+        ))),
+        "hash" => Ok(Box::new(HashEmbedder::new(HASH_DIMENSIONS))),
+        etc => Err(anyhow!("Unknown embedding backend `{}`", etc)),
+    }
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    url: String,
+    key: Option<String>,
+    model: String,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(url: String, key: Option<String>, model: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn name(&self) -> String {
+        format!("{} {}", self.url, self.model)
+    }
+
+    async fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut request = self.client.post(&self.url).json(&EmbeddingRequest {
+            model: &self.model,
+            input: texts,
+        });
+
+        if let Some(key) = &self.key {
+            request = request.bearer_auth(key);
+        }
+
+        let res = request
+            .send()
+            .await
+            .context("Failed to send request")?
+            .error_for_status()
+            .context("Embedding request failed")?
+            .json::<EmbeddingResponse>()
+            .await
+            .context("Failed to parse response")?;
+
+        let mut data = res.data;
+        data.sort_by_key(|data| data.index);
+
+        if data.len() != texts.len() {
+            return Err(anyhow!(
+                "Got {} embeddings for {} texts",
+                data.len(),
+                texts.len()
+            ));
+        }
+
+        Ok(data.into_iter().map(|data| data.embedding).collect())
+    }
+}
+
+/// Hashes the words of a text into a fixed number of buckets. Stands in for a real model.
+pub struct HashEmbedder {
+    dimensions: usize,
+}
+
+impl HashEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self { dimensions }
+    }
+
+    fn embed_text(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let bucket = fnv1a(&word.to_lowercase()) % self.dimensions as u64;
+            vector[bucket as usize] += 1.0;
+        }
+
+        vector
+    }
+}
+
+impl Default for HashEmbedder {
+    fn default() -> Self {
+        Self::new(HASH_DIMENSIONS)
+    }
+}
+
+#[async_trait]
+impl Embedder for HashEmbedder {
+    fn name(&self) -> String {
+        format!("hash {}", self.dimensions)
+    }
+
+    async fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.embed_text(text)).collect())
+    }
+}
+
+// Unlike `DefaultHasher`, stays the same across builds, which the stored index relies on
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test]
+fn test_hash_embedder() {
+    let embedder = HashEmbedder::new(64);
+    let vector = embedder.embed_text("Deploy, deploy the 배포 server");
+
+    assert_eq!(vector.len(), 64);
+    assert_eq!(vector.iter().sum::<f32>(), 5.0);
+    assert_eq!(
+        embedder.embed_text("DEPLOY!"),
+        embedder.embed_text("deploy")
+    );
+    assert_eq!(fnv1a("deploy"), 0xc0151e83a388ac9e);
+}
@@ -0,0 +1,520 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    modules::llm::{
+        preview,
+        summarize::{fetch_messages, replace_mentions, user_name},
+        tools::ToolMetadata,
+    },
+    slack::{BlockElement, HistoryMessage, SectionBlock},
+    Bot, Message, ReplyMessageEvent,
+};
+
+pub mod embedding;
+pub mod store;
+
+use embedding::{Embedder, HashEmbedder};
+use store::{Document, VectorStore};
+
+pub const TOOL_NAME: &str = "ditto_search_messages";
+
+const DEFAULT_INDEX_PATH: &str = "search_index.jsonl";
+const DEFAULT_BACKFILL_DAYS: u64 = 30;
+pub const EMBED_BATCH_SIZE: usize = 64;
+// Long messages are embedded by their beginning
+const MAX_EMBED_CHARS: usize = 4000;
+// Threads of a channel are fetched one by one
+const MAX_BACKFILL_THREADS: usize = 100;
+const SEARCH_RESULT_COUNT: usize = 5;
+const RESULT_PREVIEW_LEN: usize = 300;
+const TOOL_RESULT_COUNT: usize = 8;
+
+/// Embeddings of the messages of the channels the bot is in.
+pub struct SearchIndex {
+    embedder: Box<dyn Embedder>,
+    store: Mutex<VectorStore>,
+    /// Messages of other channels are only found if the channel is public.
+    public_channels: Mutex<HashSet<String>>,
+    /// Where the backfill reads each channel from, taken before live messages are indexed.
+    cursors: HashMap<String, String>,
+    /// Whether messages are indexed as they arrive and by the backfill.
+    indexing: bool,
+}
+
+/// One matching message.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub score: f32,
+    pub channel: String,
+    pub ts: String,
+    pub user: Option<String>,
+    pub text: String,
+}
+
+impl Default for SearchIndex {
+    fn default() -> Self {
+        Self::new(Box::new(HashEmbedder::default()), VectorStore::in_memory())
+    }
+}
+
+impl SearchIndex {
+    pub fn new(embedder: Box<dyn Embedder>, store: VectorStore) -> Self {
+        Self {
+            embedder,
+            cursors: store.latest_ts(),
+            store: Mutex::new(store),
+            public_channels: Default::default(),
+            indexing: false,
+        }
+    }
+
+    /// Reads `SEARCH_INDEXING`, `SEARCH_INDEX_PATH` and the embedding backend settings.
+    ///
+    /// Messages are indexed only if `SEARCH_INDEXING` is on, since they are sent to the embedder.
+    /// Otherwise the index stays empty, and nothing is read or set up.
+    pub fn from_env(openai_key: &str) -> anyhow::Result<Self> {
+        let indexing = env::var("SEARCH_INDEXING")
+            .map(|indexing| indexing == "1" || indexing.to_lowercase() == "true")
+            .unwrap_or(false);
+
+        if !indexing {
+            info!("Search indexing is off");
+            return Ok(Self::default());
+        }
+
+        let embedder = embedding::from_env(openai_key)?;
+        // Empty values, as docker compose passes unset variables, are the same as missing
+        let path = env::var("SEARCH_INDEX_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .unwrap_or(DEFAULT_INDEX_PATH.to_string());
+        let store = VectorStore::open(PathBuf::from(&path), &embedder.name())?;
+
+        info!(
+            "Search index {} with {} messages, embedded by {}",
+            path,
+            store.len(),
+            embedder.name()
+        );
+
+        Ok(Self {
+            indexing,
+            ..Self::new(embedder, store)
+        })
+    }
+
+    pub fn is_indexing(&self) -> bool {
+        self.indexing
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.lock().unwrap().len()
+    }
+
+    pub fn embedder(&self) -> &dyn Embedder {
+        self.embedder.as_ref()
+    }
+
+    fn set_public_channels(&self, channels: HashSet<String>) {
+        *self.public_channels.lock().unwrap() = channels;
+    }
+
+    /// Embeds and stores the messages which are not indexed yet. Returns how many were added.
+    pub async fn add(&self, channel: &str, messages: Vec<HistoryMessage>) -> anyhow::Result<usize> {
+        let messages = {
+            let store = self.store.lock().unwrap();
+
+            messages
+                .into_iter()
+                .filter(|message| !store.contains(channel, &message.ts))
+                .collect::<Vec<_>>()
+        };
+
+        let mut added = 0;
+
+        for batch in messages.chunks(EMBED_BATCH_SIZE) {
+            let texts = batch
+                .iter()
+                .map(|message| message.text.chars().take(MAX_EMBED_CHARS).collect())
+                .collect::<Vec<String>>();
+
+            let vectors = self.embedder.embed(&texts).await?;
+
+            let documents = batch
+                .iter()
+                .zip(vectors)
+                .map(|(message, vector)| document(channel, message, vector))
+                .collect::<Vec<_>>();
+
+            added += documents.len();
+            self.store.lock().unwrap().add(documents)?;
+        }
+
+        Ok(added)
+    }
+
+    /// Embeds an edited message again if its text changed, adding it if it wasn't indexed.
+    pub async fn update(&self, channel: &str, message: HistoryMessage) -> anyhow::Result<()> {
+        // Replies and unfurls also change the message, but not its text
+        let unchanged = self
+            .store
+            .lock()
+            .unwrap()
+            .get(channel, &message.ts)
+            .is_some_and(|document| document.text == message.text);
+
+        if unchanged {
+            return Ok(());
+        }
+
+        let vector = self
+            .embedder
+            .embed(&[message.text.chars().take(MAX_EMBED_CHARS).collect()])
+            .await?
+            .pop()
+            .unwrap_or_default();
+
+        self.store
+            .lock()
+            .unwrap()
+            .replace(document(channel, &message, vector))
+    }
+
+    /// Forgets a message, returning whether it was indexed.
+    pub fn remove(&self, channel: &str, ts: &str) -> anyhow::Result<bool> {
+        self.store.lock().unwrap().remove(channel, ts)
+    }
+
+    /// The messages closest to the query, among those of `channel` and of public channels.
+    pub async fn search(
+        &self,
+        query: &str,
+        channel: &str,
+        count: usize,
+    ) -> anyhow::Result<Vec<Hit>> {
+        let vector = self
+            .embedder
+            .embed(&[query.to_string()])
+            .await?
+            .pop()
+            .unwrap_or_default();
+
+        let public_channels = self.public_channels.lock().unwrap().clone();
+        let store = self.store.lock().unwrap();
+
+        let hits = store
+            .search(&vector, count, |document| {
+                document.channel == channel || public_channels.contains(&document.channel)
+            })
+            .into_iter()
+            .map(|(score, document)| Hit {
+                score,
+                channel: document.channel.clone(),
+                ts: document.ts.clone(),
+                user: document.user.clone(),
+                text: document.text.clone(),
+            })
+            .collect();
+
+        Ok(hits)
+    }
+}
+
+fn document(channel: &str, message: &HistoryMessage, vector: Vec<f32>) -> Document {
+    Document {
+        channel: channel.to_string(),
+        ts: message.ts.clone(),
+        thread_ts: message.thread_ts.clone(),
+        user: message.user.clone(),
+        text: message.text.clone(),
+        vector,
+    }
+}
+
+/// Commands to the bot and messages without words are not worth finding.
+fn is_indexable(bot_id: &str, message: &HistoryMessage) -> bool {
+    message.bot_id.is_none()
+        && message.subtype.is_none()
+        && message.text.chars().any(char::is_alphanumeric)
+        && !message.text.contains(&format!("<@{}>", bot_id))
+}
+
+/// Indexes every message as it arrives, except direct messages.
+pub async fn handle<B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::Result<()> {
+    if !bot.search_index().is_indexing() || msg.channel.starts_with('D') {
+        return Ok(());
+    }
+
+    let message = HistoryMessage {
+        ts: msg.ts.clone(),
+        user: Some(msg.user.clone()),
+        bot_id: None,
+        username: None,
+        subtype: None,
+        text: msg.text.clone(),
+        thread_ts: msg.thread_ts.clone(),
+        reply_count: None,
+        files: vec![],
+    };
+
+    if !is_indexable(bot.bot_id(), &message) {
+        return Ok(());
+    }
+
+    bot.search_index().add(&msg.channel, vec![message]).await?;
+
+    Ok(())
+}
+
+/// Keeps the index in line with edited and deleted messages.
+pub async fn handle_edit<B: Bot>(bot: &B, edit: &crate::EditEvent) -> anyhow::Result<()> {
+    let index = bot.search_index();
+    let (crate::EditEvent::Changed { channel, .. } | crate::EditEvent::Deleted { channel, .. }) =
+        edit;
+
+    if !index.is_indexing() || channel.starts_with('D') {
+        return Ok(());
+    }
+
+    match edit {
+        crate::EditEvent::Changed { channel, message } if is_indexable(bot.bot_id(), message) => {
+            index.update(channel, message.clone()).await
+        }
+        // Also deleted messages with replies, which are left as tombstones
+        crate::EditEvent::Changed { channel, message } => {
+            index.remove(channel, &message.ts)?;
+            Ok(())
+        }
+        crate::EditEvent::Deleted { channel, ts } => {
+            index.remove(channel, ts)?;
+            Ok(())
+        }
+    }
+}
+
+/// Indexes the history of every channel the bot is in, since the newest indexed message
+/// when the bot started or `SEARCH_BACKFILL_DAYS` days ago.
+pub async fn backfill<B: Bot>(bot: &B) -> anyhow::Result<()> {
+    let channels = bot.get_channels().await?;
+    let index = bot.search_index();
+
+    index.set_public_channels(
+        channels
+            .iter()
+            .filter(|channel| !channel.is_private)
+            .map(|channel| channel.id.clone())
+            .collect(),
+    );
+
+    if !index.is_indexing() {
+        return Ok(());
+    }
+
+    let days = env::var("SEARCH_BACKFILL_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_BACKFILL_DAYS);
+
+    let default_oldest = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .saturating_sub(Duration::from_secs(days * 24 * 60 * 60))
+        .as_secs()
+        .to_string();
+
+    for channel in channels {
+        let oldest = index.cursors.get(&channel.id).unwrap_or(&default_oldest);
+
+        let mut messages = vec![];
+        let mut threads = 0;
+
+        for message in fetch_messages(bot, &channel.id, None, Some(oldest)).await? {
+            let has_replies = message.reply_count.unwrap_or(0) > 0;
+            let ts = message.ts.clone();
+
+            messages.push(message);
+
+            if !has_replies || threads >= MAX_BACKFILL_THREADS {
+                continue;
+            }
+
+            threads += 1;
+
+            match fetch_messages(bot, &channel.id, Some(&ts), None).await {
+                Ok(replies) => messages.extend(replies.into_iter().filter(|reply| reply.ts != ts)),
+                Err(e) => warn!("Failed to read the thread {} - {:?}", ts, e),
+            }
+        }
+
+        let messages = messages
+            .into_iter()
+            .filter(|message| is_indexable(bot.bot_id(), message))
+            .collect::<Vec<_>>();
+
+        let added = index.add(&channel.id, messages).await?;
+
+        info!("Indexed {} messages of #{}", added, channel.name);
+    }
+
+    Ok(())
+}
+
+/// Lines describing the hits, with names instead of mentions so nobody is notified.
+pub async fn hit_lines<B: Bot>(bot: &B, hits: &[Hit]) -> anyhow::Result<Vec<String>> {
+    let mut names = HashMap::new();
+    let mut lines = vec![];
+
+    for (i, hit) in hits.iter().enumerate() {
+        let permalink = bot.get_permalink(&hit.channel, &hit.ts).await?;
+        let author = match &hit.user {
+            Some(user) => user_name(bot, &mut names, user).await,
+            None => "someone".to_string(),
+        };
+        let text = replace_mentions(bot, &mut names, &hit.text).await?;
+        let secs = hit.ts.split('.').next().unwrap_or_default();
+
+        lines.push(format!(
+            "*{}.* {} in <#{}> · <!date^{}^{{date_short_pretty}} {{time}}|{}> · <{}|View message>\n> {}",
+            i + 1,
+            author,
+            hit.channel,
+            secs,
+            hit.ts,
+            permalink,
+            preview(&text, RESULT_PREVIEW_LEN)
+        ));
+    }
+
+    Ok(lines)
+}
+
+/// Answers `@ditto search <question>` with the closest messages.
+pub async fn run<B: Bot>(bot: &B, msg: &crate::MessageEvent, query: &str) -> anyhow::Result<()> {
+    let index = bot.search_index();
+    let hits = if index.is_indexing() {
+        index
+            .search(query, &msg.channel, SEARCH_RESULT_COUNT)
+            .await?
+    } else {
+        vec![]
+    };
+
+    let text = if !index.is_indexing() {
+        "Messages are not indexed for search. Set `SEARCH_INDEXING` to turn it on.".to_string()
+    } else if hits.is_empty() {
+        "No messages found.".to_string()
+    } else {
+        hit_lines(bot, &hits).await?.join("\n")
+    };
+
+    bot.send_message(
+        &msg.channel,
+        Message::Blocks(&[BlockElement::Section(SectionBlock::new_markdown(&text))]),
+        Some(ReplyMessageEvent {
+            msg: msg.thread_ts.clone().unwrap_or_else(|| msg.ts.clone()),
+            broadcast: false,
+        }),
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The search as a tool for the models.
+pub fn tool_metadata() -> ToolMetadata {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "query": {
+                "type": "string",
+                "description": "What to look for in the past Slack messages",
+            },
+        },
+        "required": ["query"],
+    });
+
+    ToolMetadata::new(
+        TOOL_NAME.to_string(),
+        "Searches the past messages of this Slack channel and the public channels by meaning. \
+        Returns the closest messages with their authors, dates and links.",
+        schema.as_object().cloned().unwrap_or_default(),
+    )
+}
+
+pub async fn call_tool<B: Bot>(
+    bot: &B,
+    channel: &str,
+    arguments: &HashMap<String, serde_json::Value>,
+) -> anyhow::Result<String> {
+    let query = arguments
+        .get("query")
+        .and_then(|query| query.as_str())
+        .ok_or_else(|| anyhow::anyhow!("`query` is missing"))?;
+
+    let hits = bot
+        .search_index()
+        .search(query, channel, TOOL_RESULT_COUNT)
+        .await?;
+
+    if hits.is_empty() {
+        return Ok("No messages found.".to_string());
+    }
+
+    Ok(hit_lines(bot, &hits).await?.join("\n"))
+}
+
+#[tokio::test]
+async fn test_search_scope() {
+    let index = SearchIndex::default();
+    let message = |ts: &str, text: &str| HistoryMessage {
+        ts: ts.to_string(),
+        user: Some("U123".to_string()),
+        bot_id: None,
+        username: None,
+        subtype: None,
+        text: text.to_string(),
+        thread_ts: None,
+        reply_count: None,
+        files: vec![],
+    };
+
+    index
+        .add(
+            "C_DEV",
+            vec![
+                message("1.0", "The deploy is scheduled for Friday"),
+                message("2.0", "Lunch at noon?"),
+            ],
+        )
+        .await
+        .unwrap();
+    index
+        .add("C_SECRET", vec![message("3.0", "Secret deploy plan")])
+        .await
+        .unwrap();
+
+    index.set_public_channels(HashSet::from(["C_DEV".to_string()]));
+
+    let hits = index
+        .search("when is the deploy", "C_RANDOM", 5)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        hits.iter().map(|hit| hit.ts.as_str()).collect::<Vec<_>>(),
+        vec!["1.0"]
+    );
+
+    let hits = index.search("deploy", "C_SECRET", 5).await.unwrap();
+
+    assert_eq!(hits.len(), 2);
+    assert_eq!(index.len(), 3);
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context as _;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// A message with its embedding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Document {
+    pub channel: String,
+    pub ts: String,
+    #[serde(default)]
+    pub thread_ts: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    embedder: String,
+}
+
+/// Documents kept in memory and appended to a JSON lines file, after a header naming the embedder.
+///
+/// Searching compares the query with every document, which is plenty for a few channels.
+pub struct VectorStore {
+    path: Option<PathBuf>,
+    embedder: String,
+    documents: Vec<Document>,
+    keys: HashSet<(String, String)>,
+}
+
+impl VectorStore {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            embedder: String::new(),
+            documents: vec![],
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Reads the file, starting over if it was made by another embedder.
+    pub fn open(path: PathBuf, embedder: &str) -> anyhow::Result<Self> {
+        let mut store = Self {
+            path: Some(path.clone()),
+            embedder: embedder.to_string(),
+            ..Self::in_memory()
+        };
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                store.reset(embedder)?;
+                return Ok(store);
+            }
+            Err(e) => return Err(e).context("Failed to open search index"),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+
+        let header = match lines.next() {
+            Some(line) => serde_json::from_str::<Header>(&line?).ok(),
+            None => None,
+        };
+
+        if header.is_none_or(|header| header.embedder != embedder) {
+            warn!(
+                "Search index {:?} was made by another embedder, rebuilding",
+                path
+            );
+            store.reset(embedder)?;
+            return Ok(store);
+        }
+
+        for line in lines {
+            // A line cut by a crash is dropped, and indexed again by the next backfill
+            match serde_json::from_str::<Document>(&line?) {
+                Ok(document) => store.insert(document),
+                Err(e) => warn!("Skipping a broken line of the search index - {}", e),
+            }
+        }
+
+        Ok(store)
+    }
+
+    fn reset(&mut self, embedder: &str) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            let mut file = File::create(path).context("Failed to create search index")?;
+
+            serde_json::to_writer(
+                &mut file,
+                &Header {
+                    embedder: embedder.to_string(),
+                },
+            )?;
+            writeln!(file)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the whole file again, for the documents which changed or are gone.
+    fn rewrite(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // Written aside first, so a crash doesn't lose the index
+        let temp_path = path.with_extension("tmp");
+        let file = File::create(&temp_path).context("Failed to create search index")?;
+        let mut writer = BufWriter::new(file);
+
+        serde_json::to_writer(
+            &mut writer,
+            &Header {
+                embedder: self.embedder.clone(),
+            },
+        )?;
+        writeln!(writer)?;
+
+        for document in &self.documents {
+            serde_json::to_writer(&mut writer, document)?;
+            writeln!(writer)?;
+        }
+
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(&temp_path, path).context("Failed to replace search index")?;
+
+        Ok(())
+    }
+
+    fn insert(&mut self, document: Document) {
+        if self
+            .keys
+            .insert((document.channel.clone(), document.ts.clone()))
+        {
+            self.documents.push(document);
+        }
+    }
+
+    pub fn contains(&self, channel: &str, ts: &str) -> bool {
+        self.keys.contains(&(channel.to_string(), ts.to_string()))
+    }
+
+    pub fn get(&self, channel: &str, ts: &str) -> Option<&Document> {
+        self.documents
+            .iter()
+            .find(|document| document.channel == channel && document.ts == ts)
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    /// Puts the document in place of the one with the same channel and ts, or adds it.
+    pub fn replace(&mut self, document: Document) -> anyhow::Result<()> {
+        let position = self
+            .documents
+            .iter()
+            .position(|old| old.channel == document.channel && old.ts == document.ts);
+
+        match position {
+            Some(position) => {
+                self.documents[position] = document;
+                self.rewrite()
+            }
+            None => self.add(vec![document]),
+        }
+    }
+
+    /// Removes the document, returning whether it was there.
+    pub fn remove(&mut self, channel: &str, ts: &str) -> anyhow::Result<bool> {
+        if !self.keys.remove(&(channel.to_string(), ts.to_string())) {
+            return Ok(false);
+        }
+
+        self.documents
+            .retain(|document| document.channel != channel || document.ts != ts);
+        self.rewrite()?;
+
+        Ok(true)
+    }
+
+    /// The newest indexed top-level message of each channel.
+    ///
+    /// Replies are left out, as the history of a channel doesn't include them.
+    pub fn latest_ts(&self) -> HashMap<String, String> {
+        let ts = |ts: &str| ts.parse::<f64>().unwrap_or_default();
+        let mut latest = HashMap::<String, String>::new();
+
+        for document in &self.documents {
+            if document
+                .thread_ts
+                .as_ref()
+                .is_some_and(|thread_ts| *thread_ts != document.ts)
+            {
+                continue;
+            }
+
+            match latest.get_mut(&document.channel) {
+                Some(latest_ts) if ts(latest_ts) >= ts(&document.ts) => {}
+                Some(latest_ts) => *latest_ts = document.ts.clone(),
+                None => {
+                    latest.insert(document.channel.clone(), document.ts.clone());
+                }
+            }
+        }
+
+        latest
+    }
+
+    /// Adds the documents which are not in the store yet.
+    pub fn add(&mut self, documents: Vec<Document>) -> anyhow::Result<()> {
+        let documents = documents
+            .into_iter()
+            .filter(|document| !self.contains(&document.channel, &document.ts))
+            .collect::<Vec<_>>();
+
+        if let Some(path) = &self.path {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(path)
+                .context("Failed to open search index")?;
+            let mut writer = BufWriter::new(file);
+
+            for document in &documents {
+                serde_json::to_writer(&mut writer, document)?;
+                writeln!(writer)?;
+            }
+
+            writer.flush()?;
+        }
+
+        for document in documents {
+            self.insert(document);
+        }
+
+        Ok(())
+    }
+
+    /// The `count` documents most similar to the vector among those `filter` accepts, best first.
+    pub fn search(
+        &self,
+        vector: &[f32],
+        count: usize,
+        filter: impl Fn(&Document) -> bool,
+    ) -> Vec<(f32, &Document)> {
+        let mut scored = self
+            .documents
+            .iter()
+            .filter(|document| filter(document))
+            .map(|document| (cosine_similarity(vector, &document.vector), document))
+            .filter(|(score, _)| *score > 0.0)
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        scored.truncate(count);
+
+        scored
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[test]
+fn test_vector_store_file() {
+    let path = std::env::temp_dir().join(format!("ditto_search_{}.jsonl", std::process::id()));
+    let document = |ts: &str, vector: Vec<f32>| Document {
+        channel: "C123".to_string(),
+        ts: ts.to_string(),
+        thread_ts: None,
+        user: Some("U123".to_string()),
+        text: format!("message {}", ts),
+        vector,
+    };
+
+    let mut store = VectorStore::open(path.clone(), "hash 2").unwrap();
+    store
+        .add(vec![
+            document("1.000100", vec![1.0, 0.0]),
+            document("20.000200", vec![0.6, 0.8]),
+        ])
+        .unwrap();
+    store
+        .add(vec![
+            document("1.000100", vec![0.0, 1.0]),
+            // A reply doesn't move where the history is read from
+            Document {
+                thread_ts: Some("1.000100".to_string()),
+                ..document("30.000300", vec![1.0, 0.0])
+            },
+        ])
+        .unwrap();
+
+    let mut store = VectorStore::open(path.clone(), "hash 2").unwrap();
+
+    assert_eq!(store.len(), 3);
+    assert_eq!(
+        store.latest_ts(),
+        HashMap::from([("C123".to_string(), "20.000200".to_string())])
+    );
+
+    let found = store.search(&[0.0, 1.0], 5, |_| true);
+
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].1.ts, "20.000200");
+    assert!((found[0].0 - 0.8).abs() < 1e-6);
+
+    // Edits and deletions are kept in the file
+    store
+        .replace(Document {
+            text: "edited".to_string(),
+            ..document("20.000200", vec![0.0, 1.0])
+        })
+        .unwrap();
+    assert!(store.remove("C123", "30.000300").unwrap());
+    assert!(!store.remove("C123", "30.000300").unwrap());
+
+    let store = VectorStore::open(path.clone(), "hash 2").unwrap();
+
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("C123", "20.000200").unwrap().text, "edited");
+    assert!(!store.contains("C123", "30.000300"));
+
+    // Vectors of another embedder are thrown away
+    let store = VectorStore::open(path.clone(), "hash 4").unwrap();
+
+    assert_eq!(store.len(), 0);
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,304 @@
+use std::env;
+
+use anyhow::{anyhow, Context as _};
+use log::{info, warn};
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+
+use crate::{
+    modules::llm::{complete, LlmOptions, LlmProvider},
+    slack::{BlockElement, ContextBlock, ContextElement, SectionBlock, SlackFile},
+    Bot, Message, ReplyMessageEvent,
+};
+
+const DEFAULT_STT_URL: &str = "https://api.openai.com/v1/audio/transcriptions";
+const DEFAULT_STT_MODEL: &str = "whisper-1";
+// The limit of the OpenAI API
+const MAX_AUDIO_SIZE: u64 = 25 * 1024 * 1024;
+// Section text is limited to 3000 characters
+const MAX_SECTION_LEN: usize = 2900;
+// Slack allows 50 blocks per message
+const MAX_SECTIONS: usize = 40;
+// Shorter transcripts are not worth a summary
+const MIN_SUMMARY_CHARS: usize = 500;
+const TRANSCRIPT_EMOJI: &str = ":studio_microphone:";
+
+const AUDIO_FILETYPES: &[&str] = &[
+    "m4a", "mp3", "mp4", "mpga", "wav", "ogg", "webm", "flac", "aac",
+];
+
+const SUMMARY_INSTRUCTIONS: &str = "Summarize the transcript of a voice message given by the user \
+in two or three sentences, in the language of the transcript. Reply with the summary only.";
+
+/// Where to send audio, read from `STT_URL`, `STT_MODEL` and `STT_KEY`.
+///
+/// Any server taking the OpenAI transcription request works, like the whisper.cpp server
+/// (`http://localhost:8080/inference`). The OpenAI key is only sent to OpenAI.
+struct SttConfig {
+    url: String,
+    model: String,
+    key: Option<String>,
+}
+
+impl SttConfig {
+    fn from_env(openai_key: &str) -> Self {
+        // Empty values, as docker compose passes unset variables, are the same as missing
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+
+        let url = var("STT_URL").unwrap_or(DEFAULT_STT_URL.to_string());
+        let key =
+            var("STT_KEY").or_else(|| (url == DEFAULT_STT_URL).then(|| openai_key.to_string()));
+
+        Self {
+            url,
+            model: var("STT_MODEL").unwrap_or(DEFAULT_STT_MODEL.to_string()),
+            key,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+fn is_audio(file: &SlackFile) -> bool {
+    file.mimetype.starts_with("audio/") || AUDIO_FILETYPES.contains(&file.filetype.as_str())
+}
+
+async fn transcribe(
+    config: &SttConfig,
+    filename: &str,
+    content: Vec<u8>,
+) -> anyhow::Result<String> {
+    let form = Form::new()
+        .text("model", config.model.clone())
+        .text("response_format", "json")
+        .part("file", Part::bytes(content).file_name(filename.to_string()));
+
+    let mut request = reqwest::Client::new().post(&config.url).multipart(form);
+
+    if let Some(key) = &config.key {
+        request = request.bearer_auth(key);
+    }
+
+    let res = request
+        .send()
+        .await
+        .context("Failed to send request")?
+        .error_for_status()
+        .context("Transcription request failed")?
+        .json::<TranscriptionResponse>()
+        .await
+        .context("Failed to parse response")?;
+
+    Ok(res.text.trim().to_string())
+}
+
+async fn download_and_transcribe<B: Bot>(
+    bot: &B,
+    config: &SttConfig,
+    file: &SlackFile,
+) -> anyhow::Result<String> {
+    if file.size > MAX_AUDIO_SIZE {
+        return Err(anyhow!(
+            "The file is larger than {} MB",
+            MAX_AUDIO_SIZE / 1024 / 1024
+        ));
+    }
+
+    let url = file
+        .url_private_download
+        .as_deref()
+        .ok_or_else(|| anyhow!("The file can't be downloaded"))?;
+
+    let content = bot.download_file(url).await?;
+
+    transcribe(config, &file.name, content).await
+}
+
+fn transcript_blocks(label: &str, transcript: &str) -> Vec<BlockElement> {
+    let mut blocks = vec![BlockElement::Context(ContextBlock::new_markdown(label))];
+    let chars = transcript.chars().collect::<Vec<_>>();
+
+    blocks.extend(
+        chars
+            .chunks(MAX_SECTION_LEN)
+            .take(MAX_SECTIONS)
+            .map(|chunk| {
+                BlockElement::Section(SectionBlock::new_text(&chunk.iter().collect::<String>()))
+            }),
+    );
+
+    blocks
+}
+
+/// The transcript posted by the bot, so it takes part in the conversation of the thread.
+pub fn transcript_text(blocks: &[BlockElement]) -> Option<String> {
+    let label = match blocks.first()? {
+        BlockElement::Context(context) => match context.elements.first()? {
+            ContextElement::Text(text) => text
+                .text
+                .strip_prefix(TRANSCRIPT_EMOJI)?
+                .trim_start()
+                .strip_prefix("Transcript")
+                .map(|label| format!("Transcript{}", label))?,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let transcript = blocks[1..]
+        .iter()
+        .filter_map(|block| match block {
+            BlockElement::Section(section) => Some(section.text.text.as_str()),
+            _ => None,
+        })
+        .collect::<String>();
+
+    Some(format!("{}\n{}", label, transcript))
+}
+
+/// Whether `TRANSCRIBE_AUDIO` is on, or the channel is configured to transcribe audio.
+fn is_enabled<B: Bot>(bot: &B, channel: &str) -> bool {
+    env::var("TRANSCRIBE_AUDIO")
+        .map(|transcribe| transcribe == "1" || transcribe.to_lowercase() == "true")
+        .unwrap_or(false)
+        || bot
+            .config()
+            .channel(channel)
+            .is_some_and(|channel| channel.transcribe_audio)
+}
+
+/// Transcribes the audio files of a message in its thread, with a summary if `TRANSCRIPT_SUMMARY` is set.
+pub async fn handle<B: Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow::Result<()> {
+    let files = msg
+        .files
+        .iter()
+        .filter(|file| is_audio(file))
+        .collect::<Vec<_>>();
+
+    if files.is_empty() || !is_enabled(bot, &msg.channel) {
+        return Ok(());
+    }
+
+    let config = SttConfig::from_env(bot.openai_key());
+    let summarize = env::var("TRANSCRIPT_SUMMARY")
+        .map(|summary| summary == "1" || summary.to_lowercase() == "true")
+        .unwrap_or(false);
+
+    let reply_event = Some(ReplyMessageEvent {
+        msg: msg.thread_ts.clone().unwrap_or_else(|| msg.ts.clone()),
+        broadcast: false,
+    });
+
+    let author = bot
+        .get_user_name(&msg.user)
+        .await
+        .unwrap_or_else(|_| msg.user.clone());
+
+    for file in files {
+        let transcript = match download_and_transcribe(bot, &config, file).await {
+            Ok(transcript) => transcript,
+            Err(e) => {
+                warn!("Failed to transcribe {} - {:?}", file.name, e);
+
+                bot.send_message(
+                    &msg.channel,
+                    Message::Blocks(&[BlockElement::Context(ContextBlock::new_markdown(
+                        &format!(
+                            "{} Couldn't transcribe `{}` - {}",
+                            TRANSCRIPT_EMOJI, file.name, e
+                        ),
+                    ))]),
+                    reply_event.clone(),
+                    None,
+                )
+                .await?;
+
+                continue;
+            }
+        };
+
+        info!(
+            "Transcribed {} of {} in {} - {} characters",
+            file.name,
+            msg.user,
+            msg.channel,
+            transcript.chars().count()
+        );
+
+        if transcript.is_empty() {
+            continue;
+        }
+
+        let label = format!(
+            "{} Transcript of {}'s audio `{}`",
+            TRANSCRIPT_EMOJI, author, file.name
+        );
+
+        bot.send_message(
+            &msg.channel,
+            Message::Blocks(&transcript_blocks(&label, &transcript)),
+            reply_event.clone(),
+            None,
+        )
+        .await?;
+
+        if !summarize || transcript.chars().count() < MIN_SUMMARY_CHARS {
+            continue;
+        }
+
+        let summary = complete(
+            bot,
+            LlmProvider::ChatGpt,
+            &LlmOptions::default(),
+            SUMMARY_INSTRUCTIONS,
+            &transcript,
+            false,
+        )
+        .await?;
+
+        bot.send_message(
+            &msg.channel,
+            Message::Blocks(&[BlockElement::Section(SectionBlock::new_markdown(&format!(
+                "*Summary*\n{}",
+                summary
+            )))]),
+            reply_event.clone(),
+            None,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_transcript_text() {
+    let transcript = "가".repeat(MAX_SECTION_LEN + 10);
+    let blocks = transcript_blocks(
+        ":studio_microphone: Transcript of Alice's audio `clip.webm`",
+        &transcript,
+    );
+
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(
+        transcript_text(&blocks),
+        Some(format!(
+            "Transcript of Alice's audio `clip.webm`\n{}",
+            transcript
+        ))
+    );
+
+    assert_eq!(
+        transcript_text(&[BlockElement::Context(ContextBlock::new_markdown(
+            "`ChatGPT`"
+        ))]),
+        None
+    );
+}
@@ -0,0 +1,174 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use log::info;
+
+use crate::{
+    modules::llm::{complete, LlmOptions, LlmProvider},
+    slack::{BlockElement, ContextBlock, SectionBlock},
+    Bot, Message, ReactionEvent, ReplyMessageEvent,
+};
+
+// Section text is limited to 3000 characters
+const MAX_SECTION_LEN: usize = 2900;
+// The log is emptied once it holds this many translations
+const MAX_LOGGED_TRANSLATIONS: usize = 1000;
+
+/// The messages already translated, keyed by (channel, message ts, language),
+/// so a second flag of the same language doesn't post the translation again.
+#[derive(Default)]
+pub struct TranslationLog {
+    translations: Mutex<HashSet<(String, String, &'static str)>>,
+}
+
+impl TranslationLog {
+    /// Returns false if the message was already translated into the language.
+    fn claim(&self, channel: &str, ts: &str, language: &'static str) -> bool {
+        let mut translations = self.translations.lock().unwrap();
+
+        if translations.len() >= MAX_LOGGED_TRANSLATIONS {
+            translations.clear();
+        }
+
+        translations.insert((channel.to_string(), ts.to_string(), language))
+    }
+
+    /// Lets a failed translation be asked again.
+    fn release(&self, channel: &str, ts: &str, language: &'static str) {
+        let mut translations = self.translations.lock().unwrap();
+
+        translations.remove(&(channel.to_string(), ts.to_string(), language));
+    }
+}
+
+/// The language to translate into for a flag reaction. Slack names some flags both ways.
+fn flag_language(reaction: &str) -> Option<&'static str> {
+    match reaction {
+        "flag-us" | "us" => Some("English"),
+        "flag-kr" | "kr" => Some("Korean"),
+        "flag-jp" | "jp" => Some("Japanese"),
+        _ => None,
+    }
+}
+
+fn instructions(language: &str) -> String {
+    format!(
+        "Translate the Slack message given by the user into {}. \
+         Keep the Slack formatting, mentions, links and emoji codes as they are. \
+         Reply with the translation only.",
+        language
+    )
+}
+
+/// Translates the message a flag reaction was added to, in a reply under it.
+pub async fn handle_reaction<B: Bot>(bot: &B, reaction: &ReactionEvent) -> anyhow::Result<()> {
+    let language = match flag_language(&reaction.reaction) {
+        Some(language) => language,
+        None => return Ok(()),
+    };
+
+    let translations = bot.translations();
+
+    if !translations.claim(&reaction.channel, &reaction.item_ts, language) {
+        info!(
+            "{} in {} is already translated into {}",
+            reaction.item_ts, reaction.channel, language
+        );
+        return Ok(());
+    }
+
+    let res = translate(bot, reaction, language).await;
+
+    if res.is_err() {
+        translations.release(&reaction.channel, &reaction.item_ts, language);
+    }
+
+    res
+}
+
+async fn translate<B: Bot>(
+    bot: &B,
+    reaction: &ReactionEvent,
+    language: &'static str,
+) -> anyhow::Result<()> {
+    // Replies of a thread are only found through conversations.replies
+    let response = bot
+        .get_messages(&reaction.channel, Some(&reaction.item_ts), None, None)
+        .await?;
+
+    let message = match response
+        .messages
+        .into_iter()
+        .find(|message| message.ts == reaction.item_ts)
+    {
+        Some(message) if !message.text.trim().is_empty() => message,
+        _ => return Ok(()),
+    };
+
+    info!(
+        "Translating {} in {} into {} for {}",
+        message.ts, reaction.channel, language, reaction.user
+    );
+
+    let provider = LlmProvider::ChatGpt;
+    let options = LlmOptions::default();
+    let translation = complete(
+        bot,
+        provider,
+        &options,
+        &instructions(language),
+        &message.text,
+        false,
+    )
+    .await?;
+
+    let author = match (&message.user, &message.username) {
+        (Some(user), _) => format!("<@{}>", user),
+        (None, Some(username)) => username.clone(),
+        (None, None) => "someone".to_string(),
+    };
+
+    let translation = translation
+        .chars()
+        .take(MAX_SECTION_LEN)
+        .collect::<String>();
+
+    bot.send_message(
+        &reaction.channel,
+        Message::Blocks(&[
+            BlockElement::Section(SectionBlock::new_markdown(&translation)),
+            BlockElement::Context(ContextBlock::new_markdown(&format!(
+                ":{}: {} translation of {}'s message, requested by <@{}>",
+                reaction.reaction, language, author, reaction.user
+            ))),
+        ]),
+        Some(ReplyMessageEvent {
+            msg: message.thread_ts.unwrap_or(message.ts),
+            broadcast: false,
+        }),
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_flag_language() {
+    assert_eq!(flag_language("flag-us"), Some("English"));
+    assert_eq!(flag_language("kr"), Some("Korean"));
+    assert_eq!(flag_language("flag-jp"), Some("Japanese"));
+    assert_eq!(flag_language("flag-fr"), None);
+    assert_eq!(flag_language("stop"), None);
+}
+
+#[test]
+fn test_translation_log() {
+    let log = TranslationLog::default();
+
+    assert!(log.claim("C123", "1.0", "Korean"));
+    assert!(!log.claim("C123", "1.0", "Korean"));
+    assert!(log.claim("C123", "1.0", "English"));
+
+    log.release("C123", "1.0", "Korean");
+    assert!(log.claim("C123", "1.0", "Korean"));
+}
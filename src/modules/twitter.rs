@@ -13,14 +13,10 @@ pub async fn handle<B: crate::Bot>(bot: &B, msg: &crate::MessageEvent) -> anyhow
 
         parsed_url.set_host(Some("vxtwitter.com"))?;
 
-        let reply_event = if let Some(thread_ts) = &msg.thread_ts {
-            Some(ReplyMessageEvent {
-                msg: thread_ts.to_string(),
-                broadcast: false,
-            })
-        } else {
-            None
-        };
+        let reply_event = msg.thread_ts.as_ref().map(|thread_ts| ReplyMessageEvent {
+            msg: thread_ts.to_string(),
+            broadcast: false,
+        });
 
         bot.send_message(
             &msg.channel,
@@ -0,0 +1,237 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context as _};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use log::{error, info, warn};
+
+use crate::{
+    config::{Job, ScheduleConfig},
+    modules::llm::{
+        command::parse_period,
+        summarize::{summary_message, Target, DEFAULT_PERIOD},
+        LlmOptions, LlmProvider,
+    },
+    Bot, Message,
+};
+
+/// A job of the config, checked and ready to run.
+enum Task {
+    Digest {
+        channel: String,
+        post_to: String,
+        period: Duration,
+        provider: LlmProvider,
+        options: LlmOptions,
+    },
+}
+
+/// Numbered days of the week of crontab, 0 or 7 for Sunday, as `cron` numbers them, 1 for Sunday.
+/// Named days are left as they are.
+fn cron_days_of_week(field: &str) -> anyhow::Result<String> {
+    let mut days = vec![];
+    let mut named = vec![];
+
+    for part in field.split(',') {
+        if !part.contains(|c: char| c.is_ascii_digit()) {
+            named.push(part.to_string());
+            continue;
+        }
+
+        let invalid = || anyhow!("Invalid day of week `{}`", part);
+        let number = |number: &str| number.parse::<u32>().ok().filter(|number| *number <= 7);
+
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().ok().filter(|step| *step > 0)),
+            None => (part, Some(1)),
+        };
+        let step = step.ok_or_else(invalid)?;
+
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (number(first), number(last)),
+            None if range == "*" => (Some(0), Some(6)),
+            None if step > 1 => (number(range), Some(6)),
+            None => (number(range), number(range)),
+        };
+
+        match (first, last) {
+            (Some(first), Some(last)) if first <= last => {
+                days.extend((first..=last).step_by(step).map(|day| day % 7 + 1))
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    days.sort();
+    days.dedup();
+
+    Ok(days
+        .into_iter()
+        .map(|day| day.to_string())
+        .chain(named)
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// `cron` wants the seconds field, which is usually left out. Without it, the expression is
+/// read as in crontab, with days of the week numbered from 0 for Sunday.
+fn parse_cron(expression: &str) -> anyhow::Result<Schedule> {
+    let expression = expression.trim();
+    let fields = expression.split_whitespace().collect::<Vec<_>>();
+
+    let expression = if fields.len() == 5 {
+        format!(
+            "0 {} {}",
+            fields[..4].join(" "),
+            cron_days_of_week(fields[4])
+                .with_context(|| format!("Invalid cron `{}`", expression))?
+        )
+    } else {
+        expression.to_string()
+    };
+
+    Schedule::from_str(&expression).with_context(|| format!("Invalid cron `{}`", expression))
+}
+
+fn parse_job(job: &Job) -> anyhow::Result<Task> {
+    match job {
+        Job::Digest {
+            channel,
+            post_to,
+            period,
+            model,
+        } => {
+            let period = match period {
+                Some(period) => {
+                    parse_period(period).ok_or_else(|| anyhow!("Invalid period `{}`", period))?
+                }
+                None => DEFAULT_PERIOD,
+            };
+
+            let provider = match model {
+                Some(model) => LlmProvider::of_model(model)
+                    .ok_or_else(|| anyhow!("Unknown model `{}`", model))?,
+                None => LlmProvider::ChatGpt,
+            };
+
+            Ok(Task::Digest {
+                channel: channel.clone(),
+                post_to: post_to.clone().unwrap_or_else(|| channel.clone()),
+                period,
+                provider,
+                options: LlmOptions {
+                    model: model.clone(),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+}
+
+/// The first run strictly after `after`, as seen from `tz`.
+fn next_run(schedule: &Schedule, tz: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&after.with_timezone(&tz))
+        .next()
+        .map(|next| next.with_timezone(&Utc))
+}
+
+async fn run_task<B: Bot>(bot: &B, task: &Task) -> anyhow::Result<()> {
+    match task {
+        Task::Digest {
+            channel,
+            post_to,
+            period,
+            provider,
+            options,
+        } => {
+            let target = Target::Channel {
+                channel: channel.clone(),
+                period: *period,
+            };
+
+            let blocks = summary_message(bot, *provider, options, channel, &target, None).await;
+
+            bot.send_message(post_to, Message::Blocks(&blocks), None, None)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Spawns a task for every valid schedule of the config. Invalid ones are logged and skipped.
+pub fn start<B: Bot + Send + Sync + 'static>(bot: Arc<B>, schedules: &[ScheduleConfig], tz: &str) {
+    let tz = Tz::from_str(tz).unwrap_or_else(|_| {
+        warn!("Unknown timezone {}, schedules run in UTC", tz);
+        Tz::UTC
+    });
+
+    for config in schedules {
+        let (schedule, task) = match parse_cron(&config.cron)
+            .and_then(|schedule| Ok((schedule, parse_job(&config.job)?)))
+        {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                error!("Skipping schedule {:?} - {:?}", config, e);
+                continue;
+            }
+        };
+
+        info!("Scheduled {:?} at `{}` in {}", config.job, config.cron, tz);
+
+        let bot = bot.clone();
+
+        tokio::spawn(async move {
+            while let Some(next) = next_run(&schedule, tz, Utc::now()) {
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+
+                if let Err(e) = run_task(bot.as_ref(), &task).await {
+                    error!("Scheduled job failed - {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn test_next_run() {
+    let schedule = parse_cron("0 9 * * *").unwrap();
+    let after = "2026-10-18T00:30:00Z".parse::<DateTime<Utc>>().unwrap();
+
+    // 09:00 in Seoul is 00:00 UTC, which already passed on the 18th
+    assert_eq!(
+        next_run(&schedule, chrono_tz::Asia::Seoul, after),
+        Some("2026-10-19T00:00:00Z".parse().unwrap())
+    );
+    assert_eq!(
+        next_run(&schedule, Tz::UTC, after),
+        Some("2026-10-18T09:00:00Z".parse().unwrap())
+    );
+
+    // Weekdays, numbered as in crontab
+    let weekdays = parse_cron("0 9 * * 1-5").unwrap();
+    let saturday = "2026-10-17T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+    assert_eq!(
+        next_run(&weekdays, Tz::UTC, saturday),
+        Some("2026-10-19T09:00:00Z".parse().unwrap())
+    );
+    assert_eq!(
+        next_run(&parse_cron("0 9 * * 0").unwrap(), Tz::UTC, saturday),
+        Some("2026-10-18T09:00:00Z".parse().unwrap())
+    );
+
+    assert_eq!(cron_days_of_week("*").unwrap(), "*");
+    assert_eq!(cron_days_of_week("0,7").unwrap(), "1");
+    assert_eq!(cron_days_of_week("5-7").unwrap(), "1,6,7");
+    assert_eq!(cron_days_of_week("*/2").unwrap(), "1,3,5,7");
+    assert_eq!(cron_days_of_week("Mon-Fri").unwrap(), "Mon-Fri");
+    assert!(cron_days_of_week("8").is_err());
+    assert!(cron_days_of_week("5-1").is_err());
+
+    assert!(parse_cron("0 0 9 * * Mon").is_ok());
+    assert!(parse_cron("every morning").is_err());
+}
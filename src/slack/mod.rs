@@ -79,6 +79,21 @@ pub struct ActionBlock {
     pub elements: Option<Vec<BlockElement>>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ContextBlock {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_id: Option<String>,
+    pub elements: Vec<ContextElement>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ContextElement {
+    Text(TextObject),
+    // Images and anything else we don't render ourselves
+    Other(serde_json::Value),
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ButtonStyle {
     #[serde(rename = "primary")]
@@ -140,6 +155,7 @@ pub enum BlockElement {
     Button(ButtonBlock),
     Section(SectionBlock),
     Actions(ActionBlock),
+    Context(ContextBlock),
     Image(ImageBlock),
     Link(LinkBlock),
     #[serde(other)]
@@ -174,9 +190,14 @@ pub struct BasicMessage {
     pub channel: String,
     pub user: Option<String>,
     pub bot_id: Option<String>,
+    pub subtype: Option<String>,
     pub edited: Option<Edited>,
     pub event_ts: String,
+    // Messages of only files have no blocks
+    #[serde(default)]
     pub blocks: Vec<Block>,
+    #[serde(default)]
+    pub files: Vec<SlackFile>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -186,6 +207,18 @@ pub struct ChannelJoinMessage {
     pub user: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageChangedMessage {
+    pub channel: String,
+    pub message: HistoryMessage,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageDeletedMessage {
+    pub channel: String,
+    pub deleted_ts: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct LinksItem {
     pub url: String, //Error("invalid type: string expected a borrowed string", line: 0, column: 0)
@@ -207,7 +240,8 @@ pub struct LinkSharedMessage {
 #[serde(rename_all = "snake_case")]
 pub enum TaggedMessage {
     ChannelJoin(ChannelJoinMessage),
-    MessageChanged,
+    MessageChanged(MessageChangedMessage),
+    MessageDeleted(MessageDeletedMessage),
     ThreadBroadcast,
 }
 
@@ -219,6 +253,27 @@ pub enum Message {
     Unknown(serde_json::Value),
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ReactionItem {
+    Message {
+        channel: String,
+        ts: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReactionAddedEvent {
+    pub user: String,
+    pub reaction: String,
+    pub item_user: Option<String>,
+    pub item: ReactionItem,
+    pub event_ts: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -226,6 +281,7 @@ pub enum InternalEvent {
     Message(Message),
     RichText(Message),
     LinkShared(LinkSharedMessage),
+    ReactionAdded(ReactionAddedEvent),
     AppMention,
 }
 
@@ -251,14 +307,79 @@ pub struct SlackEventsApi {
     pub retry_reason: String,
 }
 
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlackInteractive {
+    pub envelope_id: String,
+    pub payload: InteractivePayload,
+    pub accepts_response_payload: bool,
+}
+
+/// https://api.slack.com/reference/interaction-payloads/block-actions
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InteractivePayload {
+    BlockActions(BlockActionsPayload),
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockActionsPayload {
+    pub user: InteractiveUser,
+    pub channel: Option<InteractiveChannel>,
+    pub message: Option<InteractiveMessage>,
+    pub actions: Vec<BlockAction>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct InteractiveUser {
+    pub id: String,
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InteractiveChannel {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InteractiveMessage {
+    pub ts: String,
+    pub thread_ts: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockAction {
+    pub action_id: String,
+    pub block_id: Option<String>,
+    pub value: Option<String>,
+    pub action_ts: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum ThreadMessageType {
-    Unbroadcasted(ThreadUnbroadcastedMessage),
-    Broadcasted(ThreadBroadcastedMessage),
+    Unbroadcasted(Box<ThreadUnbroadcastedMessage>),
+    Broadcasted(Box<ThreadBroadcastedMessage>),
+    Bot(Box<ThreadBotMessage>),
     None(ThreadNoneMessage),
 }
 
+impl ThreadMessageType {
+    pub fn ts(&self) -> Option<String> {
+        match self {
+            ThreadMessageType::Unbroadcasted(val) => Some(String::from(&val.ts)),
+            ThreadMessageType::Broadcasted(val) => Some(String::from(&val.ts)),
+            ThreadMessageType::Bot(val) => Some(String::from(&val.ts)),
+            ThreadMessageType::None(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ConversationReplyResponse {
     pub ok: bool,
@@ -267,6 +388,143 @@ pub struct ConversationReplyResponse {
     pub has_more: Option<bool>,
 }
 
+/// Response of conversations.history and conversations.replies, read leniently.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConversationHistoryResponse {
+    pub ok: bool,
+    #[serde(default)]
+    pub messages: Vec<HistoryMessage>,
+    pub error: Option<String>,
+    pub response_metadata: Option<ResponseMetadata>,
+}
+
+impl ConversationHistoryResponse {
+    pub fn next_cursor(&self) -> Option<&str> {
+        self.response_metadata
+            .as_ref()
+            .map(|metadata| metadata.next_cursor.as_str())
+            .filter(|cursor| !cursor.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponseMetadata {
+    #[serde(default)]
+    pub next_cursor: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryMessage {
+    pub ts: String,
+    pub user: Option<String>,
+    pub bot_id: Option<String>,
+    pub username: Option<String>,
+    pub subtype: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    pub thread_ts: Option<String>,
+    pub reply_count: Option<i32>,
+    #[serde(default)]
+    pub files: Vec<SlackFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlackFile {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub mimetype: String,
+    #[serde(default)]
+    pub filetype: String,
+    #[serde(default)]
+    pub size: u64,
+    pub url_private_download: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PermalinkResponse {
+    pub permalink: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Response of users.conversations.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConversationsListResponse {
+    pub ok: bool,
+    #[serde(default)]
+    pub channels: Vec<ChannelInfo>,
+    pub error: Option<String>,
+    pub response_metadata: Option<ResponseMetadata>,
+}
+
+impl ConversationsListResponse {
+    pub fn next_cursor(&self) -> Option<&str> {
+        self.response_metadata
+            .as_ref()
+            .map(|metadata| metadata.next_cursor.as_str())
+            .filter(|cursor| !cursor.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConversationMembersResponse {
+    pub ok: bool,
+    #[serde(default)]
+    pub members: Vec<String>,
+    pub error: Option<String>,
+    pub response_metadata: Option<ResponseMetadata>,
+}
+
+impl ConversationMembersResponse {
+    pub fn next_cursor(&self) -> Option<&str> {
+        self.response_metadata
+            .as_ref()
+            .map(|metadata| metadata.next_cursor.as_str())
+            .filter(|cursor| !cursor.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChannelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub is_private: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserInfoResponse {
+    pub user: Option<UserInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserInfo {
+    pub name: String,
+    pub real_name: Option<String>,
+    pub profile: Option<UserProfile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserProfile {
+    #[serde(default)]
+    pub display_name: String,
+}
+
+impl UserInfo {
+    /// The name shown in Slack: display name, then real name, then user name.
+    pub fn display_name(&self) -> &str {
+        self.profile
+            .as_ref()
+            .map(|profile| profile.display_name.as_str())
+            .filter(|name| !name.is_empty())
+            .or_else(|| self.real_name.as_deref().filter(|name| !name.is_empty()))
+            .unwrap_or(&self.name)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct ThreadUnbroadcastedMessage {
@@ -307,6 +565,17 @@ pub struct ThreadBroadcastedMessage {
     pub blocks: Vec<BlockElement>,
 }
 
+/// A reply of a bot which was not sent to the channel.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThreadBotMessage {
+    pub bot_id: String,
+    #[serde(default)]
+    pub text: String,
+    pub ts: StrTimeStamp,
+    #[serde(default)]
+    pub blocks: Vec<BlockElement>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct PostMessageResponse {
@@ -316,6 +585,61 @@ pub struct PostMessageResponse {
     pub error: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetUploadUrlResponse {
+    pub upload_url: Option<String>,
+    pub file_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompleteUploadExternal<'a> {
+    pub files: Vec<UploadedFile<'a>>,
+    pub channel_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_ts: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadedFile<'a> {
+    pub id: &'a str,
+    pub title: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostEphemeral<'a> {
+    pub channel: &'a str,
+    pub user: &'a str,
+    pub text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_ts: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PostEphemeralResponse {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AddReaction<'a> {
+    pub channel: &'a str,
+    pub timestamp: &'a str,
+    pub name: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddReactionResponse {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompleteUploadResponse {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct EditMessageResponse {
@@ -354,7 +678,8 @@ pub enum SlackEvent {
         challenge: String,
     },
     Hello(SlackHello),
-    EventsApi(SlackEventsApi),
+    EventsApi(Box<SlackEventsApi>),
+    Interactive(SlackInteractive),
     Disconnect {
         reason: String,
     },
@@ -429,6 +754,23 @@ pub struct EditMessage<'a> {
     pub ts: String,
 }
 
+impl ButtonBlock {
+    pub fn new(text: &str, action_id: &str, value: &str, style: Option<ButtonStyle>) -> Self {
+        Self {
+            text: TextObject {
+                ty: TextObjectType::PlainText,
+                text: text.to_string(),
+                emoji: Some(true),
+                verbatim: None,
+            },
+            action_id: Some(action_id.to_string()),
+            url: None,
+            value: Some(value.to_string()),
+            style,
+        }
+    }
+}
+
 impl SectionBlock {
     pub fn new_markdown(text: &str) -> Self {
         Self::new_block(text, TextObjectType::Markdown)
@@ -452,5 +794,19 @@ impl SectionBlock {
     }
 }
 
+impl ContextBlock {
+    pub fn new_markdown(text: &str) -> Self {
+        Self {
+            block_id: None,
+            elements: vec![ContextElement::Text(TextObject {
+                ty: TextObjectType::Markdown,
+                text: text.to_string(),
+                emoji: None,
+                verbatim: None,
+            })],
+        }
+    }
+}
+
 #[cfg(test)]
 mod test;
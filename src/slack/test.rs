@@ -92,3 +92,125 @@ pub fn test_deserialize_normal_message() {
     )
     .unwrap();
 }
+
+#[test]
+pub fn test_deserialize_message_edits() {
+    let deserialized = serde_json::from_str::<InternalEvent>(
+        r#"{
+        "type": "message",
+        "subtype": "message_changed",
+        "hidden": true,
+        "channel": "C123ABC456",
+        "message": {
+            "type": "message",
+            "user": "U123ABC456",
+            "text": "Hello, world!",
+            "ts": "1355517523.000005",
+            "edited": {
+                "user": "U123ABC456",
+                "ts": "1355517536.000001"
+            }
+        },
+        "ts": "1355517536.000001",
+        "event_ts": "1355517536.000001"
+    }"#,
+    )
+    .unwrap();
+
+    if let InternalEvent::Message(Message::TaggedMessage(TaggedMessage::MessageChanged(changed))) =
+        deserialized
+    {
+        assert_eq!(changed.channel, "C123ABC456");
+        assert_eq!(changed.message.ts, "1355517523.000005");
+        assert_eq!(changed.message.text, "Hello, world!");
+    } else {
+        panic!("deserialized one must be a MessageChanged!");
+    }
+
+    let deserialized = serde_json::from_str::<InternalEvent>(
+        r#"{
+        "type": "message",
+        "subtype": "message_deleted",
+        "hidden": true,
+        "channel": "C123ABC456",
+        "ts": "1358878755.000001",
+        "deleted_ts": "1358878749.000002",
+        "event_ts": "1358878755.000001"
+    }"#,
+    )
+    .unwrap();
+
+    if let InternalEvent::Message(Message::TaggedMessage(TaggedMessage::MessageDeleted(deleted))) =
+        deserialized
+    {
+        assert_eq!(deleted.deleted_ts, "1358878749.000002");
+    } else {
+        panic!("deserialized one must be a MessageDeleted!");
+    }
+}
+
+#[test]
+pub fn test_deserialize_reaction_added() {
+    let deserialized = serde_json::from_str::<InternalEvent>(
+        r#"{
+        "type": "reaction_added",
+        "user": "U024BE7LH",
+        "reaction": "stop",
+        "item_user": "U0G9QF9C6",
+        "item": {
+            "type": "message",
+            "channel": "C0G9QF9GZ",
+            "ts": "1360782400.498405"
+        },
+        "event_ts": "1360782804.083113"
+    }"#,
+    )
+    .unwrap();
+
+    if let InternalEvent::ReactionAdded(reaction) = deserialized {
+        assert_eq!(reaction.reaction, "stop");
+        assert!(matches!(reaction.item, ReactionItem::Message { .. }));
+    } else {
+        panic!("deserialized one must be a ReactionAdded!");
+    }
+}
+
+#[test]
+pub fn test_deserialize_block_actions() {
+    let deserialized = serde_json::from_str::<SlackEvent>(
+        r#"{
+        "type": "interactive",
+        "envelope_id": "d8b1b8b2-1a2b-4c3d-9e8f-1234567890ab",
+        "accepts_response_payload": false,
+        "payload": {
+            "type": "block_actions",
+            "user": { "id": "U123", "username": "ditto" },
+            "channel": { "id": "C123", "name": "general" },
+            "message": { "ts": "1548261231.000200", "thread_ts": "1548261200.000100", "text": "" },
+            "actions": [{
+                "action_id": "stop_generation",
+                "block_id": "abc",
+                "value": "stop",
+                "type": "button",
+                "action_ts": "1548426417.840180"
+            }]
+        }
+    }"#,
+    )
+    .unwrap();
+
+    if let SlackEvent::Interactive(interactive) = deserialized {
+        if let InteractivePayload::BlockActions(payload) = interactive.payload {
+            assert_eq!(payload.user.id, "U123");
+            assert_eq!(payload.actions[0].action_id, "stop_generation");
+            assert_eq!(
+                payload.message.unwrap().thread_ts.as_deref(),
+                Some("1548261200.000100")
+            );
+        } else {
+            panic!("payload must be a BlockActions!");
+        }
+    } else {
+        panic!("deserialized one must be an Interactive!");
+    }
+}
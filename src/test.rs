@@ -1,11 +1,23 @@
 use anyhow::anyhow;
-use std::{
-    collections::{HashMap, HashSet},
-    sync::RwLock,
-};
+use std::{collections::HashMap, sync::RwLock};
 
 use crate::{
-    slack::{ConversationReplyResponse, EditMessageResponse, PostMessageResponse},
+    config::Config,
+    generation::GenerationRegistry,
+    mcp::supervisor::McpRegistry,
+    modules::chatgpt::ResponseStore,
+    modules::llm::{
+        approval::{ApprovalRegistry, ToolPolicies},
+        documents::DocumentCache,
+        tools::ToolMetadata,
+        AnswerStore,
+    },
+    modules::search::SearchIndex,
+    modules::translate::TranslationLog,
+    slack::{
+        ConversationHistoryResponse, ConversationReplyResponse, EditMessageResponse,
+        PostMessageResponse,
+    },
     Message, ReplyMessageEvent,
 };
 
@@ -17,7 +29,7 @@ pub enum MockMessage {
 impl<'a> From<Message<'a>> for MockMessage {
     fn from(msg: Message<'a>) -> Self {
         match msg {
-            Message::Blocks(blocks) => MockMessage::Blocks(blocks.iter().cloned().collect()),
+            Message::Blocks(blocks) => MockMessage::Blocks(blocks.to_vec()),
             Message::Text(text) => MockMessage::Text(text.to_string()),
         }
     }
@@ -26,6 +38,16 @@ impl<'a> From<Message<'a>> for MockMessage {
 #[derive(Default)]
 pub struct MockBot {
     messages: RwLock<Vec<(String, MockMessage)>>,
+    generations: GenerationRegistry,
+    answers: AnswerStore,
+    responses: ResponseStore,
+    approvals: ApprovalRegistry,
+    search_index: SearchIndex,
+    documents: DocumentCache,
+    translations: TranslationLog,
+    mcp: McpRegistry,
+    tool_policies: ToolPolicies,
+    config: Config,
 }
 
 impl MockBot {
@@ -59,6 +81,50 @@ impl super::Bot for MockBot {
         ""
     }
 
+    fn generations(&self) -> &GenerationRegistry {
+        &self.generations
+    }
+
+    fn answers(&self) -> &AnswerStore {
+        &self.answers
+    }
+
+    fn responses(&self) -> &ResponseStore {
+        &self.responses
+    }
+
+    fn approvals(&self) -> &ApprovalRegistry {
+        &self.approvals
+    }
+
+    fn tool_policies(&self) -> &ToolPolicies {
+        &self.tool_policies
+    }
+
+    fn search_index(&self) -> &SearchIndex {
+        &self.search_index
+    }
+
+    fn documents(&self) -> &DocumentCache {
+        &self.documents
+    }
+
+    fn translations(&self) -> &TranslationLog {
+        &self.translations
+    }
+
+    fn mcp(&self) -> &McpRegistry {
+        &self.mcp
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn is_admin(&self, _user: &str) -> bool {
+        false
+    }
+
     async fn send_message(
         &self,
         channel: &str,
@@ -121,6 +187,50 @@ impl super::Bot for MockBot {
         Err(anyhow!("Not implemented!"))
     }
 
+    async fn get_messages(
+        &self,
+        _channel: &str,
+        _thread_ts: Option<&str>,
+        _oldest: Option<&str>,
+        _cursor: Option<&str>,
+    ) -> anyhow::Result<ConversationHistoryResponse> {
+        Err(anyhow!("Not implemented!"))
+    }
+
+    async fn get_user_name(&self, user: &str) -> anyhow::Result<String> {
+        Ok(user.to_string())
+    }
+
+    async fn get_permalink(&self, channel: &str, ts: &str) -> anyhow::Result<String> {
+        Ok(format!(
+            "https://example.slack.com/archives/{}/p{}",
+            channel,
+            ts.replace('.', "")
+        ))
+    }
+
+    async fn get_channels(&self) -> anyhow::Result<Vec<super::slack::ChannelInfo>> {
+        Err(anyhow!("Not implemented!"))
+    }
+
+    async fn get_channel_members(&self, _channel: &str) -> anyhow::Result<Vec<String>> {
+        Err(anyhow!("Not implemented!"))
+    }
+
+    async fn download_file(&self, _url: &str) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow!("Not implemented!"))
+    }
+
+    async fn upload_file(
+        &self,
+        _channel: &str,
+        _thread_ts: Option<&str>,
+        _filename: &str,
+        _content: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        Err(anyhow!("Not implemented!"))
+    }
+
     async fn call_mcp_tool(
         &self,
         _unified_name: &str,
@@ -129,9 +239,28 @@ impl super::Bot for MockBot {
         Err(anyhow!("Not implemented!"))
     }
 
-    async fn get_all_tools_metadata(
+    async fn add_reaction(&self, _channel: &str, _ts: &str, _name: &str) -> anyhow::Result<()> {
+        Err(anyhow!("Not implemented!"))
+    }
+
+    async fn send_ephemeral(
         &self,
-    ) -> anyhow::Result<Vec<(String, HashMap<String, (String, String)>, HashSet<String>)>> {
+        channel: &str,
+        _user: &str,
+        text: &str,
+        _thread_ts: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut messages = self
+            .messages
+            .write()
+            .map_err(|e| anyhow!("write lock failed - {}", e))?;
+
+        messages.push((channel.to_string(), MockMessage::Text(text.to_string())));
+
+        Ok(())
+    }
+
+    async fn get_all_tools_metadata(&self) -> anyhow::Result<Vec<ToolMetadata>> {
         Err(anyhow!("Not implemented!"))
     }
 }